@@ -0,0 +1,100 @@
+//! Files derived from an exported media file while processing it.
+//!
+//! Every name snapback gives a derived file comes from here, so the step
+//! that creates a file and the step that later moves it can't disagree.
+
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+/// Default suffix added to the file name of the composited copy in
+/// [`OverlayMode::Copy`](crate::OverlayMode::Copy)
+pub const OVERLAY_SUFFIX: &str = "_with_overlay";
+
+const CONVERTED_SUFFIX: &str = "_converted";
+const TEMP_SUFFIX: &str = "_temp";
+
+/// What an [`Artifact`] is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtifactKind {
+    /// The composited copy made in [`OverlayMode::Copy`](crate::OverlayMode::Copy)
+    Overlaid,
+    /// The XMP sidecar written in
+    /// [`MetadataMode::Sidecar`](crate::metadata::MetadataMode::Sidecar)
+    Sidecar,
+}
+
+/// A file produced while processing an exported media file, which is
+/// transferred to the output directory along with it
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Artifact {
+    pub kind: ArtifactKind,
+    /// Where the file was created, next to the exported file
+    pub path: PathBuf,
+    /// Where it ended up after the move step
+    pub destination: Option<PathBuf>,
+}
+
+impl Artifact {
+    pub fn new(kind: ArtifactKind, path: impl Into<PathBuf>) -> Self {
+        Self {
+            kind,
+            path: path.into(),
+            destination: None,
+        }
+    }
+}
+
+/// `path` with `suffix` added to its stem: `a.jpg` → `a{suffix}.jpg`
+pub fn with_stem_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{}{}.{}", stem, suffix, ext.to_string_lossy()),
+        None => format!("{}{}", stem, suffix),
+    };
+    path.with_file_name(name)
+}
+
+/// The composited copy of `path` ([`ArtifactKind::Overlaid`]), named with
+/// `suffix`. It's created next to the original, so an empty suffix falls
+/// back to [`OVERLAY_SUFFIX`] here rather than replacing it.
+pub fn overlaid_path(path: &Path, suffix: &str) -> PathBuf {
+    let suffix = if suffix.is_empty() {
+        OVERLAY_SUFFIX
+    } else {
+        suffix
+    };
+    with_stem_suffix(path, suffix)
+}
+
+/// The XMP sidecar of `path` ([`ArtifactKind::Sidecar`]): the full file
+/// name with `.xmp` appended (`photo.jpg.xmp`), as darktable and digiKam name
+/// them. Adobe's apps replace the extension instead (`photo.xmp`), so
+/// Lightroom doesn't find these.
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".xmp");
+    path.with_file_name(name)
+}
+
+/// Where `path` is converted to the format with extension `ext`, moved to
+/// the output directory in its place
+pub fn converted_path(path: &Path, ext: &str) -> PathBuf {
+    with_stem_suffix(&path.with_extension(ext), CONVERTED_SUFFIX)
+}
+
+/// Where ffmpeg writes the composite or conversion of `path` before it's put
+/// in place (temporary)
+pub fn composite_temp_path(path: &Path) -> PathBuf {
+    with_stem_suffix(path, TEMP_SUFFIX)
+}
+
+/// Whether `path` is named like one of the files above (with the default
+/// overlay suffix), i.e. it was made by snapback rather than exported
+pub fn is_derived(path: &Path) -> bool {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    [OVERLAY_SUFFIX, CONVERTED_SUFFIX, TEMP_SUFFIX]
+        .iter()
+        .any(|suffix| stem.ends_with(suffix))
+}
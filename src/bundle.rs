@@ -0,0 +1,178 @@
+//! Grouping the exported files that belong to the same memory or chat item.
+//!
+//! Snapchat exports every part of an item as a file of its own: the media,
+//! any number of overlay layers and sometimes a thumbnail, all named after
+//! the item's id. A [`Grouping`] tells the parts apart by their names, and
+//! [`group`] collects them into [`MemoryBundle`]s that are processed as a
+//! unit.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::artifact;
+use crate::chat::parse_chat_media_id_from_stem;
+use crate::memories::parse_id_from_stem;
+
+/// What a file is to the item it belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// The photo or video itself
+    Main,
+    /// A caption, drawing or sticker layer drawn over the media
+    Overlay,
+    /// A preview image, which snapback leaves alone
+    Thumbnail,
+}
+
+/// Recognizes the parts of an export's items by their file names
+pub trait Grouping: fmt::Debug + Send + Sync {
+    /// The id of the item the file at `path` belongs to and its role in it,
+    /// or `None` if it's not part of the export. Files are matched to the
+    /// records in the export's JSON by this id.
+    fn classify(&self, path: &Path) -> Option<(String, Role)>;
+
+    /// Where the overlay at `path` is stacked among its item's layers, lowest
+    /// first. Layers at the same position are stacked by path.
+    fn layer(&self, _path: &Path) -> u32 {
+        0
+    }
+}
+
+/// Saved memories: `2021-07-03_<uuid>-main.jpg` with `-overlay.png` (or
+/// numbered `-overlay2.png`, ... layers) and `-thumbnail.jpg` next to it
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoriesGrouping;
+
+impl Grouping for MemoriesGrouping {
+    fn classify(&self, path: &Path) -> Option<(String, Role)> {
+        // Files snapback derived from the media, such as overlaid copies left
+        // in place by `--transfer copy` or frames extracted for ffmpeg, are
+        // never processed again
+        if artifact::is_derived(path) {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        let id = parse_id_from_stem(stem)?;
+        let suffix = stem.rsplit_once('-')?.1.to_ascii_lowercase();
+        let role = if suffix == "main" {
+            Role::Main
+        } else if layer_number(&suffix, "overlay").is_some() {
+            Role::Overlay
+        } else if layer_number(&suffix, "thumbnail").is_some() {
+            Role::Thumbnail
+        } else {
+            return None;
+        };
+        Some((id, role))
+    }
+
+    /// `-overlay` is the first layer, then `-overlay2`, `-overlay3`, ...
+    fn layer(&self, path: &Path) -> u32 {
+        path.file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.rsplit_once('-'))
+            .and_then(|(_, suffix)| layer_number(&suffix.to_ascii_lowercase(), "overlay"))
+            .unwrap_or(0)
+    }
+}
+
+/// The layer number of `suffix` if it's `name` optionally followed by one
+/// (1 when it isn't)
+fn layer_number(suffix: &str, name: &str) -> Option<u32> {
+    match suffix.strip_prefix(name)? {
+        "" => Some(1),
+        n if n.bytes().all(|b| b.is_ascii_digit()) => n.parse().ok(),
+        _ => None,
+    }
+}
+
+/// Chat media: `2023-01-15_b~<id>.jpg` with `overlay~` and `thumbnail~`
+/// prefixed parts next to it. Any name after the date parses as an id, so
+/// files snapback derived from the media are recognized by their
+/// [`artifact`] names and skipped.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChatGrouping;
+
+impl Grouping for ChatGrouping {
+    fn classify(&self, path: &Path) -> Option<(String, Role)> {
+        if artifact::is_derived(path) {
+            return None;
+        }
+        let stem = path.file_stem()?.to_str()?;
+        let (_date, id) = stem.split_once('_')?;
+        if let Some(id) = id.strip_prefix("overlay~") {
+            return Some((id.to_string(), Role::Overlay));
+        }
+        if let Some(id) = id.strip_prefix("thumbnail~") {
+            return Some((id.to_string(), Role::Thumbnail));
+        }
+        parse_chat_media_id_from_stem(stem).map(|id| (id, Role::Main))
+    }
+}
+
+/// An exported media file with the overlays and thumbnails that belong to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryBundle {
+    /// The id the files share
+    pub id: String,
+    /// The file metadata is written to and that is moved to the output
+    /// directory
+    pub main: PathBuf,
+    /// Overlay layers composited onto `main`, bottom first
+    pub overlays: Vec<PathBuf>,
+    pub thumbnails: Vec<PathBuf>,
+    /// Whether the item has no media of its own, only overlays, so `main`
+    /// is its bottom overlay layer. Overlays are WebP images named `.png`,
+    /// so the pipeline leaves these alone.
+    pub caption_only: bool,
+}
+
+/// Group `paths` into bundles as `grouping` classifies them. An item with
+/// several media files (e.g. `-main.jpg` and `-main.mov`) gets a bundle for
+/// each, sharing the overlays. Items without media become caption-only
+/// bundles; files `grouping` doesn't recognize are dropped.
+pub fn group(
+    paths: impl IntoIterator<Item = PathBuf>,
+    grouping: &dyn Grouping,
+) -> Vec<MemoryBundle> {
+    let mut items: BTreeMap<String, BTreeMap<Role, Vec<PathBuf>>> = BTreeMap::new();
+    for path in paths {
+        if let Some((id, role)) = grouping.classify(&path) {
+            items
+                .entry(id)
+                .or_default()
+                .entry(role)
+                .or_default()
+                .push(path);
+        }
+    }
+
+    let mut bundles = Vec::new();
+    for (id, mut parts) in items {
+        for files in parts.values_mut() {
+            files.sort();
+        }
+        let mut overlays = parts.remove(&Role::Overlay).unwrap_or_default();
+        // Numbered layers are stacked by number, so `overlay10` goes on top
+        // of `overlay2` rather than below it
+        overlays.sort_by_cached_key(|path| grouping.layer(path));
+        let thumbnails = parts.remove(&Role::Thumbnail).unwrap_or_default();
+        let caption_only = !parts.contains_key(&Role::Main);
+        let mains = match parts.remove(&Role::Main) {
+            Some(mains) => mains,
+            None if !overlays.is_empty() => vec![overlays.remove(0)],
+            None => continue,
+        };
+        for main in mains {
+            bundles.push(MemoryBundle {
+                id: id.clone(),
+                main,
+                overlays: overlays.clone(),
+                thumbnails: thumbnails.clone(),
+                caption_only,
+            });
+        }
+    }
+    bundles
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
+use serde::Deserializer;
+use serde::{Deserialize, Serialize};
+
+use crate::memories::parse_date;
+
+/// Contents of `chat_history.json` or `snap_history.json`: messages keyed by
+/// the username of the conversation they belong to
+pub type ChatHistory = HashMap<String, Vec<ChatMessage>>;
+
+/// A single message from a chat or snap history file
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMessage {
+    #[serde(alias = "From")]
+    pub from: String,
+    #[serde(alias = "Media Type")]
+    pub media_type: String,
+    #[serde(alias = "Created", deserialize_with = "parse_date")]
+    pub created: DateTime<Utc>,
+    /// Title of the group chat, if the conversation is one
+    #[serde(alias = "Conversation Title", default)]
+    pub conversation_title: Option<String>,
+    #[serde(alias = "IsSender", default)]
+    pub is_sender: bool,
+    /// Ids of the files in `chat_media/` attached to this message
+    #[serde(alias = "Media IDs", default, deserialize_with = "parse_media_ids")]
+    pub media_ids: Vec<String>,
+}
+
+/// A file in `chat_media/` together with the message it was sent in
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMedia {
+    pub id: String,
+    /// When the message was sent or received
+    pub date: DateTime<Utc>,
+    /// The other side of the conversation: the group chat title, the
+    /// recipient for sent media or the sender for received media
+    pub partner: String,
+    pub is_sender: bool,
+}
+
+/// Extract the media id from a `chat_media/` file stem such as
+/// `2023-01-15_b~EiQSFWxyz`
+pub fn parse_chat_media_id_from_stem(stem: &str) -> Option<String> {
+    // Expected format: YYYY-MM-DD_ID, where the ID itself may contain '_' and '-'
+    let (_date, id) = stem.split_once('_')?;
+
+    // Overlays and thumbnails are exported next to the media they belong to
+    if id.is_empty() || id.starts_with("overlay~") || id.starts_with("thumbnail~") {
+        return None;
+    }
+
+    Some(id.to_string())
+}
+
+fn parse_media_ids<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // Multiple ids are joined with " | "
+    let s: Option<String> = Deserialize::deserialize(deserializer)?;
+    Ok(s.unwrap_or_default()
+        .split('|')
+        .map(|id| id.trim())
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Read and parse a `chat_history.json` or `snap_history.json` file
+pub fn parse_chat_history_file(path: &Path) -> serde_json::Result<ChatHistory> {
+    let data = std::fs::read(path).map_err(serde_json::Error::io)?;
+    serde_json::from_slice::<ChatHistory>(&data)
+}
+
+/// Index every media attachment in `history` by its id
+pub fn chat_media_map(history: ChatHistory) -> HashMap<String, ChatMedia> {
+    let mut map = HashMap::new();
+    for (conversation, messages) in history {
+        for message in messages {
+            let partner = match (&message.conversation_title, message.is_sender) {
+                (Some(title), _) if !title.is_empty() => title.clone(),
+                (_, true) => conversation.clone(),
+                (_, false) => message.from.clone(),
+            };
+            for id in message.media_ids {
+                map.insert(
+                    id.clone(),
+                    ChatMedia {
+                        id,
+                        date: message.created,
+                        partner: partner.clone(),
+                        is_sender: message.is_sender,
+                    },
+                );
+            }
+        }
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(from: &str, title: Option<&str>, is_sender: bool, ids: &str) -> ChatMessage {
+        serde_json::from_value(serde_json::json!({
+            "From": from,
+            "Media Type": "MEDIA",
+            "Created": "2023-01-15 10:20:30 UTC",
+            "Conversation Title": title,
+            "IsSender": is_sender,
+            "Media IDs": ids,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn media_ids_are_split_and_trimmed() {
+        let ids = |ids: serde_json::Value| {
+            let mut json = serde_json::json!({
+                "From": "alex",
+                "Media Type": "MEDIA",
+                "Created": "2023-01-15 10:20:30 UTC",
+            });
+            json["Media IDs"] = ids;
+            serde_json::from_value::<ChatMessage>(json)
+                .unwrap()
+                .media_ids
+        };
+        assert_eq!(
+            ids("b~One | b~Two|b~Three ".into()),
+            ["b~One", "b~Two", "b~Three"]
+        );
+        assert_eq!(ids("b~One".into()), ["b~One"]);
+        assert!(ids("".into()).is_empty());
+        assert!(ids(serde_json::Value::Null).is_empty());
+    }
+
+    #[test]
+    fn media_is_tagged_with_the_other_side_of_the_conversation() {
+        let history = ChatHistory::from([
+            (
+                "alex".to_string(),
+                vec![
+                    message("alex", None, false, "b~Received"),
+                    message("me", None, true, "b~Sent"),
+                    message("me", Some(""), true, "b~Untitled"),
+                ],
+            ),
+            (
+                "group~trip".to_string(),
+                vec![
+                    message("sam", Some("Ski trip"), false, "b~FromGroup"),
+                    message("me", Some("Ski trip"), true, "b~ToGroup | b~ToGroup2"),
+                ],
+            ),
+        ]);
+
+        let media = chat_media_map(history);
+        assert_eq!(media.len(), 6);
+        for (id, partner, is_sender) in [
+            ("b~Received", "alex", false),
+            ("b~Sent", "alex", true),
+            ("b~Untitled", "alex", true),
+            ("b~FromGroup", "Ski trip", false),
+            ("b~ToGroup", "Ski trip", true),
+            ("b~ToGroup2", "Ski trip", true),
+        ] {
+            let chat = &media[id];
+            assert_eq!(chat.id, id);
+            assert_eq!(
+                (chat.partner.as_str(), chat.is_sender),
+                (partner, is_sender)
+            );
+            assert_eq!(chat.date.to_rfc3339(), "2023-01-15T10:20:30+00:00");
+        }
+    }
+
+    #[test]
+    fn media_ids_are_parsed_from_file_stems() {
+        for (stem, expected) in [
+            ("2023-01-15_b~EiQSFWxyz", Some("b~EiQSFWxyz")),
+            (
+                "2023-01-15_b~with_under-scores",
+                Some("b~with_under-scores"),
+            ),
+            ("2023-01-15_overlay~b~EiQSFWxyz", None),
+            ("2023-01-15_thumbnail~b~EiQSFWxyz", None),
+            ("2023-01-15_", None),
+            ("2023-01-15", None),
+        ] {
+            assert_eq!(
+                parse_chat_media_id_from_stem(stem).as_deref(),
+                expected,
+                "{}",
+                stem
+            );
+        }
+    }
+}
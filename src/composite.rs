@@ -0,0 +1,520 @@
+//! Compositing overlays (captions, drawings, stickers) onto media files.
+//!
+//! Photos are composited in-process so their EXIF survives and ffmpeg isn't
+//! needed for them; videos still go through ffmpeg.
+
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPDecoder;
+use image::imageops::{self, FilterType};
+use image::{AnimationDecoder, DynamicImage, Frame, ImageFormat, RgbImage, RgbaImage};
+
+use crate::artifact::composite_temp_path;
+use crate::metadata::{jpeg, replace_file};
+use crate::report::{ErrorKind, Failure};
+use crate::tools::Tools;
+
+/// JPEG quality composited photos are encoded with unless configured otherwise
+pub const DEFAULT_IMAGE_QUALITY: u8 = 92;
+
+/// Frames of animated overlays shown for less than this are shown for
+/// [`DEFAULT_FRAME_DELAY`] instead, as browsers do
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(10);
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+
+/// Name of the ffconcat script listing the frames of an animated overlay
+const FRAME_SCRIPT: &str = "frames.ffconcat";
+
+/// Encoder used for videos re-encoded with their overlay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum VideoCodec {
+    /// H.264 (libx264), playable everywhere
+    #[default]
+    H264,
+    /// H.265/HEVC (libx265), about half the size at the same quality
+    Hevc,
+    /// AV1 (libsvtav1), smallest files but slow and not supported by every
+    /// player
+    Av1,
+}
+
+impl VideoCodec {
+    fn encoder(self) -> &'static str {
+        match self {
+            VideoCodec::H264 => "libx264",
+            VideoCodec::Hevc => "libx265",
+            VideoCodec::Av1 => "libsvtav1",
+        }
+    }
+
+    /// A CRF that keeps the re-encode visually indistinguishable from the
+    /// source. The encoders' own defaults (23, 28 and 35) visibly degrade it.
+    pub fn default_crf(self) -> u8 {
+        match self {
+            VideoCodec::H264 => 18,
+            VideoCodec::Hevc => 20,
+            VideoCodec::Av1 => 28,
+        }
+    }
+}
+
+/// How videos are re-encoded when their overlay is composited
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VideoEncoding {
+    pub codec: VideoCodec,
+    /// Constant rate factor; the codec's [default](VideoCodec::default_crf)
+    /// when unset
+    pub crf: Option<u8>,
+    /// Encoder preset (e.g. `slow` for x264/x265, `6` for SVT-AV1); the
+    /// encoder's default when unset
+    pub preset: Option<String>,
+    /// Target the source's video bitrate, probed with ffprobe, instead of a
+    /// constant quality
+    pub match_source_bitrate: bool,
+}
+
+impl VideoEncoding {
+    /// ffmpeg output arguments for encoding `video`
+    pub(crate) fn args(
+        &self,
+        video: &Path,
+        tools: &Tools,
+        warnings: &mut Vec<String>,
+    ) -> Vec<String> {
+        let mut args = vec!["-c:v".to_string(), self.codec.encoder().to_string()];
+
+        let bitrate = if self.match_source_bitrate {
+            match probe_bitrate(video, tools) {
+                Ok(bitrate) => Some(bitrate),
+                Err(e) => {
+                    warnings.push(format!(
+                        "Failed to probe the bitrate of {:?}, using CRF instead: {}",
+                        video, e
+                    ));
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        match bitrate {
+            Some(bitrate) => args.extend([
+                "-b:v".to_string(),
+                bitrate.to_string(),
+                "-maxrate".to_string(),
+                bitrate.to_string(),
+                "-bufsize".to_string(),
+                (bitrate * 2).to_string(),
+            ]),
+            None => args.extend([
+                "-crf".to_string(),
+                self.crf.unwrap_or(self.codec.default_crf()).to_string(),
+            ]),
+        }
+
+        if let Some(preset) = &self.preset {
+            args.extend(["-preset".to_string(), preset.clone()]);
+        }
+        // Keep the output playable by hardware decoders and Apple's players
+        args.extend(["-pix_fmt".to_string(), "yuv420p".to_string()]);
+        if self.codec == VideoCodec::Hevc {
+            args.extend(["-tag:v".to_string(), "hvc1".to_string()]);
+        }
+        args
+    }
+}
+
+/// The bitrate of the first video stream of `video` in bits per second,
+/// falling back to the container's overall bitrate when the stream doesn't
+/// declare one
+fn probe_bitrate(video: &Path, tools: &Tools) -> Result<u64, String> {
+    for entries in ["stream=bit_rate", "format=bit_rate"] {
+        let output = Command::new(&tools.ffprobe)
+            .args(["-v", "error", "-select_streams", "v:0", "-show_entries"])
+            .arg(entries)
+            .args(["-of", "default=noprint_wrappers=1:nokey=1"])
+            .arg(video)
+            .output()
+            .map_err(|e| format!("failed to run ffprobe: {}", e))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        if let Ok(bitrate) = String::from_utf8_lossy(&output.stdout).trim().parse() {
+            return Ok(bitrate);
+        }
+    }
+    Err("no bitrate reported".to_string())
+}
+
+/// Decode an overlay file. Snapchat names them `.png` but most contain WebP
+/// data, so the format is sniffed from the contents.
+fn load_overlay(overlay: &Path) -> Result<RgbaImage, String> {
+    let bytes =
+        fs::read(overlay).map_err(|e| format!("Failed to read overlay {:?}: {}", overlay, e))?;
+    image::load_from_memory(&bytes)
+        .map(|img| img.into_rgba8())
+        .map_err(|e| format!("Failed to decode overlay {:?}: {}", overlay, e))
+}
+
+/// The frames of an animated WebP overlay, or `None` for a still image
+fn animated_frames(bytes: &[u8]) -> Option<Vec<Frame>> {
+    let decoder = WebPDecoder::new(Cursor::new(bytes)).ok()?;
+    if !decoder.has_animation() {
+        return None;
+    }
+    decoder
+        .into_frames()
+        .collect_frames()
+        .ok()
+        .filter(|frames| frames.len() > 1)
+}
+
+/// Write `frames` into `dir` as PNGs, with an ffconcat script that shows
+/// each of them for its delay, and return the script's path
+fn write_frame_sequence(frames: Vec<Frame>, dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {:?}: {}", dir, e))?;
+
+    let mut script = String::from("ffconcat version 1.0\n");
+    for (i, frame) in frames.into_iter().enumerate() {
+        let delay = match Duration::from(frame.delay()) {
+            delay if delay < MIN_FRAME_DELAY => DEFAULT_FRAME_DELAY,
+            delay => delay,
+        };
+        let name = format!("frame_{:05}.png", i);
+        frame
+            .into_buffer()
+            .save(dir.join(&name))
+            .map_err(|e| format!("Failed to save overlay frame {}: {}", i, e))?;
+        let _ = writeln!(
+            script,
+            "file '{}'\nduration {:.3}",
+            name,
+            delay.as_secs_f64()
+        );
+    }
+
+    let path = dir.join(FRAME_SCRIPT);
+    fs::write(&path, script).map_err(|e| format!("Failed to write {:?}: {}", path, e))?;
+    Ok(path)
+}
+
+/// ffmpeg input arguments for overlay layer `layer` of a video, looped for as
+/// long as the video lasts. Still overlays are converted to a real PNG
+/// (ffmpeg's native WebP decoder can't handle lossy VP8 with a separate alpha
+/// channel); animated ones become a PNG sequence timed like the original, so
+/// they play from the start of the video instead of freezing on their first
+/// frame. Either is written into the temporary directory `scratch`.
+fn overlay_input(overlay: &Path, scratch: &Path, layer: usize) -> Result<Vec<OsString>, String> {
+    let bytes =
+        fs::read(overlay).map_err(|e| format!("Failed to read overlay {:?}: {}", overlay, e))?;
+
+    if let Some(frames) = animated_frames(&bytes) {
+        let script = write_frame_sequence(frames, &scratch.join(format!("overlay{}", layer)))?;
+        return Ok(vec![
+            "-stream_loop".into(),
+            "-1".into(),
+            "-f".into(),
+            "concat".into(),
+            "-safe".into(),
+            "0".into(),
+            "-i".into(),
+            script.into(),
+        ]);
+    }
+
+    let converted = scratch.join(format!("overlay{}.png", layer));
+    image::load_from_memory(&bytes)
+        .map_err(|e| format!("Failed to decode overlay {:?}: {}", overlay, e))?
+        .save(&converted)
+        .map_err(|e| format!("Failed to save converted overlay: {}", e))?;
+    Ok(vec![
+        "-loop".into(),
+        "1".into(),
+        "-i".into(),
+        converted.into(),
+    ])
+}
+
+/// Draw `layer` over `canvas` (same dimensions) with source-over alpha
+/// blending
+fn blend(canvas: &mut RgbImage, layer: &RgbaImage) {
+    for (pixel, top) in canvas.pixels_mut().zip(layer.pixels()) {
+        let alpha = u16::from(top[3]);
+        for c in 0..3 {
+            let value = u16::from(top[c]) * alpha + u16::from(pixel[c]) * (255 - alpha);
+            pixel[c] = ((value + 127) / 255) as u8;
+        }
+    }
+}
+
+/// Load overlay layer `overlay`, stretched to `width` x `height`
+fn fitted_layer(overlay: &Path, width: u32, height: u32) -> Result<RgbaImage, String> {
+    let layer = load_overlay(overlay)?;
+    Ok(imageops::resize(
+        &layer,
+        width,
+        height,
+        FilterType::CatmullRom,
+    ))
+}
+
+/// Composite `overlays`, bottom first and each stretched to cover the whole
+/// frame, onto `photo` and write the result to `output`, which may be `photo`
+/// itself. The result keeps the photo's format; for JPEGs, the EXIF, XMP and
+/// other metadata segments are carried over.
+pub fn photo(
+    photo: &Path,
+    overlays: &[PathBuf],
+    output: &Path,
+    quality: u8,
+) -> Result<(), Failure> {
+    let fail = |message: String| Failure::new(ErrorKind::Overlay, message);
+
+    let data = fs::read(photo).map_err(|e| fail(format!("Failed to read {:?}: {}", photo, e)))?;
+    let format = image::guess_format(&data)
+        .map_err(|e| fail(format!("Failed to decode {:?}: {}", photo, e)))?;
+    let image = image::load_from_memory_with_format(&data, format)
+        .map_err(|e| fail(format!("Failed to decode {:?}: {}", photo, e)))?;
+
+    let composited = if format == ImageFormat::Jpeg {
+        let mut canvas = image.into_rgb8();
+        for overlay in overlays {
+            let layer = fitted_layer(overlay, canvas.width(), canvas.height()).map_err(fail)?;
+            blend(&mut canvas, &layer);
+        }
+
+        let mut encoded = Vec::new();
+        JpegEncoder::new_with_quality(&mut encoded, quality)
+            .encode_image(&canvas)
+            .map_err(|e| fail(format!("Failed to encode {:?}: {}", output, e)))?;
+        jpeg::with_metadata_from(&data, &encoded)
+            .map_err(|e| fail(format!("Failed to copy metadata of {:?}: {}", photo, e)))?
+    } else {
+        // PNG and WebP keep their transparency
+        let mut canvas = image.into_rgba8();
+        for overlay in overlays {
+            let layer = fitted_layer(overlay, canvas.width(), canvas.height()).map_err(fail)?;
+            imageops::overlay(&mut canvas, &layer, 0, 0);
+        }
+
+        let mut encoded = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(canvas)
+            .write_to(&mut encoded, format)
+            .map_err(|e| fail(format!("Failed to encode {:?}: {}", output, e)))?;
+        encoded.into_inner()
+    };
+
+    replace_file(output, |out| {
+        out.write_all(&composited).map_err(|e| e.to_string())
+    })
+    .map_err(fail)
+}
+
+/// The `-filter_complex` graph drawing `layers` overlay inputs, each scaled
+/// to the video's size, over input 0 in order
+fn overlay_filter(layers: usize) -> String {
+    let mut filter = String::new();
+    let mut base = "0:v".to_string();
+    for layer in 1..=layers {
+        if layer > 1 {
+            filter.push(';');
+        }
+        let _ = write!(
+            filter,
+            "[{layer}:v][0:v]scale=rw:rh[ol{layer}];[{base}][ol{layer}]overlay=0:0:shortest=1"
+        );
+        if layer < layers {
+            base = format!("v{}", layer);
+            let _ = write!(filter, "[{}]", base);
+        }
+    }
+    filter
+}
+
+/// Composite `overlays`, bottom first, onto `video` with ffmpeg, re-encoding
+/// it as `encoding` asks, and write the result to `output`, which may be
+/// `video` itself
+pub fn video(
+    video: &Path,
+    overlays: &[PathBuf],
+    output: &Path,
+    encoding: &VideoEncoding,
+    tools: &Tools,
+    warnings: &mut Vec<String>,
+) -> Result<(), Failure> {
+    // Removed with everything in it when dropped, after ffmpeg has run
+    let scratch = tempfile::Builder::new()
+        .prefix("snapback-")
+        .tempdir()
+        .map_err(|e| {
+            Failure::new(
+                ErrorKind::Overlay,
+                format!("Failed to create a temporary directory: {}", e),
+            )
+        })?;
+    let mut inputs = Vec::new();
+    for (layer, overlay) in overlays.iter().enumerate() {
+        inputs.extend(
+            overlay_input(overlay, scratch.path(), layer).unwrap_or_else(|e| {
+                warnings.push(e);
+                vec!["-loop".into(), "1".into(), "-i".into(), overlay.into()]
+            }),
+        );
+    }
+
+    let encoder_args = encoding.args(video, tools, warnings);
+    let temp_output = composite_temp_path(video);
+    let status = Command::new(&tools.ffmpeg)
+        .arg("-y")
+        .arg("-loglevel")
+        .arg("error")
+        .arg("-i")
+        .arg(video)
+        .args(&inputs)
+        .arg("-shortest")
+        .arg("-filter_complex")
+        .arg(overlay_filter(overlays.len()))
+        .args(&encoder_args)
+        // Keep the container and stream tags, including QuickTime keys
+        .args(["-map_metadata", "0", "-movflags", "use_metadata_tags"])
+        .arg("-c:a")
+        .arg("copy")
+        .arg(&temp_output)
+        .status();
+
+    match status {
+        Ok(s) if s.success() => fs::rename(&temp_output, output).map_err(|e| {
+            Failure::new(
+                ErrorKind::Overlay,
+                format!("Failed to finalize overlaid file {:?}: {}", output, e),
+            )
+        }),
+        Ok(_) => {
+            let _ = fs::remove_file(&temp_output);
+            Err(Failure::new(
+                ErrorKind::Ffmpeg,
+                format!("FFmpeg failed for overlay on {:?}", video),
+            ))
+        }
+        Err(e) => Err(Failure::new(
+            ErrorKind::Ffmpeg,
+            format!("Failed to run FFmpeg for {:?}: {}", video, e),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Delay;
+
+    #[test]
+    fn frames_are_shown_for_their_delay() {
+        let frames = [40, 0, 5, 10, 1500]
+            .map(|ms| {
+                Frame::from_parts(
+                    RgbaImage::new(2, 2),
+                    0,
+                    0,
+                    Delay::from_numer_denom_ms(ms, 1),
+                )
+            })
+            .to_vec();
+        let dir = tempfile::tempdir().unwrap();
+
+        let script = write_frame_sequence(frames, &dir.path().join("overlay0")).unwrap();
+        assert_eq!(
+            fs::read_to_string(&script).unwrap(),
+            "ffconcat version 1.0\n\
+             file 'frame_00000.png'\nduration 0.040\n\
+             file 'frame_00001.png'\nduration 0.100\n\
+             file 'frame_00002.png'\nduration 0.100\n\
+             file 'frame_00003.png'\nduration 0.010\n\
+             file 'frame_00004.png'\nduration 1.500\n"
+        );
+        for i in 0..5 {
+            assert!(script
+                .with_file_name(format!("frame_{:05}.png", i))
+                .exists());
+        }
+    }
+
+    #[test]
+    fn encoding_args_per_codec() {
+        let video = Path::new("video.mp4");
+        for (codec, crf, preset, expected) in [
+            (
+                VideoCodec::H264,
+                None,
+                None,
+                "-c:v libx264 -crf 18 -pix_fmt yuv420p",
+            ),
+            (
+                VideoCodec::Hevc,
+                None,
+                None,
+                "-c:v libx265 -crf 20 -pix_fmt yuv420p -tag:v hvc1",
+            ),
+            (
+                VideoCodec::Av1,
+                None,
+                None,
+                "-c:v libsvtav1 -crf 28 -pix_fmt yuv420p",
+            ),
+            (
+                VideoCodec::H264,
+                Some(23),
+                Some("slow"),
+                "-c:v libx264 -crf 23 -preset slow -pix_fmt yuv420p",
+            ),
+            (
+                VideoCodec::Av1,
+                Some(35),
+                Some("6"),
+                "-c:v libsvtav1 -crf 35 -preset 6 -pix_fmt yuv420p",
+            ),
+        ] {
+            let encoding = VideoEncoding {
+                codec,
+                crf,
+                preset: preset.map(String::from),
+                match_source_bitrate: false,
+            };
+            let mut warnings = Vec::new();
+            assert_eq!(
+                encoding
+                    .args(video, &Tools::default(), &mut warnings)
+                    .join(" "),
+                expected
+            );
+            assert!(warnings.is_empty());
+        }
+    }
+
+    #[test]
+    fn unprobeable_bitrate_falls_back_to_crf() {
+        let dir = tempfile::tempdir().unwrap();
+        let encoding = VideoEncoding {
+            match_source_bitrate: true,
+            ..Default::default()
+        };
+        let mut warnings = Vec::new();
+
+        let tools = Tools {
+            ffprobe: dir.path().join("ffprobe"),
+            ..Default::default()
+        };
+        let args = encoding.args(&dir.path().join("missing.mp4"), &tools, &mut warnings);
+        assert_eq!(args.join(" "), "-c:v libx264 -crf 18 -pix_fmt yuv420p");
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("Failed to probe the bitrate"));
+    }
+}
@@ -0,0 +1,140 @@
+//! Re-encoding processed photos and videos into more compact formats.
+//!
+//! Photos are converted with `heif-enc` (libheif) for AVIF and HEIC and with
+//! ffmpeg for WebP; videos with ffmpeg. Neither keeps every tag snapback
+//! writes, so the pipeline writes the metadata again onto the converted file.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use clap::ValueEnum;
+
+use crate::artifact::composite_temp_path;
+use crate::composite::{VideoCodec, VideoEncoding};
+use crate::report::{ErrorKind, Failure};
+use crate::tools::Tools;
+
+/// Quality (0-100) photos are converted to HEIC and AVIF with, libheif's
+/// default, which is around the size of an iPhone HEIC
+const HEIF_QUALITY: u8 = 50;
+
+/// Quality (0-100) photos are converted to WebP with
+const WEBP_QUALITY: u8 = 80;
+
+/// Format photos are converted to after compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum ImageConversion {
+    /// Keep photos in the format they were exported in
+    #[default]
+    Keep,
+    /// AVIF, via heif-enc
+    Avif,
+    /// HEIC, the format iPhones take photos in, via heif-enc
+    Heic,
+    /// Lossy WebP, via ffmpeg
+    Webp,
+}
+
+impl ImageConversion {
+    /// Extension of converted photos, or `None` to keep them
+    pub fn extension(self) -> Option<&'static str> {
+        match self {
+            ImageConversion::Keep => None,
+            ImageConversion::Avif => Some("avif"),
+            ImageConversion::Heic => Some("heic"),
+            ImageConversion::Webp => Some("webp"),
+        }
+    }
+}
+
+/// Codec videos are converted to after compositing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum VideoConversion {
+    /// Keep videos in the codec they were exported in
+    #[default]
+    Keep,
+    /// H.265/HEVC
+    Hevc,
+    /// AV1
+    Av1,
+}
+
+impl VideoConversion {
+    /// Codec to convert videos to, or `None` to keep them
+    pub fn codec(self) -> Option<VideoCodec> {
+        match self {
+            VideoConversion::Keep => None,
+            VideoConversion::Hevc => Some(VideoCodec::Hevc),
+            VideoConversion::Av1 => Some(VideoCodec::Av1),
+        }
+    }
+}
+
+/// Convert the photo at `path` to `format`, writing it to `output`
+pub fn image(
+    path: &Path,
+    output: &Path,
+    format: ImageConversion,
+    tools: &Tools,
+) -> Result<(), Failure> {
+    let cmd = match format {
+        ImageConversion::Keep => return Ok(()),
+        ImageConversion::Avif | ImageConversion::Heic => {
+            let mut cmd = Command::new(&tools.heif_enc);
+            cmd.arg("-q").arg(HEIF_QUALITY.to_string());
+            if format == ImageConversion::Avif {
+                cmd.arg("-A");
+            }
+            cmd.arg(path).arg("-o");
+            cmd
+        }
+        ImageConversion::Webp => {
+            let mut cmd = Command::new(&tools.ffmpeg);
+            cmd.args(["-y", "-loglevel", "error", "-i"])
+                .arg(path)
+                .args(["-frames:v", "1", "-c:v", "libwebp", "-quality"])
+                .arg(WEBP_QUALITY.to_string());
+            cmd
+        }
+    };
+    run(cmd, path, output)
+}
+
+/// Re-encode the video at `path` as `encoding` asks, writing it to `output`
+pub fn video(
+    path: &Path,
+    output: &Path,
+    encoding: &VideoEncoding,
+    tools: &Tools,
+    warnings: &mut Vec<String>,
+) -> Result<(), Failure> {
+    let mut cmd = Command::new(&tools.ffmpeg);
+    cmd.args(["-y", "-loglevel", "error", "-i"])
+        .arg(path)
+        .args(["-map", "0:v:0", "-map", "0:a?"])
+        .args(encoding.args(path, tools, warnings))
+        .args(["-map_metadata", "0", "-movflags", "use_metadata_tags"])
+        .args(["-c:a", "copy"]);
+    run(cmd, path, output)
+}
+
+/// Run `cmd` with a temporary output path appended and put the result in
+/// place at `output` once it succeeds
+fn run(mut cmd: Command, path: &Path, output: &Path) -> Result<(), Failure> {
+    let tool = cmd.get_program().to_string_lossy().into_owned();
+    // The tools pick the output format from the extension, so keep it
+    let temp_output = composite_temp_path(output);
+    let status = cmd.arg(&temp_output).status();
+
+    let result = match status {
+        Ok(s) if s.success() => fs::rename(&temp_output, output)
+            .map_err(|e| format!("Failed to finalize converted file {:?}: {}", output, e)),
+        Ok(_) => Err(format!("{} failed to convert {:?}", tool, path)),
+        Err(e) => Err(format!("Failed to run {} for {:?}: {}", tool, path, e)),
+    };
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_output);
+    }
+    result.map_err(|e| Failure::new(ErrorKind::Convert, e))
+}
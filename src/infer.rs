@@ -0,0 +1,225 @@
+//! Filling in missing memory locations from the memories taken around them.
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::memories::{Coordinates, Media};
+use crate::timezone::distance_km;
+
+/// Written as `GPSProcessingMethod` on files whose location was inferred, when
+/// marking is enabled
+pub const INFERRED_LOCATION_METHOD: &str = "Inferred from neighbouring memories";
+
+/// Thresholds for inferring a memory's location from its neighbours in time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LocationInference {
+    /// Only memories taken at most this long before or after are used
+    pub max_gap: TimeDelta,
+    /// When there is a located memory both before and after, they must be at
+    /// most this far apart for the position in between to be trusted
+    pub max_distance_km: f64,
+}
+
+impl Default for LocationInference {
+    fn default() -> Self {
+        Self {
+            max_gap: TimeDelta::minutes(60),
+            max_distance_km: 10.0,
+        }
+    }
+}
+
+impl LocationInference {
+    /// Fill in the missing (or `0, 0`) locations in `media` and mark them as
+    /// inferred. Returns how many were filled in.
+    ///
+    /// With a located memory on both sides within [`Self::max_gap`], the
+    /// position is interpolated between them by time, unless they are more
+    /// than [`Self::max_distance_km`] apart. With only one, its location is
+    /// used as is. Only exported locations are used as anchors, never
+    /// inferred ones.
+    pub fn apply(&self, media: &mut [Media]) -> usize {
+        let mut order: Vec<usize> = (0..media.len()).collect();
+        order.sort_by_key(|&i| media[i].date);
+
+        let anchors: Vec<Option<(DateTime<Utc>, Coordinates)>> = order
+            .iter()
+            .map(|&i| {
+                let m = &media[i];
+                m.coordinate
+                    .clone()
+                    .filter(|c| !c.is_null_island())
+                    .map(|c| (m.date, c))
+            })
+            .collect();
+
+        // Nearest anchor at or before / at or after each position
+        let mut previous = Vec::with_capacity(anchors.len());
+        let mut last = None;
+        for anchor in &anchors {
+            last = anchor.as_ref().or(last);
+            previous.push(last);
+        }
+        let mut next = vec![None; anchors.len()];
+        let mut last = None;
+        for (pos, anchor) in anchors.iter().enumerate().rev() {
+            last = anchor.as_ref().or(last);
+            next[pos] = last;
+        }
+
+        let mut inferred = 0;
+        for (pos, &i) in order.iter().enumerate() {
+            if anchors[pos].is_some() {
+                continue;
+            }
+            let date = media[i].date;
+            let within_gap =
+                |anchor: &&(DateTime<Utc>, Coordinates)| (anchor.0 - date).abs() <= self.max_gap;
+            let before = previous[pos].filter(within_gap);
+            let after = next[pos].filter(within_gap);
+
+            let coordinates = match (before, after) {
+                (Some(before), Some(after)) => match self.interpolate(before, after, date) {
+                    Some(coordinates) => coordinates,
+                    None => continue,
+                },
+                (Some(anchor), None) | (None, Some(anchor)) => anchor.1.clone(),
+                (None, None) => continue,
+            };
+            media[i].coordinate = Some(coordinates);
+            media[i].location_inferred = true;
+            inferred += 1;
+        }
+        inferred
+    }
+
+    fn interpolate(
+        &self,
+        (before_date, before): &(DateTime<Utc>, Coordinates),
+        (after_date, after): &(DateTime<Utc>, Coordinates),
+        date: DateTime<Utc>,
+    ) -> Option<Coordinates> {
+        if distance_km(before.lat, before.lon, after.lat, after.lon) > self.max_distance_km {
+            return None;
+        }
+        let span = (*after_date - *before_date).num_milliseconds();
+        let t = if span == 0 {
+            0.0
+        } else {
+            (date - *before_date).num_milliseconds() as f64 / span as f64
+        };
+        Some(Coordinates {
+            lat: before.lat + t * (after.lat - before.lat),
+            lon: before.lon + t * (after.lon - before.lon),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memories::MediaType;
+
+    const START: i64 = 1_625_315_696;
+
+    /// A memory taken `minutes` after [`START`]
+    fn memory(minutes: i64, coordinate: Option<(f64, f64)>) -> Media {
+        Media {
+            date: DateTime::from_timestamp(START + minutes * 60, 0).unwrap(),
+            media_type: MediaType::Image,
+            coordinate: coordinate.map(|(lat, lon)| Coordinates { lat, lon }),
+            location_inferred: false,
+            id: format!("memory-{}", minutes),
+        }
+    }
+
+    fn inferred(inference: LocationInference, mut media: Vec<Media>) -> Vec<Option<(f64, f64)>> {
+        inference.apply(&mut media);
+        media
+            .iter()
+            .map(|m| {
+                m.coordinate
+                    .as_ref()
+                    .filter(|_| m.location_inferred)
+                    .map(|c| (c.lat, c.lon))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn neighbours_are_used_up_to_max_gap() {
+        let inference = LocationInference {
+            max_gap: TimeDelta::minutes(30),
+            ..Default::default()
+        };
+        for (gap, expected) in [
+            (0, Some((40.0, -74.0))),
+            (29, Some((40.0, -74.0))),
+            (30, Some((40.0, -74.0))),
+            (31, None),
+        ] {
+            // On either side
+            let before = vec![memory(0, Some((40.0, -74.0))), memory(gap, None)];
+            assert_eq!(inferred(inference, before)[1], expected, "{} after", gap);
+            let after = vec![memory(0, None), memory(gap, Some((40.0, -74.0)))];
+            assert_eq!(inferred(inference, after)[0], expected, "{} before", gap);
+        }
+    }
+
+    #[test]
+    fn positions_are_interpolated_up_to_max_distance_km() {
+        let (before, after) = ((40.0, -74.0), (40.1, -74.0));
+        let apart = distance_km(before.0, before.1, after.0, after.1);
+        for (max_distance_km, expected) in [
+            (apart + 0.001, Some((40.025, -74.0))),
+            (apart, Some((40.025, -74.0))),
+            (apart - 0.001, None),
+        ] {
+            let inference = LocationInference {
+                max_distance_km,
+                ..Default::default()
+            };
+            let media = vec![
+                memory(0, Some(before)),
+                memory(10, None),
+                memory(40, Some(after)),
+            ];
+            let found = inferred(inference, media)[1];
+            match (found, expected) {
+                (Some(found), Some(expected)) => {
+                    assert!((found.0 - expected.0).abs() < 1e-9, "{:?}", found);
+                    assert_eq!(found.1, expected.1);
+                }
+                _ => assert_eq!(found, expected, "{} km", max_distance_km),
+            }
+        }
+    }
+
+    #[test]
+    fn only_exported_locations_are_anchors() {
+        let mut media = vec![
+            memory(0, Some((40.0, -74.0))),
+            memory(50, None),
+            memory(100, Some((0.0, 0.0))),
+            memory(130, Some((51.5, -0.1))),
+        ];
+        // Were the second memory's inferred location an anchor, the third
+        // would lie between it and London, too far apart to interpolate
+        let filled = LocationInference::default().apply(&mut media);
+        assert_eq!(filled, 2);
+        assert_eq!(
+            media[1].coordinate,
+            Some(Coordinates {
+                lat: 40.0,
+                lon: -74.0
+            })
+        );
+        assert_eq!(
+            media[2].coordinate,
+            Some(Coordinates {
+                lat: 51.5,
+                lon: -0.1
+            })
+        );
+        assert!(media[2].location_inferred && !media[3].location_inferred);
+    }
+}
@@ -0,0 +1,381 @@
+//! Persistent record of per-file progress so interrupted runs can be resumed.
+//!
+//! The journal is an append-only JSON lines file in the output directory. Every
+//! time a stage finishes for a file, the file's full [`Entry`] is appended;
+//! when loading, the last line for each file wins. Appending keeps the cost of
+//! a write constant no matter how large the export is, and a line truncated by
+//! a crash is simply ignored.
+//!
+//! Each entry also stores a [`fingerprint`] of the file as it was first seen
+//! and after its last completed stage. That is what makes resuming safe: a
+//! file that was re-extracted from the archives matches its original
+//! fingerprint and is processed from scratch, while an overlay that was
+//! renamed into place just before a crash shows up as a file that changed
+//! while the overlay was in flight.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the journal file created inside the output directory
+pub const JOURNAL_FILE_NAME: &str = ".snapback-state.jsonl";
+
+/// Stages completed for a single exported file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Fingerprint of the file when it was first seen
+    pub original: Option<u64>,
+    /// Fingerprint of the file after its last completed stage
+    pub current: Option<u64>,
+    #[serde(default)]
+    pub exif: bool,
+    #[serde(default)]
+    pub overlay: bool,
+    /// Set while the overlay is being composited so a crash before the
+    /// journal is updated cannot cause the overlay to be applied twice
+    #[serde(default)]
+    pub overlay_pending: bool,
+    /// The file and its overlaid copy were converted to another format
+    #[serde(default)]
+    pub converted: bool,
+    /// The overlaid copy of the file, converted if the file was. Recorded
+    /// because the options that name it may change between runs.
+    pub overlaid_copy: Option<PathBuf>,
+    /// The converted file that replaces this one
+    pub converted_file: Option<PathBuf>,
+    /// Where the file was moved to, once it has been
+    pub destination: Option<PathBuf>,
+}
+
+impl Entry {
+    fn fresh(fingerprint: Option<u64>) -> Self {
+        Self {
+            original: fingerprint,
+            current: fingerprint,
+            ..Default::default()
+        }
+    }
+
+    /// The file was moved in an earlier run and is still at its destination
+    pub fn is_moved(&self) -> bool {
+        self.destination.as_ref().is_some_and(|d| d.exists())
+    }
+
+    /// Names of the stages that will not be repeated
+    pub fn completed_stages(&self) -> Vec<&'static str> {
+        let mut stages = Vec::new();
+        if self.exif {
+            stages.push("exif");
+        }
+        if self.overlay {
+            stages.push("overlay");
+        }
+        if self.converted {
+            stages.push("convert");
+        }
+        if self.is_moved() {
+            stages.push("move");
+        }
+        stages
+    }
+
+    /// Bring a journaled entry in line with the file as it is on disk now.
+    ///
+    /// Returns the entry to continue from and whether the file was changed by
+    /// something other than snapback, in which case it starts over.
+    pub fn reconcile(entry: Option<Entry>, fingerprint: Option<u64>) -> (Entry, bool) {
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => return (Entry::fresh(fingerprint), false),
+        };
+
+        if entry.is_moved() {
+            return (entry, false);
+        }
+
+        if fingerprint.is_some() && fingerprint == entry.current {
+            // Nothing happened since the last record, so a pending overlay
+            // never got renamed into place
+            entry.overlay_pending = false;
+            (entry, false)
+        } else if fingerprint.is_some() && fingerprint == entry.original {
+            // Re-extracted from the archive
+            (Entry::fresh(fingerprint), false)
+        } else if entry.overlay_pending {
+            // The only change made while an overlay is pending is the
+            // composite being renamed over the file
+            entry.overlay = true;
+            entry.overlay_pending = false;
+            entry.current = fingerprint;
+            (entry, false)
+        } else {
+            (Entry::fresh(fingerprint), true)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Line {
+    path: String,
+    #[serde(flatten)]
+    entry: Entry,
+}
+
+/// Journal of completed stages, shared between worker threads
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, Entry>>,
+    log: Mutex<File>,
+}
+
+impl Journal {
+    /// Read the entries of an existing journal without opening it for writing.
+    /// A missing journal has no entries.
+    pub fn load(path: &Path) -> io::Result<HashMap<String, Entry>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            // A partially written last line is expected after a crash
+            if let Ok(Line { path, entry }) = serde_json::from_str::<Line>(&line?) {
+                entries.insert(path, entry);
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Open the journal at `path`, compacting it to one line per file
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let entries = Self::load(path)?;
+
+        let compacted = path.with_extension("jsonl.tmp");
+        {
+            let mut out = io::BufWriter::new(File::create(&compacted)?);
+            for (key, entry) in &entries {
+                write_line(&mut out, key, entry)?;
+            }
+            out.flush()?;
+        }
+        fs::rename(&compacted, path)?;
+
+        let log = OpenOptions::new().append(true).open(path)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: Mutex::new(entries),
+            log: Mutex::new(log),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The entry to continue processing `file` from; see [`Entry::reconcile`]
+    pub fn resume(&self, key: &str, file: &Path) -> (Entry, bool) {
+        let previous = self.entries.lock().unwrap().get(key).cloned();
+        Entry::reconcile(previous, fingerprint(file).ok())
+    }
+
+    /// Record `entry` for `key`, refreshing its fingerprint from `file` first
+    pub fn update(&self, key: &str, entry: &mut Entry, file: &Path) -> io::Result<()> {
+        entry.current = fingerprint(file).ok();
+        self.record(key, entry)
+    }
+
+    /// Record that the file for `key` was moved to `destination`
+    pub fn mark_moved(&self, key: &str, destination: &Path) -> io::Result<()> {
+        let mut entry = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .unwrap_or_default();
+        entry.destination = Some(destination.to_path_buf());
+        self.record(key, &entry)
+    }
+
+    fn record(&self, key: &str, entry: &Entry) -> io::Result<()> {
+        self.entries
+            .lock()
+            .unwrap()
+            .insert(key.to_string(), entry.clone());
+
+        let mut log = self.log.lock().unwrap();
+        write_line(&mut *log, key, entry)?;
+        log.flush()
+    }
+}
+
+fn write_line(out: &mut impl Write, key: &str, entry: &Entry) -> io::Result<()> {
+    let line = Line {
+        path: key.to_string(),
+        entry: entry.clone(),
+    };
+    serde_json::to_writer(&mut *out, &line)?;
+    out.write_all(b"\n")
+}
+
+/// Cheap content fingerprint: the file size plus an FNV-1a hash of its first
+/// and last 64 KiB. Both writing metadata (headers) and compositing (the whole
+/// file) change at least one of those regions.
+pub fn fingerprint(path: &Path) -> io::Result<u64> {
+    const WINDOW: u64 = 64 * 1024;
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+
+    let mut buf = Vec::with_capacity(2 * WINDOW as usize);
+    (&mut file).take(WINDOW).read_to_end(&mut buf)?;
+    if len > WINDOW {
+        file.seek(SeekFrom::Start(len.saturating_sub(WINDOW).max(WINDOW)))?;
+        file.take(WINDOW).read_to_end(&mut buf)?;
+    }
+
+    let hash = buf
+        .iter()
+        .chain(len.to_le_bytes().iter())
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+        });
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(original: u64, current: u64) -> Entry {
+        Entry {
+            original: Some(original),
+            current: Some(current),
+            exif: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unchanged_files_resume_where_they_stopped() {
+        let mut pending = entry(1, 2);
+        pending.overlay_pending = true;
+        let (resumed, changed) = Entry::reconcile(Some(pending), Some(2));
+        assert!(!changed);
+        assert!(resumed.exif && !resumed.overlay && !resumed.overlay_pending);
+
+        assert_eq!(
+            Entry::reconcile(None, Some(1)),
+            (Entry::fresh(Some(1)), false)
+        );
+    }
+
+    #[test]
+    fn changed_files_start_over() {
+        // Extracted from the archive again
+        assert_eq!(
+            Entry::reconcile(Some(entry(1, 2)), Some(1)),
+            (Entry::fresh(Some(1)), false)
+        );
+        // Edited by something else, or gone
+        assert_eq!(
+            Entry::reconcile(Some(entry(1, 2)), Some(3)),
+            (Entry::fresh(Some(3)), true)
+        );
+        assert_eq!(
+            Entry::reconcile(Some(entry(1, 2)), None),
+            (Entry::fresh(None), true)
+        );
+    }
+
+    #[test]
+    fn a_pending_overlay_that_changed_the_file_was_applied() {
+        let mut pending = entry(1, 2);
+        pending.overlay_pending = true;
+        let (resumed, changed) = Entry::reconcile(Some(pending), Some(3));
+        assert!(!changed);
+        assert!(resumed.exif && resumed.overlay && !resumed.overlay_pending);
+        assert_eq!(resumed.current, Some(3));
+    }
+
+    #[test]
+    fn moved_files_are_only_skipped_while_their_destination_exists() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = dir.path().join("moved.jpg");
+        let mut moved = entry(1, 2);
+        moved.destination = Some(destination.clone());
+
+        // The source is gone once moved, but the destination is missing too
+        let (resumed, changed) = Entry::reconcile(Some(moved.clone()), None);
+        assert!(changed && !resumed.is_moved() && !resumed.exif);
+        let (resumed, _) = Entry::reconcile(Some(moved.clone()), Some(2));
+        assert!(!resumed.is_moved() && resumed.exif);
+
+        fs::write(&destination, b"").unwrap();
+        assert_eq!(
+            Entry::reconcile(Some(moved.clone()), None),
+            (moved.clone(), false)
+        );
+        assert_eq!(moved.completed_stages(), ["exif", "move"]);
+    }
+
+    #[test]
+    fn fingerprints_cover_the_head_the_tail_and_the_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("media");
+        let data = vec![7u8; 256 * 1024];
+        fs::write(&path, &data).unwrap();
+        let original = fingerprint(&path).unwrap();
+
+        let changed = |at: usize| {
+            let mut data = data.clone();
+            data[at] = 8;
+            fs::write(&path, &data).unwrap();
+            fingerprint(&path).unwrap()
+        };
+        assert_ne!(changed(10), original);
+        assert_ne!(changed(data.len() - 10), original);
+        // Only the first and last 64 KiB are hashed
+        assert_eq!(changed(128 * 1024), original);
+
+        fs::write(&path, &data[1..]).unwrap();
+        assert_ne!(fingerprint(&path).unwrap(), original);
+    }
+
+    #[test]
+    fn opening_compacts_to_the_last_entry_of_each_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(JOURNAL_FILE_NAME);
+        let mut log = Vec::new();
+        for (key, entry) in [("a", entry(1, 1)), ("b", entry(5, 5)), ("a", entry(1, 2))] {
+            write_line(&mut log, key, &entry).unwrap();
+        }
+        // Cut short by a crash
+        log.extend_from_slice(b"{\"path\":\"b\",\"orig");
+        fs::write(&path, log).unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        let compacted = fs::read_to_string(&path).unwrap();
+        assert_eq!(compacted.lines().count(), 2);
+        let entries = Journal::load(&path).unwrap();
+        assert_eq!(entries["a"], entry(1, 2));
+        assert_eq!(entries["b"], entry(5, 5));
+
+        journal.mark_moved("b", &dir.path().join("b.jpg")).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        assert_eq!(
+            Journal::load(&path).unwrap()["b"].destination,
+            Some(dir.path().join("b.jpg"))
+        );
+        assert!(!dir.path().join(".snapback-state.jsonl.tmp").exists());
+    }
+}
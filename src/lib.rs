@@ -0,0 +1,44 @@
+//! Restore metadata and overlays to Snapchat memory exports.
+//!
+//! The [`Pipeline`] runs the same steps as the `snapback` CLI: unzip the
+//! export, parse `memories_history.json` (or the chat history files), write
+//! metadata, composite overlays (in-process for photos, with ffmpeg for
+//! videos) and move the results into an output directory. Each step is also
+//! exposed on its own for callers that only need part of it.
+
+pub mod artifact;
+pub mod bundle;
+pub mod chat;
+pub mod composite;
+pub mod convert;
+pub mod infer;
+pub mod journal;
+pub mod memories;
+pub mod metadata;
+pub mod pipeline;
+pub mod plan;
+pub mod record;
+pub mod rename;
+pub mod report;
+pub mod timezone;
+pub mod tools;
+pub mod transfer;
+pub mod unzip;
+
+pub use artifact::{Artifact, ArtifactKind};
+pub use bundle::MemoryBundle;
+pub use chat::{
+    parse_chat_history_file, parse_chat_media_id_from_stem, ChatHistory, ChatMedia, ChatMessage,
+};
+pub use infer::LocationInference;
+pub use memories::{
+    parse_id_from_stem, parse_memories_history_file, Coordinates, LocationPolicy, Media, MediaType,
+    MemoriesHistory,
+};
+pub use pipeline::{Error, ExportSource, Layout, OverlayMode, Pipeline, ProcessOptions};
+pub use plan::{Plan, PlannedFile, PlannedOverlay};
+pub use record::Record;
+pub use rename::RenameTemplate;
+pub use report::{ErrorKind, Failure, FileOutcome, Report, StepOutcome, Summary};
+pub use tools::Tools;
+pub use transfer::Transfer;
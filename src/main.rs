@@ -1,27 +1,17 @@
-use clap::{Parser, ValueEnum};
-use std::collections::HashMap;
-use std::fs;
-use std::path::Path;
-use std::path::PathBuf;
-use std::process::Command;
-
-use chrono::{DateTime, NaiveDateTime, Utc};
-use glob::glob;
-use indicatif::{ProgressBar, ProgressStyle};
-use rayon::prelude::*;
-use ripunzip::{NullProgressReporter, UnzipEngine, UnzipOptions};
-use serde::Deserializer;
-use serde::{Deserialize, Serialize};
-
-#[derive(Debug, Clone, ValueEnum)]
-enum OverlayMode {
-    /// Apply overlay directly to the original file
-    Overwrite,
-    /// Create an _overlaid copy while preserving the original
-    Copy,
-    /// Skip overlays entirely
-    Ignore,
-}
+use chrono::TimeDelta;
+use chrono_tz::Tz;
+use clap::Parser;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use snapback::artifact::OVERLAY_SUFFIX;
+use snapback::composite::{VideoCodec, VideoEncoding, DEFAULT_IMAGE_QUALITY};
+use snapback::convert::{ImageConversion, VideoConversion};
+use snapback::metadata::{MetadataBackend, MetadataMode, TagProfile};
+use snapback::{
+    ExportSource, Layout, LocationInference, LocationPolicy, OverlayMode, Pipeline, ProcessOptions,
+    RenameTemplate, Transfer,
+};
 
 #[derive(Debug, Parser)]
 #[command(
@@ -31,18 +21,41 @@ enum OverlayMode {
 Restore metadata and overlays to Snapchat memory exports.\n\n\
 Snapback processes a Snapchat data export by:\n\n\
 1. Unzipping exported archive(s) (by default unzips all .zip files in the --zip-dir)\n\
-2. Parsing memories_history.json for dates and GPS coordinates\n\
-3. Writing EXIF/metadata back onto each photo and video via exiftool\n\
-4. Optionally compositing overlay PNGs (captions, stickers, drawings) onto\n\
-   the original media using ffmpeg\n\
-5. Moving the processed files into an output directory\n\n\
-External dependencies: exiftool, ffmpeg"
+2. Parsing memories_history.json for dates and GPS coordinates (or\n\
+   chat_history.json/snap_history.json for send times with --source chat)\n\
+3. Writing EXIF/metadata back onto each photo and video (natively for\n\
+   JPEGs and MP4s, via exiftool otherwise)\n\
+4. Optionally compositing overlay layers (captions, stickers, drawings) onto\n\
+   the original media (in-process for photos, with ffmpeg for videos)\n\
+5. Optionally converting photos and videos to more compact formats\n\
+6. Moving the processed files into an output directory\n\n\
+External dependencies: exiftool, ffmpeg (videos with overlays only)"
 )]
 struct Args {
+    /// Which part of the export to process
+    #[arg(short, long, value_enum, default_value_t = ExportSource::Memories)]
+    source: ExportSource,
+
     /// How to handle overlays (captions, drawings, stickers, etc.)
     #[arg(short, long, value_enum, default_value_t = OverlayMode::Overwrite)]
     overlays: OverlayMode,
 
+    /// How to write metadata: natively for JPEGs and MP4s (exiftool for
+    /// everything else), or with exiftool for every file
+    #[arg(long, value_enum, default_value_t = MetadataBackend::Native)]
+    metadata_backend: MetadataBackend,
+
+    /// Where to write metadata: into the media files, or into an XMP sidecar
+    /// (<file>.xmp) next to each one, leaving the media untouched (overlays
+    /// are composited onto copies, and conversions aren't allowed)
+    #[arg(long, value_enum, default_value_t = MetadataMode::Embedded)]
+    metadata_mode: MetadataMode,
+
+    /// Which tags to write: everything photo libraries read, only the
+    /// capture date and location, or only XMP properties
+    #[arg(long, value_enum, default_value_t = TagProfile::PhotosApp)]
+    tag_profile: TagProfile,
+
     /// Number of concurrent exiftool/ffmpeg processes
     #[arg(short, long, default_value_t = 1)]
     processes: usize,
@@ -55,6 +68,70 @@ struct Args {
     #[arg(short = 'd', long, default_value = "./processed_media")]
     output_dir: PathBuf,
 
+    /// Added to the file names of overlaid copies (copy overlay mode)
+    #[arg(long, default_value = OVERLAY_SUFFIX)]
+    overlay_suffix: String,
+
+    /// Move overlaid copies (copy overlay mode) into this directory instead of
+    /// --output-dir, e.g. "./processed_media/with_overlays"
+    #[arg(long)]
+    overlay_output_dir: Option<PathBuf>,
+
+    /// JPEG quality (1-100) photos are re-encoded with after compositing
+    /// their overlay
+    #[arg(long, value_name = "QUALITY", default_value_t = DEFAULT_IMAGE_QUALITY,
+          value_parser = clap::value_parser!(u8).range(1..=100))]
+    image_quality: u8,
+
+    /// Codec videos are re-encoded with after compositing their overlay
+    #[arg(long, value_enum, default_value_t = VideoCodec::H264)]
+    video_codec: VideoCodec,
+
+    /// Constant rate factor for re-encoded videos, lower is better [default:
+    /// 18 for h264, 20 for hevc, 28 for av1]
+    #[arg(long, value_name = "CRF", value_parser = clap::value_parser!(u8).range(0..=63))]
+    video_crf: Option<u8>,
+
+    /// Encoder preset for re-encoded videos, e.g. "slow" for h264/hevc or
+    /// "6" for av1 [default: the encoder's]
+    #[arg(long, value_name = "PRESET")]
+    video_preset: Option<String>,
+
+    /// Re-encode videos at the bitrate of the source (probed with ffprobe)
+    /// instead of at a constant quality
+    #[arg(long, conflicts_with = "video_crf")]
+    match_source_bitrate: bool,
+
+    /// Convert photos to a more compact format before moving them, keeping
+    /// their metadata (avif and heic need heif-enc, webp needs ffmpeg)
+    #[arg(long, value_enum, default_value_t = ImageConversion::Keep)]
+    convert_images: ImageConversion,
+
+    /// Convert videos to a more compact codec with ffmpeg before moving
+    /// them, keeping their metadata (uses --video-crf and --video-preset)
+    #[arg(long, value_enum, default_value_t = VideoConversion::Keep)]
+    convert_videos: VideoConversion,
+
+    /// Name output files after a template instead of keeping their exported
+    /// names, e.g. "{date:%Y%m%d_%H%M%S}_{id_short}{overlay_suffix}.{ext}".
+    /// Placeholders: date (with an optional strftime format), id, id_short,
+    /// media_type, overlay_suffix, stem and ext
+    #[arg(long, value_name = "TEMPLATE")]
+    rename: Option<RenameTemplate>,
+
+    /// How to sort files into subdirectories of --output-dir: by local
+    /// capture date, or into images/ and videos/
+    #[arg(long, value_enum, default_value_t = Layout::Flat)]
+    layout: Layout,
+
+    /// How to get files into --output-dir: move them (falling back to copy
+    /// and delete across file systems), copy them, leaving the extracted
+    /// export intact, or hard link or reflink them so they also stay where
+    /// they are (processed in place all the same). Copies are
+    /// checksum-verified
+    #[arg(long, value_enum, default_value_t = Transfer::Move)]
+    transfer: Transfer,
+
     /// Skip the unzip step (use if .zip files are already extracted)
     #[arg(long, default_value_t = false)]
     skip_unzip: bool,
@@ -63,462 +140,165 @@ struct Args {
     #[arg(short = 'j', long, default_value = "./json/memories_history.json")]
     memories_history_json_path: PathBuf,
 
-    /// Directory name prefix to glob for media files (e.g. "memories" matches "memories*/**/*.jpg")
-    #[arg(short, long, default_value = "memories")]
-    media_prefix: String,
-}
+    /// Path to the "chat_history.json" file from the export (chat source only)
+    #[arg(long, default_value = "./json/chat_history.json")]
+    chat_history_json_path: PathBuf,
 
-fn main() {
-    let args = Args::parse();
+    /// Path to the "snap_history.json" file from the export (chat source only)
+    #[arg(long, default_value = "./json/snap_history.json")]
+    snap_history_json_path: PathBuf,
 
-    // Set up parallel processing
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(args.processes)
-        .build_global()
-        .unwrap();
-
-    // Unzip logic (using ripunzip for parallel extraction)
-    if !args.skip_unzip {
-        let zip_dir = &args.zip_dir;
-        let zip_pattern = zip_dir.join("*.zip");
-        let zip_pattern_str = zip_pattern.to_str().expect("Invalid zip path pattern");
-
-        println!("Looking for zip files in: {}", zip_pattern_str);
-
-        for entry in glob(zip_pattern_str).expect("Failed to read glob pattern for zips") {
-            match entry {
-                Ok(path) => {
-                    println!("Unzipping {:?}", path);
-                    let zip_file = match fs::File::open(&path) {
-                        Ok(f) => f,
-                        Err(e) => {
-                            eprintln!("Failed to open zip {:?}: {}", path, e);
-                            continue;
-                        }
-                    };
-                    match UnzipEngine::for_file(zip_file) {
-                        Ok(engine) => {
-                            let options = UnzipOptions {
-                                output_directory: Some(PathBuf::from(".")),
-                                password: None,
-                                single_threaded: false,
-                                filename_filter: None,
-                                progress_reporter: Box::new(NullProgressReporter),
-                            };
-                            match engine.unzip(options) {
-                                Ok(()) => println!("Successfully unzipped {:?}", path),
-                                Err(e) => eprintln!("Unzip failed for {:?}: {}", path, e),
-                            }
-                        }
-                        Err(e) => eprintln!("Failed to open zip {:?}: {}", path, e),
-                    }
-                }
-                Err(e) => eprintln!("Glob error: {:?}", e),
-            }
-        }
-    }
+    /// Tag chat media with who the conversation was with (chat source only)
+    #[arg(long, default_value_t = false)]
+    tag_partner: bool,
+
+    /// Time zone capture times are written in, e.g. "Europe/Berlin". With
+    /// --timezone-lookup only for those that can't be localized from a
+    /// location (chat media, memories without one)
+    #[arg(long, default_value_t = Tz::UTC)]
+    timezone: Tz,
+
+    /// Convert capture times to the local time at the capture location
+    /// instead of --timezone. The zone is taken from the nearest of a set of
+    /// reference cities rather than from zone boundaries, so it is often the
+    /// neighbouring one, even in cities a few hundred kilometres from a
+    /// border (Lviv gets Warsaw's, Lahore gets India's).
+    #[arg(long, default_value_t = false)]
+    timezone_lookup: bool,
 
-    // Existing logic
-    if !args.memories_history_json_path.exists() {
-        eprintln!(
-            "Memories history file not found at {:?}. Did unzipping work?",
-            args.memories_history_json_path
-        );
-    }
+    /// What to write for memories without a location (Snapchat exports
+    /// "0.0, 0.0" for those): "skip" writes no GPS data, "keep" writes the
+    /// exported value anyway, or give a LAT,LON to write instead
+    #[arg(long, default_value = "skip")]
+    missing_location: LocationPolicy,
 
-    // Check if we can proceed
-    if !args.memories_history_json_path.exists() {
-        return;
-    }
+    /// Fill in missing locations from memories taken shortly before or after
+    /// (applied before --missing-location)
+    #[arg(long, default_value_t = false)]
+    infer_location: bool,
 
-    let memories_data = parse_memories_history_file(&args.memories_history_json_path).unwrap();
-
-    let media_map: HashMap<String, Media> = memories_data
-        .saved_media
-        .into_iter()
-        .map(|m| (m.id.clone(), m))
-        .collect();
-
-    let patterns = [
-        format!("{}*/**/*.jpg", args.media_prefix),
-        format!("{}*/**/*.mp4", args.media_prefix),
-    ];
-
-    // Collect paths to a vector for parallel iteration
-    let paths: Vec<PathBuf> = patterns
-        .iter()
-        .flat_map(|p| glob(p).expect("Failed to read glob pattern"))
-        .filter_map(Result::ok)
-        .collect();
-
-    let overlay_mode = &args.overlays;
-
-    let pb = ProgressBar::new(paths.len() as u64);
-    pb.set_style(
-        ProgressStyle::with_template(
-            "Processing {pos}/{len} [{wide_bar:.cyan/blue}] {percent}% ({eta})",
-        )
-        .unwrap()
-        .progress_chars("=> "),
-    );
-
-    paths.par_iter().for_each(|path| {
-        let file_name_str = path
-            .file_name()
-            .unwrap_or_default()
-            .to_str()
-            .unwrap_or("?")
-            .to_string();
-        let mut did_exif = false;
-        let mut did_overlay = false;
-
-        // 1. Apply EXIF metadata
-        if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
-            if let Some(id) = parse_id_from_stem(stem) {
-                if let Some(media) = media_map.get(&id) {
-                    let date_str = media.date.format("%Y:%m:%d %H:%M:%S").to_string();
-                    let lat_str = media.coordinate.lat.to_string();
-                    let lon_str = media.coordinate.lon.to_string();
-
-                    let status = Command::new("exiftool")
-                        .arg("-overwrite_original")
-                        .arg(format!("-DateTimeOriginal={}", date_str))
-                        .arg(format!("-GPSLatitude={}", lat_str))
-                        .arg(format!("-GPSLatitudeRef={}", lat_str))
-                        .arg(format!("-GPSLongitude={}", lon_str))
-                        .arg(format!("-GPSLongitudeRef={}", lon_str))
-                        .arg("-q")
-                        .arg(path)
-                        .status();
-
-                    match status {
-                        Ok(s) => {
-                            if s.success() {
-                                did_exif = true;
-                            } else {
-                                pb.println(format!("ExifTool failed for {:?}", path));
-                            }
-                        }
-                        Err(e) => {
-                            pb.println(format!("Failed to execute ExifTool for {:?}: {}", path, e))
-                        }
-                    }
-                }
-            }
-        }
+    /// With --infer-location, only use memories taken at most this many
+    /// minutes apart
+    #[arg(long, value_name = "MINUTES", default_value_t = 60)]
+    infer_max_gap: u32,
 
-        // 2. Apply overlay (after EXIF so metadata is already set)
-        if !matches!(overlay_mode, OverlayMode::Ignore) {
-            if let Some(parent) = path.parent() {
-                if let Some(file_name) = path.file_name().and_then(|n| n.to_str()) {
-                    let overlay_filename = if file_name.ends_with("-main.jpg") {
-                        Some(file_name.replace("-main.jpg", "-overlay.png"))
-                    } else if file_name.ends_with("-main.mp4") {
-                        Some(file_name.replace("-main.mp4", "-overlay.png"))
-                    } else {
-                        None
-                    };
-
-                    if let Some(overlay_name) = overlay_filename {
-                        let overlay_path = parent.join(overlay_name);
-                        if overlay_path.exists() {
-                            // Overlay files are named .png but contain WebP data;
-                            // convert to real PNG for ffmpeg (ffmpeg's native WebP decoder
-                            // can't handle lossy VP8 with a separate alpha channel).
-                            let converted_overlay = path.with_file_name(format!(
-                                "{}_overlay.png",
-                                path.file_stem().unwrap().to_str().unwrap()
-                            ));
-                            let overlay_to_use = match fs::read(&overlay_path) {
-                                Ok(bytes) => match image::load_from_memory(&bytes) {
-                                    Ok(img) => match img.save(&converted_overlay) {
-                                        Ok(()) => &converted_overlay,
-                                        Err(e) => {
-                                            pb.println(format!(
-                                                "Failed to save converted overlay: {}",
-                                                e
-                                            ));
-                                            &overlay_path
-                                        }
-                                    },
-                                    Err(e) => {
-                                        pb.println(format!(
-                                            "Failed to decode overlay {:?}: {}",
-                                            overlay_path, e
-                                        ));
-                                        &overlay_path
-                                    }
-                                },
-                                Err(e) => {
-                                    pb.println(format!(
-                                        "Failed to read overlay file {:?}: {}",
-                                        overlay_path, e
-                                    ));
-                                    &overlay_path
-                                }
-                            };
-
-                            let ext = path.extension().unwrap_or_default().to_str().unwrap_or("");
-                            let stem = path.file_stem().unwrap().to_str().unwrap();
-                            let (input_path, final_output) = match overlay_mode {
-                                OverlayMode::Copy => {
-                                    let overlaid = path
-                                        .with_file_name(format!("{}_with_overlay.{}", stem, ext));
-                                    if let Err(e) = fs::copy(path, &overlaid) {
-                                        pb.println(format!(
-                                            "Failed to copy {:?} for overlay: {}",
-                                            path, e
-                                        ));
-                                        let _ = fs::remove_file(&converted_overlay);
-                                        pb.inc(1);
-                                        return;
-                                    }
-                                    (overlaid.clone(), overlaid)
-                                }
-                                OverlayMode::Overwrite => (path.to_path_buf(), path.to_path_buf()),
-                                OverlayMode::Ignore => unreachable!(),
-                            };
-
-                            let temp_output = path.with_file_name(format!("{}_temp.{}", stem, ext));
-
-                            let is_video = file_name.ends_with(".mp4");
-
-                            let mut cmd = Command::new("ffmpeg");
-                            cmd.arg("-y")
-                                .arg("-loglevel")
-                                .arg("error")
-                                .arg("-i")
-                                .arg(&input_path);
-
-                            if is_video {
-                                cmd.arg("-loop").arg("1");
-                            }
-
-                            cmd.arg("-i").arg(overlay_to_use);
-
-                            if is_video {
-                                cmd.arg("-shortest");
-                            }
-
-                            if is_video {
-                                cmd.arg("-filter_complex")
-                                    .arg("[1:v][0:v]scale=rw:rh[ol];[0:v][ol]overlay=0:0");
-                                cmd.arg("-c:a").arg("copy");
-                            } else {
-                                cmd.arg("-filter_complex")
-                                    .arg("[1:v][0:v]scale=rw:rh[ol];[0:v][ol]overlay=0:0");
-                                cmd.arg("-pix_fmt").arg("yuvj420p");
-                                cmd.arg("-update").arg("1");
-                                cmd.arg("-frames:v").arg("1");
-                            }
-
-                            cmd.arg(&temp_output);
-
-                            let status = cmd.status();
-
-                            let _ = fs::remove_file(&converted_overlay);
-
-                            match status {
-                                Ok(s) => {
-                                    if s.success() {
-                                        if let Err(e) = fs::rename(&temp_output, &final_output) {
-                                            pb.println(format!(
-                                                "Failed to finalize overlaid file {:?}: {}",
-                                                final_output, e
-                                            ));
-                                        } else {
-                                            did_overlay = true;
-                                        }
-                                    } else {
-                                        pb.println(format!(
-                                            "FFmpeg failed for overlay on {:?}",
-                                            path
-                                        ));
-                                        let _ = fs::remove_file(&temp_output);
-                                        if matches!(overlay_mode, OverlayMode::Copy) {
-                                            let _ = fs::remove_file(&final_output);
-                                        }
-                                    }
-                                }
-                                Err(e) => pb
-                                    .println(format!("Failed to run FFmpeg for {:?}: {}", path, e)),
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    /// With --infer-location, don't interpolate between memories more than
+    /// this many kilometres apart
+    #[arg(long, value_name = "KM", default_value_t = 10.0)]
+    infer_max_distance: f64,
 
-        // Log once per file
-        match (did_exif, did_overlay) {
-            (true, true) => pb.println(format!("Added EXIF data and overlay to {}", file_name_str)),
-            (true, false) => pb.println(format!("Added EXIF data to {}", file_name_str)),
-            (false, true) => pb.println(format!("Added overlay to {}", file_name_str)),
-            (false, false) => {}
-        }
-        pb.inc(1);
-    });
+    /// Record inferred locations as such in the XMP GPSProcessingMethod tag
+    #[arg(long, default_value_t = false)]
+    mark_inferred_location: bool,
 
-    pb.finish_with_message("Processing complete");
+    /// Directory name prefix to glob for media files (e.g. "memories" matches "memories*/**/*.jpg")
+    #[arg(short, long, default_value = "memories")]
+    media_prefix: String,
 
-    // 3. Move processed media files to output directory
-    let output_dir = &args.output_dir;
-    if let Err(e) = fs::create_dir_all(output_dir) {
-        eprintln!("Failed to create output directory {:?}: {}", output_dir, e);
-        return;
-    }
+    /// Write a JSON report of every file's outcome to this path
+    #[arg(long)]
+    report: Option<PathBuf>,
 
-    let move_pb = ProgressBar::new(paths.len() as u64);
-    move_pb.set_style(
-        ProgressStyle::with_template("Moving {pos}/{len} [{wide_bar:.green/dim}] {percent}%")
-            .unwrap()
-            .progress_chars("=> "),
-    );
-
-    let mut moved = 0usize;
-    for path in &paths {
-        let file_name = match path.file_name() {
-            Some(name) => name,
-            None => {
-                move_pb.inc(1);
-                continue;
-            }
-        };
+    /// Show what would be done without unzipping, tagging, compositing or
+    /// moving anything (with --report, the plan is written as JSON instead)
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
 
-        let dest = output_dir.join(file_name);
-        match fs::rename(path, &dest) {
-            Ok(()) => moved += 1,
-            Err(e) => move_pb.println(format!("Failed to move {:?} to {:?}: {}", path, dest, e)),
-        }
+    /// Don't read or write the journal of completed stages in the output
+    /// directory (by default an interrupted run resumes where it left off)
+    #[arg(long, default_value_t = false)]
+    no_journal: bool,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let pipeline = ProcessOptions::new()
+        .source(args.source)
+        .overlays(args.overlays)
+        .metadata_backend(args.metadata_backend)
+        .metadata_mode(args.metadata_mode)
+        .tag_profile(args.tag_profile)
+        .processes(args.processes)
+        .zip_dir(&args.zip_dir)
+        .output_dir(&args.output_dir)
+        .overlay_suffix(&args.overlay_suffix)
+        .overlay_output_dir(args.overlay_output_dir)
+        .image_quality(args.image_quality)
+        .video_encoding(VideoEncoding {
+            codec: args.video_codec,
+            crf: args.video_crf,
+            preset: args.video_preset,
+            match_source_bitrate: args.match_source_bitrate,
+        })
+        .convert_images(args.convert_images)
+        .convert_videos(args.convert_videos)
+        .rename(args.rename)
+        .layout(args.layout)
+        .transfer(args.transfer)
+        .skip_unzip(args.skip_unzip)
+        .memories_history_json_path(&args.memories_history_json_path)
+        .chat_history_json_path(&args.chat_history_json_path)
+        .snap_history_json_path(&args.snap_history_json_path)
+        .tag_partner(args.tag_partner)
+        .timezone(args.timezone)
+        .timezone_lookup(args.timezone_lookup)
+        .missing_location(args.missing_location)
+        .infer_location(args.infer_location.then(|| LocationInference {
+            max_gap: TimeDelta::minutes(args.infer_max_gap.into()),
+            max_distance_km: args.infer_max_distance,
+        }))
+        .mark_inferred_location(args.mark_inferred_location)
+        .media_prefix(&args.media_prefix)
+        .journal(!args.no_journal)
+        .show_progress(true)
+        .build();
+
+    if args.dry_run {
+        return dry_run(&pipeline, args.report.as_deref());
+    }
 
-        // Also move the _overlaid version if it exists (copy mode)
-        if matches!(args.overlays, OverlayMode::Copy) {
-            let ext = path.extension().unwrap_or_default().to_str().unwrap_or("");
-            let stem = path.file_stem().unwrap().to_str().unwrap();
-            let overlaid = path.with_file_name(format!("{}_overlaid.{}", stem, ext));
-            if overlaid.exists() {
-                let overlaid_dest = output_dir.join(overlaid.file_name().unwrap());
-                match fs::rename(&overlaid, &overlaid_dest) {
-                    Ok(()) => moved += 1,
-                    Err(e) => move_pb.println(format!(
-                        "Failed to move {:?} to {:?}: {}",
-                        overlaid, overlaid_dest, e
-                    )),
+    match pipeline.run() {
+        Ok(report) => {
+            println!("Moved {} files to {:?}", report.moved, args.output_dir);
+            println!("{}", report.summary());
+
+            if let Some(report_path) = &args.report {
+                match report.write_json(report_path) {
+                    Ok(()) => println!("Wrote report to {:?}", report_path),
+                    Err(e) => {
+                        eprintln!("Failed to write report {:?}: {}", report_path, e);
+                        return ExitCode::FAILURE;
+                    }
                 }
             }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
         }
-
-        move_pb.inc(1);
     }
-
-    move_pb.finish_and_clear();
-    println!("Moved {} files to {:?}", moved, output_dir);
 }
 
-fn parse_id_from_stem(stem: &str) -> Option<String> {
-    // Expected format: YYYY-MM-DD_UUID-suffix
-    // 1. Split by first '_' to separate date and rest
-    let (_date, rest) = stem.split_once('_')?;
-
-    // 2. Split by last '-' to separate UUID from suffix (e.g. "main")
-    let (uuid, _suffix) = rest.rsplit_once('-')?;
-
-    Some(uuid.to_string())
-}
-
-#[derive(Serialize, Deserialize)]
-struct MemoriesHistory {
-    #[serde(alias = "Saved Media")]
-    saved_media: Vec<Media>,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-enum MediaType {
-    Image,
-    Video,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct Media {
-    #[serde(alias = "Date", deserialize_with = "parse_date")]
-    date: DateTime<Utc>,
-    #[serde(alias = "Media Type")]
-    media_type: MediaType,
-    #[serde(alias = "Location", deserialize_with = "parse_coords")]
-    coordinate: Coordinates,
-    #[serde(alias = "Download Link", deserialize_with = "parse_id")]
-    id: String,
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct Coordinates {
-    lat: f64,
-    lon: f64,
-}
-
-fn parse_date<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    let s: String = Deserialize::deserialize(deserializer)?;
-    let dt = NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S UTC")
-        .map_err(serde::de::Error::custom)?;
-    Ok(DateTime::from_naive_utc_and_offset(dt, Utc))
-}
-
-fn parse_id<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    // 1. First, deserialize the field into a standard String
-    let mut s: &str = Deserialize::deserialize(deserializer)?;
-
-    let start_pattern = "&sid=";
-    if let Some(idx) = s.find(start_pattern) {
-        let start_index = idx + start_pattern.len();
-        s = &s[start_index..];
-
-        let end_pattern = "&mid";
-        if let Some(end_index) = s.find(end_pattern) {
-            let id = s[..end_index].to_string();
-            return Ok(id);
+fn dry_run(pipeline: &Pipeline, report: Option<&Path>) -> ExitCode {
+    let plan = match pipeline.plan() {
+        Ok(plan) => plan,
+        Err(e) => {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
         }
-    }
-    Err(serde::de::Error::custom(
-        "Could not parse ID from Download Link",
-    ))
-}
+    };
 
-fn parse_coords<'de, D>(deserializer: D) -> Result<Coordinates, D::Error>
-where
-    D: Deserializer<'de>,
-{
-    // 1. First, deserialize the field into a standard String
-    let s: String = Deserialize::deserialize(deserializer)?;
-
-    // 2. Process the string logic (finding the numbers after the colon)
-    let parts: Vec<&str> = s
-        .split(':')
-        .next_back()
-        .ok_or_else(|| serde::de::Error::custom("Missing colon in Location string"))?
-        .split(',')
-        .map(|p| p.trim())
-        .collect();
-
-    if parts.len() != 2 {
-        return Err(serde::de::Error::custom(
-            "Expected two comma-separated values",
-        ));
+    match report {
+        Some(report_path) => {
+            if let Err(e) = plan.write_json(report_path) {
+                eprintln!("Failed to write plan {:?}: {}", report_path, e);
+                return ExitCode::FAILURE;
+            }
+            println!("Wrote plan to {:?}", report_path);
+        }
+        None => println!("{}", plan),
     }
-
-    // 3. Parse strings into floats
-    let lat = parts[0].parse::<f64>().map_err(serde::de::Error::custom)?;
-    let lon = parts[1].parse::<f64>().map_err(serde::de::Error::custom)?;
-
-    Ok(Coordinates { lat, lon })
-}
-
-fn parse_memories_history_file(path: &Path) -> serde_json::Result<MemoriesHistory> {
-    let data = std::fs::read(path).expect("File should be readable");
-    serde_json::from_slice::<MemoriesHistory>(&data)
+    ExitCode::SUCCESS
 }
@@ -0,0 +1,261 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::Deserializer;
+use serde::{Deserialize, Serialize};
+
+/// Contents of the `memories_history.json` file from a Snapchat export
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MemoriesHistory {
+    #[serde(alias = "Saved Media")]
+    pub saved_media: Vec<Media>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum MediaType {
+    Image,
+    Video,
+}
+
+impl MediaType {
+    /// The name Snapchat uses in the export
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MediaType::Image => "Image",
+            MediaType::Video => "Video",
+        }
+    }
+}
+
+/// A single saved memory as described by `memories_history.json`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Media {
+    #[serde(alias = "Date", deserialize_with = "parse_date")]
+    pub date: DateTime<Utc>,
+    #[serde(alias = "Media Type")]
+    pub media_type: MediaType,
+    /// Location as exported. Snapchat exports `0.0, 0.0` when there is none,
+    /// see [`LocationPolicy`].
+    #[serde(alias = "Location", default, deserialize_with = "parse_coords")]
+    pub coordinate: Option<Coordinates>,
+    /// Whether `coordinate` was filled in from neighbouring memories rather
+    /// than exported, see [`crate::infer::LocationInference`]
+    #[serde(skip)]
+    pub location_inferred: bool,
+    /// The `sid` of the download link, which also appears in the exported filenames
+    #[serde(alias = "Download Link", deserialize_with = "parse_id")]
+    pub id: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Coordinates {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Coordinates {
+    /// Whether this is the `0, 0` placeholder used for missing locations
+    pub fn is_null_island(&self) -> bool {
+        self.lat == 0.0 && self.lon == 0.0
+    }
+
+    /// The GPS reference letters for the latitude and longitude
+    pub fn refs(&self) -> (char, char) {
+        let lat_ref = if self.lat < 0.0 { 'S' } else { 'N' };
+        let lon_ref = if self.lon < 0.0 { 'W' } else { 'E' };
+        (lat_ref, lon_ref)
+    }
+}
+
+impl FromStr for Coordinates {
+    type Err = String;
+
+    /// Parse `lat,lon` in decimal degrees
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (lat, lon) = s
+            .split_once(',')
+            .ok_or_else(|| format!("expected LAT,LON, got {:?}", s))?;
+        let parse = |v: &str| v.trim().parse::<f64>().map_err(|e| e.to_string());
+        let coordinates = Coordinates {
+            lat: parse(lat)?,
+            lon: parse(lon)?,
+        };
+        if !(-90.0..=90.0).contains(&coordinates.lat)
+            || !(-180.0..=180.0).contains(&coordinates.lon)
+        {
+            return Err(format!("coordinates out of range: {:?}", s));
+        }
+        Ok(coordinates)
+    }
+}
+
+/// What to do with memories that have no real location: no `Location` in the
+/// export, or the `0, 0` placeholder
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum LocationPolicy {
+    /// Write no GPS data for them
+    #[default]
+    Skip,
+    /// Write whatever the export says, including `0, 0`
+    Keep,
+    /// Write this location instead
+    Default(Coordinates),
+}
+
+impl LocationPolicy {
+    /// The coordinates to use for a memory exported with `coordinates`
+    pub fn apply(&self, coordinates: Option<Coordinates>) -> Option<Coordinates> {
+        match (self, coordinates) {
+            (_, Some(c)) if !c.is_null_island() => Some(c),
+            (LocationPolicy::Skip, _) => None,
+            (LocationPolicy::Keep, coordinates) => coordinates,
+            (LocationPolicy::Default(default), _) => Some(default.clone()),
+        }
+    }
+}
+
+impl FromStr for LocationPolicy {
+    type Err = String;
+
+    /// `skip`, `keep` or a default location as `LAT,LON`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(LocationPolicy::Skip),
+            "keep" => Ok(LocationPolicy::Keep),
+            _ => s
+                .parse()
+                .map(LocationPolicy::Default)
+                .map_err(|e| format!("expected \"skip\", \"keep\" or a LAT,LON location ({})", e)),
+        }
+    }
+}
+
+/// Extract the memory id from an exported file stem such as `2021-05-03_<uuid>-main`
+pub fn parse_id_from_stem(stem: &str) -> Option<String> {
+    // Expected format: YYYY-MM-DD_UUID-suffix
+    // 1. Split by first '_' to separate date and rest
+    let (_date, rest) = stem.split_once('_')?;
+
+    // 2. Split by last '-' to separate UUID from suffix (e.g. "main")
+    let (uuid, _suffix) = rest.rsplit_once('-')?;
+
+    Some(uuid.to_string())
+}
+
+pub(crate) fn parse_date<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s: String = Deserialize::deserialize(deserializer)?;
+    let dt = NaiveDateTime::parse_from_str(&s, "%Y-%m-%d %H:%M:%S UTC")
+        .map_err(serde::de::Error::custom)?;
+    Ok(DateTime::from_naive_utc_and_offset(dt, Utc))
+}
+
+fn parse_id<'de, D>(deserializer: D) -> Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // 1. First, deserialize the field into a standard String
+    let mut s: &str = Deserialize::deserialize(deserializer)?;
+
+    let start_pattern = "&sid=";
+    if let Some(idx) = s.find(start_pattern) {
+        let start_index = idx + start_pattern.len();
+        s = &s[start_index..];
+
+        let end_pattern = "&mid";
+        if let Some(end_index) = s.find(end_pattern) {
+            let id = s[..end_index].to_string();
+            return Ok(id);
+        }
+    }
+    Err(serde::de::Error::custom(
+        "Could not parse ID from Download Link",
+    ))
+}
+
+fn parse_coords<'de, D>(deserializer: D) -> Result<Option<Coordinates>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    // 1. First, deserialize the field into a standard String
+    let s: String = Deserialize::deserialize(deserializer)?;
+    if s.trim().is_empty() {
+        return Ok(None);
+    }
+
+    // 2. Process the string logic (finding the numbers after the colon)
+    let parts: Vec<&str> = s
+        .split(':')
+        .next_back()
+        .ok_or_else(|| serde::de::Error::custom("Missing colon in Location string"))?
+        .split(',')
+        .map(|p| p.trim())
+        .collect();
+
+    if parts.len() != 2 {
+        return Err(serde::de::Error::custom(
+            "Expected two comma-separated values",
+        ));
+    }
+
+    // 3. Parse strings into floats
+    let lat = parts[0].parse::<f64>().map_err(serde::de::Error::custom)?;
+    let lon = parts[1].parse::<f64>().map_err(serde::de::Error::custom)?;
+
+    Ok(Some(Coordinates { lat, lon }))
+}
+
+/// Read and parse a `memories_history.json` file
+pub fn parse_memories_history_file(path: &Path) -> serde_json::Result<MemoriesHistory> {
+    let data = std::fs::read(path).map_err(serde_json::Error::io)?;
+    serde_json::from_slice::<MemoriesHistory>(&data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn location_policies_are_parsed() {
+        let default = |lat, lon| Ok(LocationPolicy::Default(Coordinates { lat, lon }));
+        for (input, expected) in [
+            ("skip", Ok(LocationPolicy::Skip)),
+            ("keep", Ok(LocationPolicy::Keep)),
+            ("40.6892,-74.0445", default(40.6892, -74.0445)),
+            (" 40.6892 , -74.0445 ", default(40.6892, -74.0445)),
+            ("-90,180", default(-90.0, 180.0)),
+            ("0,0", default(0.0, 0.0)),
+        ] {
+            assert_eq!(input.parse::<LocationPolicy>(), expected, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn malformed_locations_are_rejected() {
+        for (input, reason) in [
+            ("", "expected LAT,LON"),
+            ("Skip", "expected LAT,LON"),
+            ("40.6892", "expected LAT,LON"),
+            ("40.6892;-74.0445", "expected LAT,LON"),
+            ("40.6892,", "empty string"),
+            ("north,-74.0445", "invalid float literal"),
+            ("40.6892,-74.0445,10", "invalid float literal"),
+            ("90.5,0", "out of range"),
+            ("0,-180.5", "out of range"),
+            ("NaN,0", "out of range"),
+            ("0,inf", "out of range"),
+        ] {
+            let err = input.parse::<LocationPolicy>().unwrap_err();
+            assert!(
+                err.starts_with("expected \"skip\", \"keep\" or a LAT,LON location")
+                    && err.contains(reason),
+                "{:?}: {}",
+                input,
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::thread;
+
+use super::{is_video, Metadata, MetadataWriter, TagProfile};
+use crate::report::{ErrorKind, Failure};
+
+/// Writes metadata through long-lived `exiftool -stay_open` processes, one
+/// per rayon worker, so Perl starts once per worker instead of once per file
+pub struct Exiftool {
+    profile: TagProfile,
+    processes: Vec<Mutex<Option<Process>>>,
+}
+
+impl Exiftool {
+    /// A pool for `workers` threads writing the tags in `profile`. Processes
+    /// are started on first use.
+    pub fn new(workers: usize, profile: TagProfile) -> Self {
+        Self {
+            profile,
+            processes: (0..workers.max(1)).map(|_| Mutex::new(None)).collect(),
+        }
+    }
+
+    /// The process slot belonging to the current rayon worker
+    fn slot(&self) -> &Mutex<Option<Process>> {
+        let index = rayon::current_thread_index().unwrap_or(0);
+        &self.processes[index % self.processes.len()]
+    }
+}
+
+impl MetadataWriter for Exiftool {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), Failure> {
+        let mut args = vec!["-overwrite_original".to_string()];
+        let tags = metadata.tags(self.profile, is_video(path));
+        args.extend(tags.iter().map(|tag| format!("-{}", tag)));
+        args.push("-q".to_string());
+        args.push(path.to_string_lossy().into_owned());
+
+        let mut slot = self.slot().lock().unwrap_or_else(|e| e.into_inner());
+        let result = match slot.as_mut() {
+            Some(process) => process.execute(&args),
+            None => Process::spawn().and_then(|process| slot.insert(process).execute(&args)),
+        };
+
+        match result {
+            Ok(response) if response.success() => Ok(()),
+            Ok(response) => Err(Failure::new(
+                ErrorKind::Exiftool,
+                match response.errors.first() {
+                    Some(error) => format!("ExifTool failed for {:?}: {}", path, error),
+                    None => format!("ExifTool failed for {:?}", path),
+                },
+            )),
+            Err(e) => {
+                // The process is unusable; start a fresh one for the next file
+                *slot = None;
+                Err(Failure::new(
+                    ErrorKind::Exiftool,
+                    format!("Failed to execute ExifTool for {:?}: {}", path, e),
+                ))
+            }
+        }
+    }
+}
+
+/// What exiftool reported for one `-execute`d command
+struct Response {
+    status: Option<i32>,
+    errors: Vec<String>,
+}
+
+impl Response {
+    fn success(&self) -> bool {
+        self.status
+            .map_or(self.errors.is_empty(), |status| status == 0)
+    }
+}
+
+/// A running `exiftool -stay_open True -@ -` reading arguments from stdin
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    /// What stderr held up to each command's marker, in order. It's drained
+    /// on a thread of its own, since exiftool blocks once the pipe buffer is
+    /// full of warnings while we wait for stdout.
+    stderr: Receiver<io::Result<Vec<String>>>,
+    next_id: u64,
+}
+
+impl Process {
+    fn spawn() -> io::Result<Self> {
+        let mut child = Command::new("exiftool")
+            .args(["-stay_open", "True", "-@", "-"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let mut stderr = BufReader::new(child.stderr.take().unwrap());
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for id in 1.. {
+                let errors = read_until_marker(&mut stderr, &ready_marker(id));
+                let done = errors.is_err();
+                if sender.send(errors).is_err() || done {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            stdin: child.stdin.take().unwrap(),
+            stdout: BufReader::new(child.stdout.take().unwrap()),
+            stderr: receiver,
+            child,
+            next_id: 1,
+        })
+    }
+
+    /// Run one command and wait for its `{readyN}` markers. The exit status
+    /// is echoed to stdout and the marker to stderr as well, so both streams
+    /// can be read up to the end of this command.
+    fn execute(&mut self, args: &[String]) -> io::Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let ready = ready_marker(id);
+
+        for arg in args {
+            // Arguments are newline-separated, so a value can't span lines
+            writeln!(self.stdin, "{}", arg.replace(['\r', '\n'], " "))?;
+        }
+        writeln!(self.stdin, "-echo3\n${{status}}")?;
+        writeln!(self.stdin, "-echo4\n{}", ready)?;
+        writeln!(self.stdin, "-execute{}", id)?;
+        self.stdin.flush()?;
+
+        let output = read_until_marker(&mut self.stdout, &ready)?;
+        let errors = self.stderr.recv().map_err(|_| {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "exiftool exited unexpectedly")
+        })??;
+        Ok(Response {
+            status: output.last().and_then(|line| line.trim().parse().ok()),
+            errors,
+        })
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = writeln!(self.stdin, "-stay_open\nFalse").and_then(|()| self.stdin.flush());
+        let _ = self.child.wait();
+    }
+}
+
+/// The marker echoed at the end of command `id`
+fn ready_marker(id: u64) -> String {
+    format!("{{ready{}}}", id)
+}
+
+/// Read lines up to (not including) `marker`
+fn read_until_marker(reader: &mut impl BufRead, marker: &str) -> io::Result<Vec<String>> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "exiftool exited unexpectedly",
+            ));
+        }
+        let line = line.trim_end();
+        if line == marker {
+            return Ok(lines);
+        }
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::*;
+
+    /// Hands out at most three bytes per read, like a pipe exiftool is
+    /// still writing to
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = buf.len().min(3);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    fn reader(data: &str) -> BufReader<Trickle> {
+        BufReader::with_capacity(3, Trickle(Cursor::new(data.as_bytes().to_vec())))
+    }
+
+    #[test]
+    fn output_is_read_up_to_a_marker_split_across_reads() {
+        let mut output =
+            reader("Warning: one\n\n   1 image files updated\n{ready1}\n0\n{ready2}\n");
+        assert_eq!(
+            read_until_marker(&mut output, "{ready1}").unwrap(),
+            ["Warning: one", "   1 image files updated"]
+        );
+        assert_eq!(read_until_marker(&mut output, "{ready2}").unwrap(), ["0"]);
+    }
+
+    #[test]
+    fn the_last_marker_needs_no_newline() {
+        let mut output = reader("0\r\n{ready7}");
+        assert_eq!(read_until_marker(&mut output, "{ready7}").unwrap(), ["0"]);
+    }
+
+    #[test]
+    fn output_ending_before_the_marker_is_an_error() {
+        for data in ["", "1\n", "Error: file not found\n{ready"] {
+            let error = read_until_marker(&mut reader(data), "{ready1}").unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof, "{:?}", data);
+        }
+    }
+}
@@ -0,0 +1,440 @@
+//! In-process metadata writer for JPEG files (APP1 EXIF + XMP segments).
+
+use std::fs;
+use std::path::Path;
+
+use super::tiff::{self, Exif, Field};
+use super::{replace_file, xmp, Metadata, TagProfile};
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+const XMP_HEADER: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+const MARKER_APP0: u8 = 0xe0;
+const MARKER_APP1: u8 = 0xe1;
+const MARKER_APP2: u8 = 0xe2;
+const MARKER_APP13: u8 = 0xed;
+const MARKER_COM: u8 = 0xfe;
+const MARKER_SOS: u8 = 0xda;
+
+/// Segments that describe the image rather than how it's coded: EXIF and
+/// XMP (APP1), ICC profiles (APP2), IPTC (APP13) and comments
+const METADATA_MARKERS: [u8; 4] = [MARKER_APP1, MARKER_APP2, MARKER_APP13, MARKER_COM];
+
+/// Largest payload a segment's 16-bit length field can describe
+const MAX_SEGMENT_PAYLOAD: usize = 0xffff - 2;
+
+const TAG_MODIFY_DATE: u16 = 0x0132;
+const TAG_EXIF_VERSION: u16 = 0x9000;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_CREATE_DATE: u16 = 0x9004;
+const TAG_OFFSET_TIME: u16 = 0x9010;
+const TAG_OFFSET_TIME_ORIGINAL: u16 = 0x9011;
+const TAG_OFFSET_TIME_DIGITIZED: u16 = 0x9012;
+const TAG_IMAGE_UNIQUE_ID: u16 = 0xa420;
+
+const TAG_GPS_VERSION_ID: u16 = 0x0000;
+const TAG_GPS_LATITUDE_REF: u16 = 0x0001;
+const TAG_GPS_LATITUDE: u16 = 0x0002;
+const TAG_GPS_LONGITUDE_REF: u16 = 0x0003;
+const TAG_GPS_LONGITUDE: u16 = 0x0004;
+
+/// A marker segment before the image data
+struct Segment<'a> {
+    marker: u8,
+    payload: &'a [u8],
+}
+
+impl Segment<'_> {
+    fn is_app1_with(&self, header: &[u8]) -> bool {
+        self.marker == MARKER_APP1 && self.payload.starts_with(header)
+    }
+}
+
+/// Whether `data` starts with a JPEG SOI marker
+pub fn is_jpeg(data: &[u8]) -> bool {
+    data.starts_with(&[0xff, 0xd8, 0xff])
+}
+
+/// Split a JPEG into the segments before SOS and everything from SOS onwards
+fn split(data: &[u8]) -> Result<(Vec<Segment<'_>>, &[u8]), String> {
+    if !is_jpeg(data) {
+        return Err("not a JPEG file".to_string());
+    }
+
+    let mut segments = Vec::new();
+    let mut pos = 2;
+    loop {
+        let header = data
+            .get(pos..pos + 2)
+            .ok_or("unexpected end of file before image data")?;
+        if header[0] != 0xff {
+            return Err(format!("invalid marker at offset {}", pos));
+        }
+        let marker = header[1];
+        // Fill bytes
+        if marker == 0xff {
+            pos += 1;
+            continue;
+        }
+        if marker == MARKER_SOS {
+            return Ok((segments, &data[pos..]));
+        }
+        // Markers without a length field
+        if marker == 0x01 || (0xd0..=0xd7).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+
+        let len = data
+            .get(pos + 2..pos + 4)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]) as usize)
+            .ok_or("truncated segment length")?;
+        let payload = data
+            .get(pos + 4..pos + 2 + len)
+            .ok_or("truncated segment")?;
+        segments.push(Segment { marker, payload });
+        pos += 2 + len;
+    }
+}
+
+fn push_segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) -> Result<(), String> {
+    if payload.len() > MAX_SEGMENT_PAYLOAD {
+        return Err(format!(
+            "segment of {} bytes is too large for a JPEG marker",
+            payload.len()
+        ));
+    }
+    out.extend_from_slice(&[0xff, marker]);
+    out.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+    out.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Degrees, minutes and seconds as EXIF rationals. The value is rounded
+/// once, to ten-thousandths of a second, so rounding carries into the minutes
+/// and degrees instead of producing 60 seconds.
+fn dms(value: f64) -> [(u32, u32); 3] {
+    let ticks = (value.abs() * 36_000_000.0).round() as u64;
+    [
+        ((ticks / 36_000_000) as u32, 1),
+        ((ticks / 600_000 % 60) as u32, 1),
+        ((ticks % 600_000) as u32, 10_000),
+    ]
+}
+
+/// Set the tags `profile` asks for in `exif`, keeping everything else it
+/// contains
+fn apply(exif: &mut Exif, metadata: &Metadata, profile: TagProfile) {
+    let order = exif.order;
+    let date = metadata.date.format("%Y:%m:%d %H:%M:%S").to_string();
+    let offset = metadata.date.format("%:z").to_string();
+
+    // OffsetTime* were introduced in EXIF 2.31
+    if !exif.exif.iter().any(|f| f.tag == TAG_EXIF_VERSION) {
+        tiff::set(&mut exif.exif, Field::undefined(TAG_EXIF_VERSION, b"0231"));
+    }
+    tiff::set(&mut exif.exif, Field::ascii(TAG_DATE_TIME_ORIGINAL, &date));
+    tiff::set(
+        &mut exif.exif,
+        Field::ascii(TAG_OFFSET_TIME_ORIGINAL, &offset),
+    );
+    if profile.extended() {
+        tiff::set(&mut exif.ifd0, Field::ascii(TAG_MODIFY_DATE, &date));
+        tiff::set(&mut exif.exif, Field::ascii(TAG_CREATE_DATE, &date));
+        tiff::set(&mut exif.exif, Field::ascii(TAG_OFFSET_TIME, &offset));
+        tiff::set(
+            &mut exif.exif,
+            Field::ascii(TAG_OFFSET_TIME_DIGITIZED, &offset),
+        );
+        tiff::set(
+            &mut exif.exif,
+            Field::ascii(TAG_IMAGE_UNIQUE_ID, &metadata.media_id),
+        );
+    }
+
+    if let Some(coordinates) = &metadata.coordinates {
+        let (lat_ref, lon_ref) = coordinates.refs();
+        tiff::set(
+            &mut exif.gps,
+            Field::bytes(TAG_GPS_VERSION_ID, &[2, 3, 0, 0]),
+        );
+        tiff::set(
+            &mut exif.gps,
+            Field::ascii(TAG_GPS_LATITUDE_REF, &lat_ref.to_string()),
+        );
+        tiff::set(
+            &mut exif.gps,
+            Field::rationals(order, TAG_GPS_LATITUDE, &dms(coordinates.lat)),
+        );
+        tiff::set(
+            &mut exif.gps,
+            Field::ascii(TAG_GPS_LONGITUDE_REF, &lon_ref.to_string()),
+        );
+        tiff::set(
+            &mut exif.gps,
+            Field::rationals(order, TAG_GPS_LONGITUDE, &dms(coordinates.lon)),
+        );
+    }
+}
+
+/// Return a copy of `jpeg` with `metadata` written into the EXIF and XMP
+/// segments `profile` asks for. Existing segments are updated rather than
+/// replaced, and segments the profile doesn't cover are left untouched.
+/// EXIF that can't be parsed is an error, since writing over it would lose
+/// the camera's metadata.
+pub fn embed(jpeg: &[u8], metadata: &Metadata, profile: TagProfile) -> Result<Vec<u8>, String> {
+    let (segments, image_data) = split(jpeg)?;
+    let existing_exif = segments.iter().find(|s| s.is_app1_with(EXIF_HEADER));
+    let existing_xmp = segments.iter().find(|s| s.is_app1_with(XMP_HEADER));
+
+    let exif = if profile.embedded() {
+        let mut exif = match existing_exif {
+            Some(s) => Exif::parse(&s.payload[EXIF_HEADER.len()..])
+                .ok_or("the existing EXIF data can't be parsed")?,
+            None => Exif::default(),
+        };
+        apply(&mut exif, metadata, profile);
+        Some([EXIF_HEADER, exif.serialize().as_slice()].concat())
+    } else {
+        existing_exif.map(|s| s.payload.to_vec())
+    };
+
+    let xmp = if profile.xmp() {
+        let packet = match existing_xmp {
+            Some(s) => xmp::merge(
+                &String::from_utf8_lossy(&s.payload[XMP_HEADER.len()..]),
+                metadata,
+            ),
+            None => xmp::packet(metadata),
+        };
+        Some([XMP_HEADER, packet.as_bytes()].concat())
+    } else {
+        existing_xmp.map(|s| s.payload.to_vec())
+    };
+
+    let mut out = Vec::with_capacity(jpeg.len() + 4096);
+    out.extend_from_slice(&[0xff, 0xd8]);
+
+    // A leading JFIF APP0 segment has to stay first
+    let mut rest = segments.iter().peekable();
+    if let Some(app0) = rest.next_if(|s| s.marker == MARKER_APP0) {
+        push_segment(&mut out, app0.marker, app0.payload)?;
+    }
+
+    for payload in [exif, xmp].into_iter().flatten() {
+        push_segment(&mut out, MARKER_APP1, &payload)?;
+    }
+
+    for segment in rest {
+        if segment.is_app1_with(EXIF_HEADER) || segment.is_app1_with(XMP_HEADER) {
+            continue;
+        }
+        push_segment(&mut out, segment.marker, segment.payload)?;
+    }
+    out.extend_from_slice(image_data);
+    Ok(out)
+}
+
+/// Return a copy of `jpeg` carrying the metadata segments of `source`
+/// instead of its own, e.g. to keep the EXIF of a photo that was decoded and
+/// re-encoded
+pub fn with_metadata_from(source: &[u8], jpeg: &[u8]) -> Result<Vec<u8>, String> {
+    let (source_segments, _) = split(source)?;
+    let (segments, image_data) = split(jpeg)?;
+
+    let mut out = Vec::with_capacity(source.len() + jpeg.len());
+    out.extend_from_slice(&[0xff, 0xd8]);
+
+    let mut rest = segments.iter().peekable();
+    if let Some(app0) = rest.next_if(|s| s.marker == MARKER_APP0) {
+        push_segment(&mut out, app0.marker, app0.payload)?;
+    }
+    for segment in source_segments
+        .iter()
+        .filter(|s| METADATA_MARKERS.contains(&s.marker))
+    {
+        push_segment(&mut out, segment.marker, segment.payload)?;
+    }
+    for segment in rest.filter(|s| !METADATA_MARKERS.contains(&s.marker)) {
+        push_segment(&mut out, segment.marker, segment.payload)?;
+    }
+    out.extend_from_slice(image_data);
+    Ok(out)
+}
+
+/// Write `metadata` into the JPEG at `path`, replacing it atomically
+pub fn write(path: &Path, metadata: &Metadata, profile: TagProfile) -> Result<(), String> {
+    let data = fs::read(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    let updated = embed(&data, metadata, profile)
+        .map_err(|e| format!("Failed to update {:?}: {}", path, e))?;
+    replace_file(path, |out| {
+        out.write_all(&updated).map_err(|e| e.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::metadata;
+    use crate::metadata::tiff::{ByteOrder, Thumbnail};
+
+    const JFIF: &[u8] = b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0";
+    const IMAGE_DATA: &[u8] = b"\xff\xda\x00\x02scan data\xff\xd9";
+
+    fn jpeg(segments: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut out = vec![0xff, 0xd8];
+        push_segment(&mut out, MARKER_APP0, JFIF).unwrap();
+        for &(marker, payload) in segments {
+            push_segment(&mut out, marker, payload).unwrap();
+        }
+        out.extend_from_slice(IMAGE_DATA);
+        out
+    }
+
+    /// The markers of `jpeg`'s segments and its EXIF and XMP payloads
+    fn segments(jpeg: &[u8]) -> (Vec<u8>, Option<Exif>, Option<String>) {
+        let (segments, image_data) = split(jpeg).unwrap();
+        assert_eq!(image_data, IMAGE_DATA);
+        let exif = segments
+            .iter()
+            .find(|s| s.is_app1_with(EXIF_HEADER))
+            .map(|s| Exif::parse(&s.payload[EXIF_HEADER.len()..]).unwrap());
+        let xmp = segments
+            .iter()
+            .find(|s| s.is_app1_with(XMP_HEADER))
+            .map(|s| String::from_utf8(s.payload[XMP_HEADER.len()..].to_vec()).unwrap());
+        (segments.iter().map(|s| s.marker).collect(), exif, xmp)
+    }
+
+    fn value(fields: &[Field], tag: u16) -> Option<&[u8]> {
+        fields
+            .iter()
+            .find(|f| f.tag == tag)
+            .map(|f| f.data.as_slice())
+    }
+
+    fn camera_exif() -> Vec<u8> {
+        let order = ByteOrder::Big;
+        let exif = Exif {
+            order,
+            ifd0: vec![Field::ascii(0x010f, "Camera")],
+            exif: vec![Field::ascii(TAG_DATE_TIME_ORIGINAL, "2000:01:01 00:00:00")],
+            gps: Vec::new(),
+            thumbnail: Some(Thumbnail {
+                fields: vec![Field::rationals(order, 0x011a, &[(72, 1)])],
+                jpeg: b"\xff\xd8thumbnail\xff\xd9".to_vec(),
+            }),
+        };
+        [EXIF_HEADER, exif.serialize().as_slice()].concat()
+    }
+
+    #[test]
+    fn existing_segments_are_updated() {
+        let exif = camera_exif();
+        let xmp = [
+            XMP_HEADER,
+            b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"><rdf:RDF \
+              xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\
+              <rdf:Description xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\" \
+              xmp:CreatorTool=\"Camera\" xmp:CreateDate=\"2000-01-01\"/>\
+              </rdf:RDF></x:xmpmeta>",
+        ]
+        .concat();
+        let original = jpeg(&[
+            (MARKER_APP1, &exif),
+            (MARKER_APP2, b"ICC_PROFILE\0"),
+            (MARKER_APP1, &xmp),
+        ]);
+
+        let updated = embed(&original, &metadata(), TagProfile::PhotosApp).unwrap();
+        let (markers, exif, xmp) = segments(&updated);
+        assert_eq!(
+            markers,
+            [MARKER_APP0, MARKER_APP1, MARKER_APP1, MARKER_APP2]
+        );
+
+        let exif = exif.unwrap();
+        assert_eq!(exif.order, ByteOrder::Big);
+        assert_eq!(value(&exif.ifd0, 0x010f), Some(&b"Camera\0"[..]));
+        assert_eq!(
+            value(&exif.exif, TAG_DATE_TIME_ORIGINAL),
+            Some(&b"2021:07:03 14:34:56\0"[..])
+        );
+        assert_eq!(
+            value(&exif.exif, TAG_OFFSET_TIME_ORIGINAL),
+            Some(&b"+02:00\0"[..])
+        );
+        assert_eq!(
+            value(&exif.gps, TAG_GPS_LATITUDE),
+            Some(
+                Field::rationals(ByteOrder::Big, 0, &dms(40.6892))
+                    .data
+                    .as_slice()
+            )
+        );
+        assert_eq!(value(&exif.gps, TAG_GPS_LATITUDE_REF), Some(&b"N\0"[..]));
+        assert_eq!(value(&exif.gps, TAG_GPS_LONGITUDE_REF), Some(&b"W\0"[..]));
+        let thumbnail = exif.thumbnail.unwrap();
+        assert_eq!(thumbnail.jpeg, b"\xff\xd8thumbnail\xff\xd9");
+
+        let xmp = xmp.unwrap();
+        assert!(xmp.contains("xmp:CreatorTool=\"Camera\""));
+        assert!(!xmp.contains("2000-01-01"));
+        assert!(xmp.contains("xmp:CreateDate=\"2021-07-03T14:34:56+02:00\""));
+    }
+
+    #[test]
+    fn embedding_again_gives_the_same_file() {
+        let original = jpeg(&[(MARKER_APP1, &camera_exif())]);
+        let once = embed(&original, &metadata(), TagProfile::PhotosApp).unwrap();
+        let twice = embed(&once, &metadata(), TagProfile::PhotosApp).unwrap();
+        assert!(once == twice);
+    }
+
+    #[test]
+    fn profiles_only_touch_their_segments() {
+        let xmp = [XMP_HEADER, b"<x:xmpmeta/>"].concat();
+        let original = jpeg(&[(MARKER_APP1, &xmp)]);
+
+        let minimal = embed(&original, &metadata(), TagProfile::Minimal).unwrap();
+        let (_, exif, packet) = segments(&minimal);
+        let exif = exif.unwrap();
+        assert_eq!(packet.as_deref(), Some("<x:xmpmeta/>"));
+        assert_eq!(value(&exif.ifd0, TAG_MODIFY_DATE), None);
+        assert_eq!(value(&exif.exif, TAG_IMAGE_UNIQUE_ID), None);
+        assert!(value(&exif.exif, TAG_DATE_TIME_ORIGINAL).is_some());
+
+        let sidecar = embed(&original, &metadata(), TagProfile::XmpSidecar).unwrap();
+        let (markers, exif, packet) = segments(&sidecar);
+        assert_eq!(markers, [MARKER_APP0, MARKER_APP1]);
+        assert!(exif.is_none());
+        assert!(packet.unwrap().contains("dc:identifier"));
+    }
+
+    #[test]
+    fn unparseable_exif_is_not_replaced() {
+        let broken = [EXIF_HEADER, b"XX\0\x2anot a tiff header"].concat();
+        let original = jpeg(&[(MARKER_APP1, &broken)]);
+
+        for profile in [TagProfile::PhotosApp, TagProfile::Minimal] {
+            assert_eq!(
+                embed(&original, &metadata(), profile),
+                Err("the existing EXIF data can't be parsed".to_string())
+            );
+        }
+        // Profiles without EXIF leave it as it is
+        let updated = embed(&original, &metadata(), TagProfile::XmpSidecar).unwrap();
+        let (segments, _) = split(&updated).unwrap();
+        let exif = segments.iter().find(|s| s.is_app1_with(EXIF_HEADER));
+        assert_eq!(exif.unwrap().payload, broken.as_slice());
+    }
+
+    #[test]
+    fn dms_rounds_to_ten_thousandths_of_a_second() {
+        assert_eq!(dms(40.6892), [(40, 1), (41, 1), (211_200, 10_000)]);
+        assert_eq!(dms(-74.0445), [(74, 1), (2, 1), (402_000, 10_000)]);
+        // 59.99999.. seconds carries over instead of becoming 60 seconds
+        assert_eq!(dms(10.999_999_999), [(11, 1), (0, 1), (0, 10_000)]);
+        assert_eq!(dms(0.0), [(0, 1), (0, 1), (0, 10_000)]);
+    }
+}
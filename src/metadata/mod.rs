@@ -0,0 +1,540 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+use chrono::{DateTime, FixedOffset};
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::memories::{Coordinates, MediaType};
+use crate::record::Record;
+use crate::report::{ErrorKind, Failure};
+use crate::timezone::LocalTime;
+
+mod exiftool;
+pub mod jpeg;
+pub mod mp4;
+pub mod sidecar;
+mod tiff;
+pub mod xmp;
+
+pub use exiftool::Exiftool;
+pub use sidecar::Sidecar;
+
+/// A single metadata tag in exiftool's `Name=value` form
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Tag {
+    pub name: String,
+    pub value: String,
+}
+
+impl Tag {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
+impl fmt::Display for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.value)
+    }
+}
+
+/// `dc:source` of memories
+pub const MEMORIES_SOURCE: &str = "Snapchat Memories";
+/// `dc:source` of chat media
+pub const CHAT_SOURCE: &str = "Snapchat Chat Media";
+
+/// Everything snapback writes onto a media file, independent of the backend
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metadata {
+    /// Snapchat id of the record, as it appears in the exported filename
+    pub media_id: String,
+    /// Whether the memory is an image or a video (memories only)
+    pub media_type: Option<MediaType>,
+    /// Which part of the export the file came from, written as `dc:source`
+    pub source: &'static str,
+    /// Name of the file in the export, so it can be traced back after being
+    /// renamed or moved
+    pub original_filename: Option<String>,
+    /// Capture time (memories) or send time (chats)
+    pub date: DateTime<FixedOffset>,
+    pub coordinates: Option<Coordinates>,
+    /// How `coordinates` were determined, when not simply exported (written
+    /// as XMP `GPSProcessingMethod`)
+    pub location_method: Option<String>,
+    pub description: Option<String>,
+    pub subjects: Vec<String>,
+}
+
+impl Metadata {
+    /// The metadata for whichever kind of record a file was matched to, with
+    /// its date in the local time `local_time` resolves for it
+    pub fn for_record(record: &Record, tag_partner: bool, local_time: &LocalTime) -> Self {
+        match record {
+            Record::Memory(media) => Self {
+                media_id: media.id.clone(),
+                media_type: Some(media.media_type.clone()),
+                source: MEMORIES_SOURCE,
+                original_filename: None,
+                date: local_time.convert(media.date, record.coordinates()),
+                coordinates: record.coordinates().cloned(),
+                location_method: None,
+                description: None,
+                subjects: Vec::new(),
+            },
+            Record::Chat(chat) => {
+                let (description, subjects) = if tag_partner {
+                    let description = if chat.is_sender {
+                        format!("Sent to {}", chat.partner)
+                    } else {
+                        format!("Received from {}", chat.partner)
+                    };
+                    (Some(description), vec![chat.partner.clone()])
+                } else {
+                    (None, Vec::new())
+                };
+                Self {
+                    media_id: chat.id.clone(),
+                    media_type: None,
+                    source: CHAT_SOURCE,
+                    original_filename: None,
+                    date: local_time.convert(chat.date, None),
+                    coordinates: None,
+                    location_method: None,
+                    description,
+                    subjects,
+                }
+            }
+        }
+    }
+
+    /// The metadata as exiftool tag assignments for `profile`. Videos get
+    /// QuickTime tags where images get EXIF ones.
+    pub fn tags(&self, profile: TagProfile, video: bool) -> Vec<Tag> {
+        let date = self.date.format("%Y:%m:%d %H:%M:%S").to_string();
+        let offset = self.date.format("%:z").to_string();
+        // exiftool converts QuickTime dates to UTC when they carry an offset
+        let date_with_offset = format!("{}{}", date, offset);
+
+        let mut tags = Vec::new();
+        if profile.embedded() && video {
+            tags.push(Tag::new("QuickTime:CreateDate", &date_with_offset));
+            tags.push(Tag::new("QuickTime:ModifyDate", &date_with_offset));
+            if profile.extended() {
+                for name in [
+                    "QuickTime:TrackCreateDate",
+                    "QuickTime:TrackModifyDate",
+                    "QuickTime:MediaCreateDate",
+                    "QuickTime:MediaModifyDate",
+                    "Keys:CreationDate",
+                ] {
+                    tags.push(Tag::new(name, &date_with_offset));
+                }
+            }
+            if let Some(coordinates) = &self.coordinates {
+                let value = format!("{}, {}", coordinates.lat, coordinates.lon);
+                if profile.extended() {
+                    tags.push(Tag::new("Keys:GPSCoordinates", &value));
+                }
+                tags.push(Tag::new("UserData:GPSCoordinates", value));
+            }
+            if profile.extended() {
+                if let Some(description) = &self.description {
+                    tags.push(Tag::new("Keys:Description", description));
+                }
+                if !self.subjects.is_empty() {
+                    tags.push(Tag::new("Keys:Keywords", self.subjects.join(",")));
+                }
+            }
+        } else if profile.embedded() {
+            tags.push(Tag::new("DateTimeOriginal", &date));
+            if profile.extended() {
+                tags.push(Tag::new("CreateDate", &date));
+                tags.push(Tag::new("ModifyDate", &date));
+                tags.push(Tag::new("OffsetTime", &offset));
+            }
+            tags.push(Tag::new("OffsetTimeOriginal", &offset));
+            if profile.extended() {
+                tags.push(Tag::new("OffsetTimeDigitized", &offset));
+                tags.push(Tag::new("ImageUniqueID", &self.media_id));
+            }
+            if let Some(coordinates) = &self.coordinates {
+                let (lat_ref, lon_ref) = coordinates.refs();
+                tags.push(Tag::new("GPSLatitude", coordinates.lat.abs().to_string()));
+                tags.push(Tag::new("GPSLatitudeRef", lat_ref.to_string()));
+                tags.push(Tag::new("GPSLongitude", coordinates.lon.abs().to_string()));
+                tags.push(Tag::new("GPSLongitudeRef", lon_ref.to_string()));
+            }
+        }
+
+        if profile.xmp() {
+            for name in [
+                "XMP-exif:DateTimeOriginal",
+                "XMP-xmp:CreateDate",
+                "XMP-xmp:ModifyDate",
+                "XMP-photoshop:DateCreated",
+            ] {
+                tags.push(Tag::new(name, &date_with_offset));
+            }
+            if let Some(coordinates) = &self.coordinates {
+                let (lat_ref, lon_ref) = coordinates.refs();
+                tags.push(Tag::new(
+                    "XMP-exif:GPSLatitude",
+                    format!("{} {}", coordinates.lat.abs(), lat_ref),
+                ));
+                tags.push(Tag::new(
+                    "XMP-exif:GPSLongitude",
+                    format!("{} {}", coordinates.lon.abs(), lon_ref),
+                ));
+                if let Some(method) = &self.location_method {
+                    tags.push(Tag::new("XMP-exif:GPSProcessingMethod", method));
+                }
+            }
+            if let Some(description) = &self.description {
+                tags.push(Tag::new("XMP-dc:Description", description));
+            }
+            for subject in &self.subjects {
+                tags.push(Tag::new("XMP-dc:Subject", subject));
+            }
+            tags.push(Tag::new("XMP-dc:Identifier", &self.media_id));
+            tags.push(Tag::new("XMP-dc:Source", self.source));
+            if let Some(media_type) = &self.media_type {
+                tags.push(Tag::new("XMP-dc:Type", media_type.as_str()));
+            }
+            if let Some(original_filename) = &self.original_filename {
+                tags.push(Tag::new("XMP-xmpMM:PreservedFileName", original_filename));
+            }
+        }
+
+        if profile.extended() {
+            tags.push(Tag::new("FileModifyDate", &date_with_offset));
+        }
+        tags
+    }
+}
+
+/// Which set of tags gets written onto each file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum TagProfile {
+    /// Every date and location tag photo libraries read: EXIF and XMP for
+    /// images; QuickTime atoms, keys and XMP for videos; and the file's
+    /// modification time
+    #[default]
+    PhotosApp,
+    /// Only the capture date and GPS position, in EXIF for images and
+    /// QuickTime atoms for videos
+    Minimal,
+    /// Only XMP properties, leaving EXIF and QuickTime tags alone
+    XmpSidecar,
+}
+
+impl TagProfile {
+    /// Whether the format's own tags (EXIF, QuickTime atoms) are written
+    pub fn embedded(self) -> bool {
+        self != TagProfile::XmpSidecar
+    }
+
+    /// Whether the secondary dates, QuickTime keys and file modification
+    /// time are written alongside the capture date and location
+    pub fn extended(self) -> bool {
+        self == TagProfile::PhotosApp
+    }
+
+    /// Whether XMP properties are written
+    pub fn xmp(self) -> bool {
+        self != TagProfile::Minimal
+    }
+}
+
+/// Whether `path` has a video extension, which decides between EXIF and
+/// QuickTime tags
+pub fn is_video(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("mp4") || ext.eq_ignore_ascii_case("mov"))
+}
+
+/// Writes [`Metadata`] onto media files in place
+pub trait MetadataWriter: Send + Sync {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), Failure>;
+}
+
+/// Where metadata is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum MetadataMode {
+    /// Into the media files themselves
+    #[default]
+    Embedded,
+    /// Into an XMP sidecar (`<file>.xmp`) next to each media file, leaving
+    /// the media untouched: overlays are composited onto copies, and
+    /// conversions are rejected
+    Sidecar,
+}
+
+/// Which [`MetadataWriter`] to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MetadataBackend {
+    /// Write JPEG and MP4/QuickTime metadata in-process and fall back to
+    /// exiftool for other formats
+    Native,
+    /// Run exiftool for every file
+    Exiftool,
+}
+
+impl MetadataBackend {
+    /// A writer for `workers` concurrent threads writing the tags in `profile`
+    pub fn writer(self, workers: usize, profile: TagProfile) -> Box<dyn MetadataWriter> {
+        let exiftool = Exiftool::new(workers, profile);
+        match self {
+            MetadataBackend::Native => Box::new(Native::new(profile, Box::new(exiftool))),
+            MetadataBackend::Exiftool => Box::new(exiftool),
+        }
+    }
+}
+
+/// Writes JPEG and MP4/QuickTime metadata without spawning any processes,
+/// delegating formats it can't handle to a fallback writer
+pub struct Native {
+    profile: TagProfile,
+    fallback: Box<dyn MetadataWriter>,
+}
+
+impl Native {
+    pub fn new(profile: TagProfile, fallback: Box<dyn MetadataWriter>) -> Self {
+        Self { profile, fallback }
+    }
+}
+
+impl MetadataWriter for Native {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), Failure> {
+        let magic = read_magic(path);
+        let result = if jpeg::is_jpeg(&magic) {
+            jpeg::write(path, metadata, self.profile)
+        } else if mp4::is_mp4(&magic) {
+            mp4::write(path, metadata, self.profile)
+        } else {
+            return self.fallback.write(path, metadata);
+        };
+        result
+            .and_then(|()| {
+                if self.profile.extended() {
+                    set_modified(path, metadata.date)
+                } else {
+                    Ok(())
+                }
+            })
+            .map_err(|e| Failure::new(ErrorKind::Metadata, e))
+    }
+}
+
+/// Set the file system modification time of `path` to `date`
+fn set_modified(path: &Path, date: DateTime<FixedOffset>) -> Result<(), String> {
+    File::options()
+        .write(true)
+        .open(path)
+        .and_then(|f| f.set_modified(SystemTime::from(date)))
+        .map_err(|e| format!("Failed to set modification time of {:?}: {}", path, e))
+}
+
+/// The first few bytes of a file, enough to tell the supported formats apart
+fn read_magic(path: &Path) -> Vec<u8> {
+    let mut magic = Vec::with_capacity(12);
+    let _ = File::open(path).and_then(|f| f.take(12).read_to_end(&mut magic));
+    magic
+}
+
+/// Replace `path` with the output of `write`, going through a temporary file
+/// next to it so a failure never leaves a half-written original
+pub(crate) fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut dyn Write) -> Result<(), String>,
+) -> Result<(), String> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp = path.with_file_name(format!(".{}.snapback-tmp", file_name));
+
+    let result = File::create(&temp)
+        .map_err(|e| e.to_string())
+        .and_then(|file| {
+            let mut out = BufWriter::new(file);
+            write(&mut out)?;
+            out.flush().map_err(|e| e.to_string())
+        })
+        .and_then(|()| fs::rename(&temp, path).map_err(|e| e.to_string()));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result.map_err(|e| format!("Failed to write {:?}: {}", path, e))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Metadata for a memory taken at the Statue of Liberty
+    pub(crate) fn metadata() -> Metadata {
+        Metadata {
+            media_id: "B1A2C3D4-E5F6-4789-ABCD-0123456789AB".to_string(),
+            media_type: Some(MediaType::Image),
+            source: MEMORIES_SOURCE,
+            original_filename: None,
+            date: DateTime::parse_from_rfc3339("2021-07-03T14:34:56+02:00").unwrap(),
+            coordinates: Some(Coordinates {
+                lat: 40.6892,
+                lon: -74.0445,
+            }),
+            location_method: None,
+            description: None,
+            subjects: Vec::new(),
+        }
+    }
+
+    fn names(tags: &[Tag]) -> Vec<&str> {
+        tags.iter().map(|tag| tag.name.as_str()).collect()
+    }
+
+    fn value<'a>(tags: &'a [Tag], name: &str) -> Option<&'a str> {
+        tags.iter()
+            .find(|tag| tag.name == name)
+            .map(|tag| tag.value.as_str())
+    }
+
+    const XMP_TAGS: [&str; 9] = [
+        "XMP-exif:DateTimeOriginal",
+        "XMP-xmp:CreateDate",
+        "XMP-xmp:ModifyDate",
+        "XMP-photoshop:DateCreated",
+        "XMP-exif:GPSLatitude",
+        "XMP-exif:GPSLongitude",
+        "XMP-dc:Identifier",
+        "XMP-dc:Source",
+        "XMP-dc:Type",
+    ];
+
+    #[test]
+    fn each_profile_writes_its_tags() {
+        let exif_gps = [
+            "GPSLatitude",
+            "GPSLatitudeRef",
+            "GPSLongitude",
+            "GPSLongitudeRef",
+        ];
+        let image_photos_app = [
+            &[
+                "DateTimeOriginal",
+                "CreateDate",
+                "ModifyDate",
+                "OffsetTime",
+                "OffsetTimeOriginal",
+                "OffsetTimeDigitized",
+                "ImageUniqueID",
+            ][..],
+            &exif_gps,
+            &XMP_TAGS,
+            &["FileModifyDate"],
+        ]
+        .concat();
+        let video_photos_app = [
+            &[
+                "QuickTime:CreateDate",
+                "QuickTime:ModifyDate",
+                "QuickTime:TrackCreateDate",
+                "QuickTime:TrackModifyDate",
+                "QuickTime:MediaCreateDate",
+                "QuickTime:MediaModifyDate",
+                "Keys:CreationDate",
+                "Keys:GPSCoordinates",
+                "UserData:GPSCoordinates",
+            ][..],
+            &XMP_TAGS,
+            &["FileModifyDate"],
+        ]
+        .concat();
+        let image_minimal = [&["DateTimeOriginal", "OffsetTimeOriginal"][..], &exif_gps].concat();
+        let video_minimal = [
+            "QuickTime:CreateDate",
+            "QuickTime:ModifyDate",
+            "UserData:GPSCoordinates",
+        ];
+
+        for (profile, video, expected) in [
+            (TagProfile::PhotosApp, false, &image_photos_app[..]),
+            (TagProfile::PhotosApp, true, &video_photos_app[..]),
+            (TagProfile::Minimal, false, &image_minimal[..]),
+            (TagProfile::Minimal, true, &video_minimal[..]),
+            (TagProfile::XmpSidecar, false, &XMP_TAGS[..]),
+            (TagProfile::XmpSidecar, true, &XMP_TAGS[..]),
+        ] {
+            let tags = metadata().tags(profile, video);
+            assert_eq!(names(&tags), expected, "{:?}, video: {}", profile, video);
+        }
+    }
+
+    #[test]
+    fn tag_values_follow_each_format() {
+        let tags = metadata().tags(TagProfile::PhotosApp, false);
+        for (name, expected) in [
+            ("DateTimeOriginal", "2021:07:03 14:34:56"),
+            ("OffsetTimeOriginal", "+02:00"),
+            ("GPSLatitude", "40.6892"),
+            ("GPSLatitudeRef", "N"),
+            ("GPSLongitude", "74.0445"),
+            ("GPSLongitudeRef", "W"),
+            ("XMP-xmp:CreateDate", "2021:07:03 14:34:56+02:00"),
+            ("XMP-exif:GPSLongitude", "74.0445 W"),
+            ("XMP-dc:Type", "Image"),
+        ] {
+            assert_eq!(value(&tags, name), Some(expected), "{}", name);
+        }
+
+        let tags = metadata().tags(TagProfile::PhotosApp, true);
+        assert_eq!(
+            value(&tags, "Keys:CreationDate"),
+            Some("2021:07:03 14:34:56+02:00")
+        );
+        assert_eq!(
+            value(&tags, "UserData:GPSCoordinates"),
+            Some("40.6892, -74.0445")
+        );
+    }
+
+    #[test]
+    fn optional_fields_add_tags_only_where_they_belong() {
+        let mut metadata = metadata();
+        metadata.coordinates = None;
+        metadata.description = Some("Beach".to_string());
+        metadata.subjects = vec!["Alex".to_string(), "Sam".to_string()];
+        metadata.location_method = Some("Inferred".to_string());
+        metadata.original_filename = Some("photo.jpg".to_string());
+
+        let tags = metadata.tags(TagProfile::PhotosApp, true);
+        assert_eq!(value(&tags, "Keys:Description"), Some("Beach"));
+        assert_eq!(value(&tags, "Keys:Keywords"), Some("Alex,Sam"));
+        assert_eq!(value(&tags, "XMP-dc:Description"), Some("Beach"));
+        assert_eq!(
+            names(&tags)
+                .iter()
+                .filter(|n| **n == "XMP-dc:Subject")
+                .count(),
+            2
+        );
+        assert_eq!(
+            value(&tags, "XMP-xmpMM:PreservedFileName"),
+            Some("photo.jpg")
+        );
+        // The processing method describes a position, so it needs one
+        assert_eq!(value(&tags, "XMP-exif:GPSProcessingMethod"), None);
+        assert!(!names(&tags).iter().any(|n| n.contains("GPS")));
+
+        let tags = metadata.tags(TagProfile::Minimal, true);
+        assert_eq!(
+            names(&tags),
+            ["QuickTime:CreateDate", "QuickTime:ModifyDate"]
+        );
+    }
+}
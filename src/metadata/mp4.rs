@@ -0,0 +1,788 @@
+//! In-process metadata writer for MP4/QuickTime files.
+//!
+//! Only the `moov` atom is rewritten: the creation/modification times in
+//! `mvhd`, `tkhd` and `mdhd`, the `©xyz` location and `XMP_` packet in
+//! `udta` and the Apple `mdta` keys in `moov/meta`. The media data is copied
+//! through unchanged, with chunk offsets adjusted when `moov` is stored
+//! before it.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use super::{replace_file, xmp, Metadata, TagProfile};
+
+/// Atoms that can open an MP4/QuickTime file
+const LEADING_ATOMS: [&[u8; 4]; 6] = [b"ftyp", b"moov", b"mdat", b"wide", b"free", b"skip"];
+
+/// Atoms whose payload is nothing but child atoms
+const CONTAINERS: [&[u8; 4]; 6] = [b"moov", b"trak", b"mdia", b"minf", b"stbl", b"udta"];
+
+/// Seconds from the QuickTime epoch (1904-01-01) to the Unix epoch
+const QUICKTIME_EPOCH_OFFSET: i64 = 2_082_844_800;
+
+/// Packed ISO 639-2 code for English, as iOS writes in `©xyz`
+const XYZ_LANGUAGE: u16 = 0x15c7;
+
+const KEY_LOCATION: &str = "com.apple.quicktime.location.ISO6709";
+const KEY_CREATION_DATE: &str = "com.apple.quicktime.creationdate";
+const KEY_DESCRIPTION: &str = "com.apple.quicktime.description";
+const KEY_KEYWORDS: &str = "com.apple.quicktime.keywords";
+
+/// `data` atom type for UTF-8 text
+const DATA_TYPE_UTF8: u32 = 1;
+
+/// Whether `data` looks like the start of an MP4/QuickTime file
+pub fn is_mp4(data: &[u8]) -> bool {
+    data.get(4..8)
+        .is_some_and(|kind| LEADING_ATOMS.iter().any(|a| a.as_slice() == kind))
+}
+
+/// An atom inside `moov`. Containers keep any bytes before their children
+/// (none for the atoms in [`CONTAINERS`]) in `payload`; leaves keep their
+/// whole payload there.
+struct Atom {
+    kind: [u8; 4],
+    payload: Vec<u8>,
+    children: Vec<Atom>,
+}
+
+impl Atom {
+    fn leaf(kind: [u8; 4], payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            payload,
+            children: Vec::new(),
+        }
+    }
+
+    fn container(kind: [u8; 4], children: Vec<Atom>) -> Self {
+        Self {
+            kind,
+            payload: Vec::new(),
+            children,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        let body = self.payload.len() as u64 + self.children.iter().map(Atom::size).sum::<u64>();
+        if body + 8 > u32::MAX as u64 {
+            body + 16
+        } else {
+            body + 8
+        }
+    }
+
+    fn serialize(&self, out: &mut Vec<u8>) {
+        let size = self.size();
+        if size > u32::MAX as u64 {
+            out.extend_from_slice(&1u32.to_be_bytes());
+            out.extend_from_slice(&self.kind);
+            out.extend_from_slice(&size.to_be_bytes());
+        } else {
+            out.extend_from_slice(&(size as u32).to_be_bytes());
+            out.extend_from_slice(&self.kind);
+        }
+        out.extend_from_slice(&self.payload);
+        for child in &self.children {
+            child.serialize(out);
+        }
+    }
+
+    fn child_mut(&mut self, kind: &[u8; 4]) -> Option<&mut Atom> {
+        self.children.iter_mut().find(|c| &c.kind == kind)
+    }
+}
+
+/// Parse a sequence of atoms, descending into [`CONTAINERS`]
+fn parse_atoms(data: &[u8]) -> Result<Vec<Atom>, String> {
+    let mut atoms = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let rest = &data[pos..];
+        // QuickTime allows a 32-bit zero terminator at the end of a container
+        if rest.len() < 8 {
+            if rest.iter().all(|&b| b == 0) {
+                break;
+            }
+            return Err("truncated atom".to_string());
+        }
+
+        let mut size = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        let mut header = 8;
+        if size == 1 {
+            let large = rest.get(8..16).ok_or("truncated atom size")?;
+            size = u64::from_be_bytes(large.try_into().unwrap()) as usize;
+            header = 16;
+        } else if size == 0 {
+            size = rest.len();
+        }
+        if size < header || size > rest.len() {
+            return Err(format!(
+                "invalid size for '{}' atom",
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+
+        let body = &rest[header..size];
+        atoms.push(if CONTAINERS.contains(&&kind) {
+            Atom::container(kind, parse_atoms(body)?)
+        } else {
+            Atom::leaf(kind, body.to_vec())
+        });
+        pos += size;
+    }
+    Ok(atoms)
+}
+
+/// Set the creation and modification times of a `mvhd`, `tkhd` or `mdhd`
+/// payload
+fn set_times(atom: &mut Atom, seconds: u64) -> Result<(), String> {
+    let payload = &mut atom.payload;
+    match payload.first() {
+        Some(0) if payload.len() >= 12 => {
+            let seconds = u32::try_from(seconds)
+                .map_err(|_| "date is too late for a version 0 header".to_string())?
+                .to_be_bytes();
+            payload[4..8].copy_from_slice(&seconds);
+            payload[8..12].copy_from_slice(&seconds);
+            Ok(())
+        }
+        Some(1) if payload.len() >= 20 => {
+            let seconds = seconds.to_be_bytes();
+            payload[4..12].copy_from_slice(&seconds);
+            payload[12..20].copy_from_slice(&seconds);
+            Ok(())
+        }
+        _ => Err(format!(
+            "unsupported '{}' atom",
+            String::from_utf8_lossy(&atom.kind)
+        )),
+    }
+}
+
+fn set_all_times(atom: &mut Atom, seconds: u64) -> Result<(), String> {
+    if matches!(&atom.kind, b"mvhd" | b"tkhd" | b"mdhd") {
+        set_times(atom, seconds)?;
+    }
+    for child in &mut atom.children {
+        set_all_times(child, seconds)?;
+    }
+    Ok(())
+}
+
+/// Move every chunk offset at or after `from` by `delta` bytes
+fn shift_chunk_offsets(atom: &mut Atom, from: u64, delta: i64) -> Result<(), String> {
+    let width = match &atom.kind {
+        b"stco" => 4,
+        b"co64" => 8,
+        _ => 0,
+    };
+    if width > 0 {
+        let entries = atom
+            .payload
+            .get_mut(8..)
+            .ok_or("truncated chunk offset table")?;
+        for entry in entries.chunks_exact_mut(width) {
+            let offset = match width {
+                4 => u32::from_be_bytes(entry.try_into().unwrap()) as u64,
+                _ => u64::from_be_bytes(entry.try_into().unwrap()),
+            };
+            if offset < from {
+                continue;
+            }
+            let shifted = offset
+                .checked_add_signed(delta)
+                .ok_or("invalid chunk offset")?;
+            if width == 4 {
+                let shifted = u32::try_from(shifted)
+                    .map_err(|_| "chunk offset no longer fits in 'stco'".to_string())?;
+                entry.copy_from_slice(&shifted.to_be_bytes());
+            } else {
+                entry.copy_from_slice(&shifted.to_be_bytes());
+            }
+        }
+    }
+    for child in &mut atom.children {
+        shift_chunk_offsets(child, from, delta)?;
+    }
+    Ok(())
+}
+
+/// The largest offset in any 32-bit `stco` chunk offset table
+fn max_stco_offset(atom: &Atom) -> u64 {
+    let own = if &atom.kind == b"stco" {
+        atom.payload
+            .get(8..)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|entry| u32::from_be_bytes(entry.try_into().unwrap()) as u64)
+            .max()
+            .unwrap_or(0)
+    } else {
+        0
+    };
+    atom.children
+        .iter()
+        .map(max_stco_offset)
+        .fold(own, u64::max)
+}
+
+/// Turn every `stco` table into a `co64` one with the same offsets
+fn promote_chunk_offsets(atom: &mut Atom) {
+    if &atom.kind == b"stco" && atom.payload.len() >= 8 {
+        let mut payload = atom.payload[..8].to_vec();
+        for entry in atom.payload[8..].chunks_exact(4) {
+            let offset = u32::from_be_bytes(entry.try_into().unwrap()) as u64;
+            payload.extend_from_slice(&offset.to_be_bytes());
+        }
+        atom.kind = *b"co64";
+        atom.payload = payload;
+    }
+    for child in &mut atom.children {
+        promote_chunk_offsets(child);
+    }
+}
+
+/// Adjust the chunk offsets in `moov` for it having changed from `old_size`
+/// bytes, when the media from `from` on is stored after it. `stco` tables
+/// are promoted to `co64` first if the offsets would no longer fit.
+fn relocate_chunks(moov: &mut Atom, old_size: u64, from: u64) -> Result<(), String> {
+    let mut delta = moov.size() as i64 - old_size as i64;
+    if delta > 0 && max_stco_offset(moov) + delta as u64 > u32::MAX as u64 {
+        promote_chunk_offsets(moov);
+        delta = moov.size() as i64 - old_size as i64;
+    }
+    if delta == 0 {
+        return Ok(());
+    }
+    shift_chunk_offsets(moov, from, delta)
+}
+
+/// Coordinates in the ISO 6709 form QuickTime uses, e.g. `+40.6892-074.0445/`
+fn iso6709(lat: f64, lon: f64) -> String {
+    format!("{:+08.4}{:+09.4}/", lat, lon)
+}
+
+/// Replace the `udta` child of type `kind`
+fn set_user_data(moov: &mut Atom, kind: [u8; 4], payload: Vec<u8>) {
+    if moov.child_mut(b"udta").is_none() {
+        moov.children.push(Atom::container(*b"udta", Vec::new()));
+    }
+    let udta = moov.child_mut(b"udta").unwrap();
+    udta.children.retain(|c| c.kind != kind);
+    udta.children.push(Atom::leaf(kind, payload));
+}
+
+/// The payload of a `©xyz` atom holding `location`
+fn xyz(location: &str) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(4 + location.len());
+    payload.extend_from_slice(&(location.len() as u16).to_be_bytes());
+    payload.extend_from_slice(&XYZ_LANGUAGE.to_be_bytes());
+    payload.extend_from_slice(location.as_bytes());
+    payload
+}
+
+/// The `mdta` keys and values in `moov/meta`
+struct Keys {
+    /// Version/flags when `meta` is written as an ISO full box
+    header: Vec<u8>,
+    /// Children other than `keys` and `ilst`, such as `hdlr`
+    others: Vec<Atom>,
+    /// Raw `keys` entries (namespace + name) and their `ilst` values
+    entries: Vec<(Vec<u8>, Option<Atom>)>,
+}
+
+impl Keys {
+    fn new() -> Self {
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"mdta");
+        hdlr.extend_from_slice(&[0; 13]);
+        Self {
+            header: Vec::new(),
+            others: vec![Atom::leaf(*b"hdlr", hdlr)],
+            entries: Vec::new(),
+        }
+    }
+
+    /// Parse the payload of a `meta` atom, or `None` if it holds something
+    /// other than `mdta` keys, such as iTunes' `mdir` tags
+    fn parse(payload: &[u8]) -> Result<Option<Self>, String> {
+        // QuickTime's `meta` has no version/flags, the ISO one does
+        let header_len = if payload.get(4..8) == Some(b"hdlr") {
+            0
+        } else {
+            4
+        };
+        let header = payload.get(..header_len).ok_or("truncated 'meta' atom")?;
+        let children = parse_atoms(&payload[header_len..])?;
+        let is_key_list = children
+            .iter()
+            .any(|c| &c.kind == b"hdlr" && c.payload.get(8..12) == Some(b"mdta"));
+        if !is_key_list {
+            return Ok(None);
+        }
+
+        let mut keys = Self {
+            header: header.to_vec(),
+            others: Vec::new(),
+            entries: Vec::new(),
+        };
+        let mut values = Vec::new();
+        for child in children {
+            match &child.kind {
+                b"keys" => {
+                    let mut rest = child.payload.get(8..).ok_or("truncated 'keys' atom")?;
+                    while rest.len() >= 8 {
+                        let size = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+                        let entry = rest.get(4..size).ok_or("truncated 'keys' entry")?;
+                        keys.entries.push((entry.to_vec(), None));
+                        rest = &rest[size.max(8)..];
+                    }
+                }
+                b"ilst" => values = parse_atoms(&child.payload)?,
+                _ => keys.others.push(child),
+            }
+        }
+        for value in values {
+            let index = u32::from_be_bytes(value.kind) as usize;
+            if let Some(entry) = index.checked_sub(1).and_then(|i| keys.entries.get_mut(i)) {
+                entry.1 = Some(value);
+            }
+        }
+        Ok(Some(keys))
+    }
+
+    /// Set `key` to `value`, or remove it when `value` is `None`
+    fn set(&mut self, key: &str, value: Option<&str>) {
+        let name = [b"mdta".as_slice(), key.as_bytes()].concat();
+        self.entries.retain(|(entry, _)| *entry != name);
+        if let Some(value) = value {
+            let mut data = Vec::with_capacity(8 + value.len());
+            data.extend_from_slice(&DATA_TYPE_UTF8.to_be_bytes());
+            data.extend_from_slice(&0u32.to_be_bytes());
+            data.extend_from_slice(value.as_bytes());
+            let item = Atom::container([0; 4], vec![Atom::leaf(*b"data", data)]);
+            self.entries.push((name, Some(item)));
+        }
+    }
+
+    fn into_atom(self) -> Atom {
+        let mut keys = vec![0; 4];
+        keys.extend_from_slice(&(self.entries.len() as u32).to_be_bytes());
+        let mut items = Vec::new();
+        for (index, (name, value)) in self.entries.into_iter().enumerate() {
+            keys.extend_from_slice(&(name.len() as u32 + 4).to_be_bytes());
+            keys.extend_from_slice(&name);
+            if let Some(mut value) = value {
+                value.kind = (index as u32 + 1).to_be_bytes();
+                items.push(value);
+            }
+        }
+
+        let mut children = self.others;
+        children.push(Atom::leaf(*b"keys", keys));
+        children.push(Atom::container(*b"ilst", items));
+        Atom {
+            kind: *b"meta",
+            payload: self.header,
+            children,
+        }
+    }
+}
+
+/// Apply the parts of `metadata` that `profile` asks for to a parsed `moov`
+/// atom
+fn update(moov: &mut Atom, metadata: &Metadata, profile: TagProfile) -> Result<(), String> {
+    let location = metadata.coordinates.as_ref().map(|c| iso6709(c.lat, c.lon));
+
+    if profile.embedded() {
+        let seconds = u64::try_from(metadata.date.timestamp() + QUICKTIME_EPOCH_OFFSET)
+            .map_err(|_| "date is before 1904".to_string())?;
+        set_all_times(moov, seconds)?;
+        // A location the camera recorded is kept when there is none to write
+        if let Some(location) = &location {
+            set_user_data(moov, *b"\xa9xyz", xyz(location));
+        }
+    }
+
+    if profile.extended() {
+        // Any other `meta` atom is left alone, next to the key list
+        let mut existing = None;
+        for (i, child) in moov.children.iter().enumerate() {
+            if &child.kind == b"meta" {
+                if let Some(keys) = Keys::parse(&child.payload)? {
+                    existing = Some((i, keys));
+                    break;
+                }
+            }
+        }
+        let mut keys = match existing {
+            Some((i, keys)) => {
+                moov.children.remove(i);
+                keys
+            }
+            None => Keys::new(),
+        };
+        let date = metadata.date.format("%Y-%m-%dT%H:%M:%S%z").to_string();
+        let keywords = (!metadata.subjects.is_empty()).then(|| metadata.subjects.join(","));
+        keys.set(KEY_CREATION_DATE, Some(&date));
+        if location.is_some() {
+            keys.set(KEY_LOCATION, location.as_deref());
+        }
+        keys.set(KEY_DESCRIPTION, metadata.description.as_deref());
+        keys.set(KEY_KEYWORDS, keywords.as_deref());
+        moov.children.push(keys.into_atom());
+    }
+
+    if profile.xmp() {
+        let existing = moov
+            .child_mut(b"udta")
+            .and_then(|udta| udta.child_mut(b"XMP_"))
+            .map(|xmp| String::from_utf8_lossy(&xmp.payload).into_owned());
+        let packet = match existing {
+            Some(existing) => xmp::merge(&existing, metadata),
+            None => xmp::packet(metadata),
+        };
+        set_user_data(moov, *b"XMP_", packet.into_bytes());
+    }
+    Ok(())
+}
+
+/// Position of a top-level atom in the file
+struct TopLevel {
+    kind: [u8; 4],
+    offset: u64,
+    header: u64,
+    size: u64,
+}
+
+fn top_level_atoms(file: &mut File, len: u64) -> Result<Vec<TopLevel>, String> {
+    let mut atoms = Vec::new();
+    let mut offset = 0;
+    while offset + 8 <= len {
+        let mut header = [0u8; 8];
+        file.seek(SeekFrom::Start(offset))
+            .and_then(|_| file.read_exact(&mut header))
+            .map_err(|e| e.to_string())?;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (size, header_len) =
+            match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) {
+                0 => (len - offset, 8),
+                1 => {
+                    let mut large = [0u8; 8];
+                    file.read_exact(&mut large).map_err(|e| e.to_string())?;
+                    (u64::from_be_bytes(large), 16)
+                }
+                size => (size as u64, 8),
+            };
+        if size < header_len || offset + size > len {
+            return Err(format!(
+                "invalid size for top-level '{}' atom",
+                String::from_utf8_lossy(&kind)
+            ));
+        }
+        atoms.push(TopLevel {
+            kind,
+            offset,
+            header: header_len,
+            size,
+        });
+        offset += size;
+    }
+    Ok(atoms)
+}
+
+/// Copy `input` to `output` with its `moov` atom updated for `metadata`
+fn rewrite(
+    input: &mut File,
+    output: &mut dyn Write,
+    metadata: &Metadata,
+    profile: TagProfile,
+) -> Result<(), String> {
+    let len = input.metadata().map_err(|e| e.to_string())?.len();
+    let atoms = top_level_atoms(input, len)?;
+    let old = atoms
+        .iter()
+        .find(|a| &a.kind == b"moov")
+        .ok_or("no 'moov' atom")?;
+
+    let mut body = vec![0; (old.size - old.header) as usize];
+    input
+        .seek(SeekFrom::Start(old.offset + old.header))
+        .and_then(|_| input.read_exact(&mut body))
+        .map_err(|e| e.to_string())?;
+    let mut moov = Atom::container(*b"moov", parse_atoms(&body)?);
+    update(&mut moov, metadata, profile)?;
+
+    let media_after_moov = atoms
+        .iter()
+        .any(|a| &a.kind == b"mdat" && a.offset > old.offset);
+    if media_after_moov {
+        relocate_chunks(&mut moov, old.size, old.offset + old.size)?;
+    }
+
+    let mut serialized = Vec::with_capacity(moov.size() as usize);
+    moov.serialize(&mut serialized);
+
+    let copy = |input: &mut File, output: &mut dyn Write, from: u64, len: u64| {
+        input.seek(SeekFrom::Start(from))?;
+        io::copy(&mut input.take(len), output).map(|_| ())
+    };
+    copy(input, output, 0, old.offset)
+        .and_then(|()| output.write_all(&serialized))
+        .and_then(|()| {
+            let end = old.offset + old.size;
+            copy(input, output, end, len - end)
+        })
+        .map_err(|e| e.to_string())
+}
+
+/// Write `metadata` into the MP4/QuickTime file at `path`, replacing it
+/// atomically
+pub fn write(path: &Path, metadata: &Metadata, profile: TagProfile) -> Result<(), String> {
+    let mut input = File::open(path).map_err(|e| format!("Failed to read {:?}: {}", path, e))?;
+    replace_file(path, |output| {
+        rewrite(&mut input, output, metadata, profile)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::metadata;
+
+    fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let size = (payload.len() as u32 + 8).to_be_bytes();
+        [&size[..], kind, payload].concat()
+    }
+
+    fn mvhd() -> Vec<u8> {
+        atom(b"mvhd", &[0; 100])
+    }
+
+    /// An iTunes-style `meta` atom: an ISO full box with an `mdir` handler
+    /// and a `©nam` title in `ilst`
+    fn itunes_meta() -> Vec<u8> {
+        let mut hdlr = vec![0; 8];
+        hdlr.extend_from_slice(b"mdirappl");
+        hdlr.extend_from_slice(&[0; 9]);
+        let mut data = 1u32.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(b"Title");
+        let ilst = atom(b"ilst", &atom(b"\xa9nam", &atom(b"data", &data)));
+        atom(
+            b"meta",
+            &[&[0; 4][..], &atom(b"hdlr", &hdlr), &ilst].concat(),
+        )
+    }
+
+    fn moov(children: &[Vec<u8>]) -> Atom {
+        Atom::container(*b"moov", parse_atoms(&children.concat()).unwrap())
+    }
+
+    /// `moov` as it would be read back from the file
+    fn reparse(moov: &Atom) -> Atom {
+        let mut serialized = Vec::new();
+        moov.serialize(&mut serialized);
+        parse_atoms(&serialized).unwrap().remove(0)
+    }
+
+    #[test]
+    fn itunes_meta_is_left_alone_next_to_the_keys() {
+        let foreign = itunes_meta();
+        let mut moov = moov(&[mvhd(), foreign.clone()]);
+        update(&mut moov, &metadata(), TagProfile::PhotosApp).unwrap();
+        let moov = reparse(&moov);
+
+        let metas: Vec<_> = moov
+            .children
+            .iter()
+            .filter(|c| &c.kind == b"meta")
+            .collect();
+        assert_eq!(metas.len(), 2);
+        let mut serialized = Vec::new();
+        metas[0].serialize(&mut serialized);
+        assert_eq!(serialized, foreign);
+
+        let keys = Keys::parse(&metas[1].payload).unwrap().unwrap();
+        let names: Vec<_> = keys.entries.iter().map(|(name, _)| name.clone()).collect();
+        assert!(names.contains(&[b"mdta".as_slice(), KEY_CREATION_DATE.as_bytes()].concat()));
+        assert!(names.contains(&[b"mdta".as_slice(), KEY_LOCATION.as_bytes()].concat()));
+    }
+
+    #[test]
+    fn existing_keys_are_updated_in_place() {
+        let mut moov = moov(&[mvhd()]);
+        update(&mut moov, &metadata(), TagProfile::PhotosApp).unwrap();
+        let mut moov = reparse(&moov);
+        update(&mut moov, &metadata(), TagProfile::PhotosApp).unwrap();
+        let moov = reparse(&moov);
+
+        let metas: Vec<_> = moov
+            .children
+            .iter()
+            .filter(|c| &c.kind == b"meta")
+            .collect();
+        assert_eq!(metas.len(), 1);
+        let keys = Keys::parse(&metas[0].payload).unwrap().unwrap();
+        assert_eq!(keys.entries.len(), 2);
+        assert!(keys.entries.iter().all(|(_, value)| value.is_some()));
+    }
+
+    #[test]
+    fn an_existing_location_is_kept_without_coordinates() {
+        let mut moov = moov(&[mvhd()]);
+        update(&mut moov, &metadata(), TagProfile::PhotosApp).unwrap();
+        let mut moov = reparse(&moov);
+        let mut metadata = metadata();
+        metadata.coordinates = None;
+        update(&mut moov, &metadata, TagProfile::PhotosApp).unwrap();
+        let moov = reparse(&moov);
+
+        let location = xyz(&iso6709(40.6892, -74.0445));
+        assert_eq!(find(&moov, b"\xa9xyz").unwrap().payload, location);
+        let meta = find(&moov, b"meta").unwrap();
+        let keys = Keys::parse(&meta.payload).unwrap().unwrap();
+        let name = [b"mdta".as_slice(), KEY_LOCATION.as_bytes()].concat();
+        assert!(keys
+            .entries
+            .iter()
+            .any(|(entry, value)| *entry == name && value.is_some()));
+    }
+
+    /// A track whose chunk offset table holds `offsets`
+    fn trak(offsets: &[u32]) -> Vec<u8> {
+        let mut stco = vec![0; 4];
+        stco.extend_from_slice(&(offsets.len() as u32).to_be_bytes());
+        for offset in offsets {
+            stco.extend_from_slice(&offset.to_be_bytes());
+        }
+        let stbl = atom(b"stbl", &atom(b"stco", &stco));
+        let mdia = [atom(b"mdhd", &[0; 24]), atom(b"minf", &stbl)].concat();
+        atom(
+            b"trak",
+            &[atom(b"tkhd", &[0; 84]), atom(b"mdia", &mdia)].concat(),
+        )
+    }
+
+    fn find<'a>(atom: &'a Atom, kind: &[u8; 4]) -> Option<&'a Atom> {
+        if &atom.kind == kind {
+            return Some(atom);
+        }
+        atom.children.iter().find_map(|child| find(child, kind))
+    }
+
+    fn offsets(moov: &Atom) -> Vec<u64> {
+        if let Some(stco) = find(moov, b"stco") {
+            stco.payload[8..]
+                .chunks_exact(4)
+                .map(|e| u32::from_be_bytes(e.try_into().unwrap()) as u64)
+                .collect()
+        } else {
+            find(moov, b"co64").unwrap().payload[8..]
+                .chunks_exact(8)
+                .map(|e| u64::from_be_bytes(e.try_into().unwrap()))
+                .collect()
+        }
+    }
+
+    /// Rewrite the file made of `atoms` for [`metadata`]
+    fn rewritten(atoms: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&atoms.concat()).unwrap();
+        let mut out = Vec::new();
+        rewrite(&mut file, &mut out, &metadata(), TagProfile::PhotosApp).unwrap();
+        out
+    }
+
+    const MEDIA: &[u8] = b"MEDIA";
+
+    #[test]
+    fn offsets_follow_media_stored_after_moov() {
+        let ftyp = atom(b"ftyp", b"isom\0\0\x02\0");
+        let moov_len = atom(b"moov", &[mvhd(), trak(&[0])].concat()).len();
+        let offset = (ftyp.len() + moov_len + 8) as u32;
+        let moov = atom(b"moov", &[mvhd(), trak(&[offset])].concat());
+
+        let out = rewritten(&[ftyp, moov, atom(b"mdat", MEDIA)]);
+        let atoms = parse_atoms(&out).unwrap();
+        assert!(atoms[1].size() > moov_len as u64);
+        let offset = offsets(&atoms[1])[0] as usize;
+        assert_eq!(&out[offset..offset + MEDIA.len()], MEDIA);
+    }
+
+    #[test]
+    fn offsets_stay_for_media_stored_before_moov() {
+        let ftyp = atom(b"ftyp", b"isom\0\0\x02\0");
+        // A 64-bit `largesize` header, as used for media over 4 GiB
+        let mdat = [
+            &1u32.to_be_bytes()[..],
+            b"mdat",
+            &(16 + MEDIA.len() as u64).to_be_bytes(),
+            MEDIA,
+        ]
+        .concat();
+        let offset = (ftyp.len() + 16) as u32;
+        let moov = atom(b"moov", &[mvhd(), trak(&[offset])].concat());
+
+        let out = rewritten(&[ftyp.clone(), mdat.clone(), moov]);
+        assert_eq!(&out[..ftyp.len() + mdat.len()], [ftyp, mdat].concat());
+        let atoms = parse_atoms(&out).unwrap();
+        assert_eq!(atoms[1].payload, MEDIA);
+        assert_eq!(offsets(&atoms[2]), [offset as u64]);
+    }
+
+    #[test]
+    fn stco_is_promoted_to_co64_when_offsets_overflow() {
+        let mut moov = moov(&[mvhd(), trak(&[u32::MAX - 8, 10])]);
+        let old_size = moov.size() - 16;
+        relocate_chunks(&mut moov, old_size, 100).unwrap();
+
+        assert!(find(&moov, b"stco").is_none());
+        // Two offsets grew by four bytes each on top of the 16
+        assert_eq!(offsets(&moov), [u32::MAX as u64 - 8 + 24, 10]);
+    }
+
+    #[test]
+    fn offsets_before_the_moved_media_are_kept() {
+        let mut moov = moov(&[trak(&[50, 500])]);
+        let old_size = moov.size() + 8;
+        relocate_chunks(&mut moov, old_size, 100).unwrap();
+        assert_eq!(offsets(&moov), [50, 492]);
+    }
+
+    #[test]
+    fn largesize_children_are_parsed() {
+        let free = [
+            &1u32.to_be_bytes()[..],
+            b"free",
+            &20u64.to_be_bytes(),
+            b"abcd",
+        ]
+        .concat();
+        let atoms = parse_atoms(&atom(b"udta", &free)).unwrap();
+        assert_eq!(atoms[0].children.len(), 1);
+        assert_eq!(atoms[0].children[0].payload, b"abcd");
+        assert_eq!(atoms[0].children[0].size(), 12);
+    }
+
+    #[test]
+    fn header_times_are_set_for_both_versions() {
+        let seconds = 3_708_160_496u64;
+        let mut v0 = Atom::leaf(*b"mvhd", vec![0; 100]);
+        set_times(&mut v0, seconds).unwrap();
+        assert_eq!(v0.payload[4..8], (seconds as u32).to_be_bytes());
+        assert_eq!(v0.payload[8..12], (seconds as u32).to_be_bytes());
+
+        let mut v1 = Atom::leaf(*b"mdhd", [&[1, 0, 0, 0][..], &[0; 32]].concat());
+        set_times(&mut v1, seconds << 8).unwrap();
+        assert_eq!(v1.payload[4..12], (seconds << 8).to_be_bytes());
+        assert_eq!(v1.payload[12..20], (seconds << 8).to_be_bytes());
+        assert_eq!(v1.payload[20..], [0; 16]);
+
+        assert!(set_times(&mut v0, seconds << 8).is_err());
+        assert!(set_times(&mut Atom::leaf(*b"tkhd", vec![2; 100]), seconds).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+//! XMP sidecar files, written next to the media instead of into it.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use super::{replace_file, xmp, Metadata, MetadataWriter};
+use crate::artifact::sidecar_path;
+use crate::report::{ErrorKind, Failure};
+
+/// Writes [`Metadata`] into an XMP sidecar (see [`sidecar_path`]) next to
+/// each file without modifying the file itself. An existing sidecar not
+/// written by snapback is merged into rather than replaced.
+pub struct Sidecar;
+
+impl MetadataWriter for Sidecar {
+    fn write(&self, path: &Path, metadata: &Metadata) -> Result<(), Failure> {
+        let sidecar = sidecar_path(path);
+        let packet = match fs::read_to_string(&sidecar) {
+            Ok(existing) => xmp::merge(&existing, metadata),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => xmp::packet(metadata),
+            Err(e) => {
+                return Err(Failure::new(
+                    ErrorKind::Metadata,
+                    format!("Failed to read {:?}: {}", sidecar, e),
+                ))
+            }
+        };
+        replace_file(&sidecar, |out| {
+            out.write_all(packet.as_bytes()).map_err(|e| e.to_string())
+        })
+        .map_err(|e| Failure::new(ErrorKind::Metadata, e))
+    }
+}
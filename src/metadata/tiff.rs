@@ -0,0 +1,454 @@
+//! Minimal reader and writer for the TIFF structure inside an EXIF segment.
+//!
+//! IFD0, the Exif and GPS sub-IFDs and a JPEG thumbnail in IFD1 are kept.
+//! The interoperability IFD, maker notes and uncompressed (strip) thumbnails
+//! hold offsets into the original segment that would be invalid once it is
+//! rewritten, so they are dropped.
+
+const TAG_THUMBNAIL_OFFSET: u16 = 0x0201;
+const TAG_THUMBNAIL_LENGTH: u16 = 0x0202;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_GPS_IFD: u16 = 0x8825;
+const TAG_INTEROP_IFD: u16 = 0xa005;
+const TAG_MAKER_NOTE: u16 = 0x927c;
+
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteOrder {
+    Little,
+    Big,
+}
+
+impl ByteOrder {
+    fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        match self {
+            ByteOrder::Little => u16::from_le_bytes(b),
+            ByteOrder::Big => u16::from_be_bytes(b),
+        }
+    }
+
+    fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        match self {
+            ByteOrder::Little => u32::from_le_bytes(b),
+            ByteOrder::Big => u32::from_be_bytes(b),
+        }
+    }
+
+    fn put_u16(self, out: &mut Vec<u8>, v: u16) {
+        match self {
+            ByteOrder::Little => out.extend_from_slice(&v.to_le_bytes()),
+            ByteOrder::Big => out.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+
+    fn put_u32(self, out: &mut Vec<u8>, v: u32) {
+        match self {
+            ByteOrder::Little => out.extend_from_slice(&v.to_le_bytes()),
+            ByteOrder::Big => out.extend_from_slice(&v.to_be_bytes()),
+        }
+    }
+}
+
+/// A single IFD entry. `data` holds the raw value bytes in the byte order of
+/// the [`Exif`] it belongs to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub tag: u16,
+    pub kind: u16,
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+impl Field {
+    pub fn ascii(tag: u16, value: &str) -> Self {
+        let mut data = value.as_bytes().to_vec();
+        data.push(0);
+        Self {
+            tag,
+            kind: TYPE_ASCII,
+            count: data.len() as u32,
+            data,
+        }
+    }
+
+    pub fn bytes(tag: u16, values: &[u8]) -> Self {
+        Self {
+            tag,
+            kind: TYPE_BYTE,
+            count: values.len() as u32,
+            data: values.to_vec(),
+        }
+    }
+
+    pub fn undefined(tag: u16, values: &[u8]) -> Self {
+        Self {
+            tag,
+            kind: TYPE_UNDEFINED,
+            count: values.len() as u32,
+            data: values.to_vec(),
+        }
+    }
+
+    pub fn rationals(order: ByteOrder, tag: u16, values: &[(u32, u32)]) -> Self {
+        let mut data = Vec::with_capacity(values.len() * 8);
+        for &(num, den) in values {
+            order.put_u32(&mut data, num);
+            order.put_u32(&mut data, den);
+        }
+        Self {
+            tag,
+            kind: TYPE_RATIONAL,
+            count: values.len() as u32,
+            data,
+        }
+    }
+
+    fn long(order: ByteOrder, tag: u16, value: u32) -> Self {
+        let mut data = Vec::with_capacity(4);
+        order.put_u32(&mut data, value);
+        Self {
+            tag,
+            kind: TYPE_LONG,
+            count: 1,
+            data,
+        }
+    }
+}
+
+fn type_size(kind: u16) -> Option<usize> {
+    match kind {
+        1 | 2 | 6 | 7 => Some(1),
+        3 | 8 => Some(2),
+        4 | 9 | 11 => Some(4),
+        5 | 10 | 12 => Some(8),
+        _ => None,
+    }
+}
+
+/// The embedded preview image: the IFD1 fields describing it, without the
+/// offset and length of `jpeg` which are set when it is serialized
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub fields: Vec<Field>,
+    pub jpeg: Vec<u8>,
+}
+
+/// The parts of an EXIF segment snapback reads and rewrites
+#[derive(Debug, Clone)]
+pub struct Exif {
+    pub order: ByteOrder,
+    pub ifd0: Vec<Field>,
+    pub exif: Vec<Field>,
+    pub gps: Vec<Field>,
+    pub thumbnail: Option<Thumbnail>,
+}
+
+impl Default for Exif {
+    fn default() -> Self {
+        Self {
+            order: ByteOrder::Big,
+            ifd0: Vec::new(),
+            exif: Vec::new(),
+            gps: Vec::new(),
+            thumbnail: None,
+        }
+    }
+}
+
+impl Exif {
+    /// Parse the TIFF structure that follows the `Exif\0\0` header
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let order = match data.get(..4)? {
+            [b'I', b'I', 42, 0] => ByteOrder::Little,
+            [b'M', b'M', 0, 42] => ByteOrder::Big,
+            _ => return None,
+        };
+        let ifd0_offset = order.u32(data.get(4..8)?) as usize;
+        let (ifd0, ifd1_offset) = read_ifd(data, order, ifd0_offset)?;
+
+        let sub_ifd = |tag: u16| -> Vec<Field> {
+            ifd0.iter()
+                .find(|f| f.tag == tag && f.data.len() >= 4)
+                .and_then(|f| read_ifd(data, order, order.u32(&f.data) as usize))
+                .map(|(fields, _)| fields)
+                .unwrap_or_default()
+        };
+        let exif = sub_ifd(TAG_EXIF_IFD)
+            .into_iter()
+            .filter(|f| f.tag != TAG_INTEROP_IFD && f.tag != TAG_MAKER_NOTE)
+            .collect();
+        let gps = sub_ifd(TAG_GPS_IFD);
+        let thumbnail = match ifd1_offset {
+            0 => None,
+            offset => read_thumbnail(data, order, offset as usize),
+        };
+
+        Some(Self {
+            order,
+            ifd0: ifd0
+                .into_iter()
+                .filter(|f| f.tag != TAG_EXIF_IFD && f.tag != TAG_GPS_IFD)
+                .collect(),
+            exif,
+            gps,
+            thumbnail,
+        })
+    }
+
+    /// Serialize back into a TIFF structure (without the `Exif\0\0` header)
+    pub fn serialize(&self) -> Vec<u8> {
+        let order = self.order;
+        let mut ifd0 = self.ifd0.clone();
+        let exif = sorted(&self.exif);
+        let gps = sorted(&self.gps);
+
+        // Pointer values are fixed up below once the layout is known, but
+        // they have to be present to size IFD0 correctly
+        if !exif.is_empty() {
+            ifd0.push(Field::long(order, TAG_EXIF_IFD, 0));
+        }
+        if !gps.is_empty() {
+            ifd0.push(Field::long(order, TAG_GPS_IFD, 0));
+        }
+        let mut ifd0 = sorted(&ifd0);
+
+        let ifd0_offset = 8;
+        let exif_offset = ifd0_offset + ifd_size(&ifd0);
+        let gps_offset = exif_offset + if exif.is_empty() { 0 } else { ifd_size(&exif) };
+        let ifd1_offset = gps_offset + if gps.is_empty() { 0 } else { ifd_size(&gps) };
+        let ifd1 = self.thumbnail.as_ref().map(|thumbnail| {
+            let mut fields = thumbnail.fields.clone();
+            fields.push(Field::long(order, TAG_THUMBNAIL_OFFSET, 0));
+            fields.push(Field::long(
+                order,
+                TAG_THUMBNAIL_LENGTH,
+                thumbnail.jpeg.len() as u32,
+            ));
+            let jpeg_offset = ifd1_offset + ifd_size(&fields);
+            set(
+                &mut fields,
+                Field::long(order, TAG_THUMBNAIL_OFFSET, jpeg_offset as u32),
+            );
+            sorted(&fields)
+        });
+        for field in ifd0.iter_mut() {
+            match field.tag {
+                TAG_EXIF_IFD => *field = Field::long(order, TAG_EXIF_IFD, exif_offset as u32),
+                TAG_GPS_IFD => *field = Field::long(order, TAG_GPS_IFD, gps_offset as u32),
+                _ => {}
+            }
+        }
+
+        let mut out = Vec::new();
+        match order {
+            ByteOrder::Little => out.extend_from_slice(b"II\x2a\x00"),
+            ByteOrder::Big => out.extend_from_slice(b"MM\x00\x2a"),
+        }
+        order.put_u32(&mut out, ifd0_offset as u32);
+        let next = if ifd1.is_some() { ifd1_offset } else { 0 };
+        write_ifd(&mut out, order, &ifd0, next as u32);
+        if !exif.is_empty() {
+            write_ifd(&mut out, order, &exif, 0);
+        }
+        if !gps.is_empty() {
+            write_ifd(&mut out, order, &gps, 0);
+        }
+        if let (Some(ifd1), Some(thumbnail)) = (ifd1, &self.thumbnail) {
+            write_ifd(&mut out, order, &ifd1, 0);
+            out.extend_from_slice(&thumbnail.jpeg);
+        }
+        out
+    }
+}
+
+/// Replace or add `field` in `fields`
+pub fn set(fields: &mut Vec<Field>, field: Field) {
+    fields.retain(|f| f.tag != field.tag);
+    fields.push(field);
+}
+
+fn sorted(fields: &[Field]) -> Vec<Field> {
+    let mut fields = fields.to_vec();
+    fields.sort_by_key(|f| f.tag);
+    fields
+}
+
+/// Read the IFD at `offset`, returning its fields and the offset of the next
+/// IFD (0 if there is none)
+fn read_ifd(data: &[u8], order: ByteOrder, offset: usize) -> Option<(Vec<Field>, u32)> {
+    let count = order.u16(data.get(offset..offset + 2)?) as usize;
+    let mut fields = Vec::with_capacity(count);
+    for i in 0..count {
+        let entry = data.get(offset + 2 + i * 12..offset + 2 + (i + 1) * 12)?;
+        let tag = order.u16(&entry[0..2]);
+        let kind = order.u16(&entry[2..4]);
+        let count = order.u32(&entry[4..8]);
+        let Some(size) = type_size(kind).map(|s| s * count as usize) else {
+            continue;
+        };
+        let value = if size <= 4 {
+            &entry[8..8 + size]
+        } else {
+            let start = order.u32(&entry[8..12]) as usize;
+            match data.get(start..start + size) {
+                Some(value) => value,
+                None => continue,
+            }
+        };
+        fields.push(Field {
+            tag,
+            kind,
+            count,
+            data: value.to_vec(),
+        });
+    }
+    let next_at = offset + 2 + count * 12;
+    let next = data
+        .get(next_at..next_at + 4)
+        .map_or(0, |next| order.u32(next));
+    Some((fields, next))
+}
+
+/// Read IFD1 at `offset` if it describes a JPEG thumbnail
+fn read_thumbnail(data: &[u8], order: ByteOrder, offset: usize) -> Option<Thumbnail> {
+    let (fields, _) = read_ifd(data, order, offset)?;
+    let long = |tag: u16| {
+        fields
+            .iter()
+            .find(|f| f.tag == tag && f.kind == TYPE_LONG)
+            .map(|f| order.u32(&f.data) as usize)
+    };
+    let start = long(TAG_THUMBNAIL_OFFSET)?;
+    let len = long(TAG_THUMBNAIL_LENGTH)?;
+    let jpeg = data.get(start..start + len)?.to_vec();
+    Some(Thumbnail {
+        fields: fields
+            .into_iter()
+            .filter(|f| f.tag != TAG_THUMBNAIL_OFFSET && f.tag != TAG_THUMBNAIL_LENGTH)
+            .collect(),
+        jpeg,
+    })
+}
+
+fn padded(len: usize) -> usize {
+    len + len % 2
+}
+
+/// Size of an IFD including the values that don't fit in its entries
+fn ifd_size(fields: &[Field]) -> usize {
+    2 + fields.len() * 12
+        + 4
+        + fields
+            .iter()
+            .filter(|f| f.data.len() > 4)
+            .map(|f| padded(f.data.len()))
+            .sum::<usize>()
+}
+
+/// Append an IFD whose entries start at `out.len()`, followed by its values.
+/// `next` is the offset of the IFD that follows it, or 0.
+fn write_ifd(out: &mut Vec<u8>, order: ByteOrder, fields: &[Field], next: u32) {
+    let start = out.len();
+    let mut value_offset = start + 2 + fields.len() * 12 + 4;
+    let mut values = Vec::new();
+
+    order.put_u16(out, fields.len() as u16);
+    for field in fields {
+        order.put_u16(out, field.tag);
+        order.put_u16(out, field.kind);
+        order.put_u32(out, field.count);
+        if field.data.len() <= 4 {
+            let mut inline = field.data.clone();
+            inline.resize(4, 0);
+            out.extend_from_slice(&inline);
+        } else {
+            order.put_u32(out, value_offset as u32);
+            values.extend_from_slice(&field.data);
+            if field.data.len() % 2 == 1 {
+                values.push(0);
+            }
+            value_offset += padded(field.data.len());
+        }
+    }
+    order.put_u32(out, next);
+    out.extend_from_slice(&values);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exif(order: ByteOrder) -> Exif {
+        Exif {
+            order,
+            ifd0: vec![Field::ascii(0x010f, "Camera"), Field::bytes(0x0001, &[1])],
+            exif: vec![
+                Field::ascii(0x9003, "2021:07:03 14:34:56"),
+                Field::undefined(0x9000, b"0231"),
+            ],
+            gps: vec![Field::rationals(
+                order,
+                0x0002,
+                &[(40, 1), (41, 1), (211_200, 10_000)],
+            )],
+            thumbnail: Some(Thumbnail {
+                fields: vec![Field::rationals(order, 0x011a, &[(72, 1)])],
+                jpeg: b"\xff\xd8thumbnail\xff\xd9".to_vec(),
+            }),
+        }
+    }
+
+    #[test]
+    fn serialized_exif_is_parsed_back_in_both_byte_orders() {
+        for order in [ByteOrder::Little, ByteOrder::Big] {
+            let original = exif(order);
+            let parsed = Exif::parse(&original.serialize()).unwrap();
+            assert_eq!(parsed.order, order);
+            assert_eq!(parsed.ifd0, sorted(&original.ifd0));
+            assert_eq!(parsed.exif, sorted(&original.exif));
+            assert_eq!(parsed.gps, original.gps);
+            assert_eq!(parsed.thumbnail, original.thumbnail);
+        }
+    }
+
+    #[test]
+    fn big_endian_values_are_read_as_stored() {
+        let data = b"MM\x00\x2a\x00\x00\x00\x08\
+            \x00\x02\
+            \x01\x0f\x00\x02\x00\x00\x00\x04Abc\x00\
+            \x01\x10\x00\x02\x00\x00\x00\x06\x00\x00\x00\x26\
+            \x00\x00\x00\x00\
+            Model\x00";
+        let exif = Exif::parse(data).unwrap();
+        assert_eq!(exif.order, ByteOrder::Big);
+        assert_eq!(
+            exif.ifd0,
+            [Field::ascii(0x010f, "Abc"), Field::ascii(0x0110, "Model")]
+        );
+        assert!(exif.thumbnail.is_none());
+        assert!(exif.serialize() == data);
+    }
+
+    #[test]
+    fn ifd1_without_a_jpeg_thumbnail_is_dropped() {
+        let mut original = exif(ByteOrder::Little);
+        original.thumbnail = None;
+        let mut data = original.serialize();
+        // Point IFD0 at an IFD1 holding only a StripOffsets entry
+        let ifd1 = data.len() as u32;
+        let next = 8 + 2 + 12 * 4;
+        data[next..next + 4].copy_from_slice(&ifd1.to_le_bytes());
+        data.extend_from_slice(b"\x01\x00\x11\x01\x04\x00\x01\x00\x00\x00\x00\x00\x00\x00\0\0\0\0");
+
+        let parsed = Exif::parse(&data).unwrap();
+        assert!(parsed.thumbnail.is_none());
+        assert_eq!(parsed.ifd0, sorted(&original.ifd0));
+    }
+}
@@ -0,0 +1,329 @@
+//! Building XMP packets, used for the JPEG APP1 XMP segment and the
+//! QuickTime `XMP_` atom.
+
+use super::Metadata;
+
+/// Toolkit name recorded in packets written by snapback, so they can be
+/// recognized and replaced rather than merged into
+const XMPTK: &str = "snapback";
+
+const NAMESPACES: [(&str, &str); 5] = [
+    ("exif", "http://ns.adobe.com/exif/1.0/"),
+    ("xmp", "http://ns.adobe.com/xap/1.0/"),
+    ("xmpMM", "http://ns.adobe.com/xap/1.0/mm/"),
+    ("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+    ("dc", "http://purl.org/dc/elements/1.1/"),
+];
+
+/// Escape text for use in XML attribute values and element content
+pub fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Format a coordinate the way XMP expects GPS values: `DDD,MM.mmmmK`. The
+/// value is rounded once, to millionths of a minute, so rounding carries into
+/// the degrees instead of producing 60 minutes.
+fn xmp_coordinate(value: f64, positive: char, negative: char) -> String {
+    let reference = if value < 0.0 { negative } else { positive };
+    let ticks = (value.abs() * 60_000_000.0).round() as u64;
+    let minutes = ticks % 60_000_000;
+    format!(
+        "{},{}.{:06}{}",
+        ticks / 60_000_000,
+        minutes / 1_000_000,
+        minutes % 1_000_000,
+        reference
+    )
+}
+
+/// The properties written as attributes of the `rdf:Description`
+fn attributes(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let date = metadata.date.to_rfc3339();
+
+    let mut attributes = vec![
+        ("dc:identifier", metadata.media_id.clone()),
+        ("dc:source", metadata.source.to_string()),
+        ("exif:DateTimeOriginal", date.clone()),
+        ("xmp:CreateDate", date.clone()),
+        ("xmp:ModifyDate", date.clone()),
+        ("photoshop:DateCreated", date),
+    ];
+    if let Some(coordinates) = &metadata.coordinates {
+        attributes.push((
+            "exif:GPSLatitude",
+            xmp_coordinate(coordinates.lat, 'N', 'S'),
+        ));
+        attributes.push((
+            "exif:GPSLongitude",
+            xmp_coordinate(coordinates.lon, 'E', 'W'),
+        ));
+        if let Some(method) = &metadata.location_method {
+            attributes.push(("exif:GPSProcessingMethod", method.clone()));
+        }
+    }
+
+    if let Some(original_filename) = &metadata.original_filename {
+        attributes.push(("xmpMM:PreservedFileName", original_filename.clone()));
+    }
+    attributes
+}
+
+/// Every property written for `metadata`, attributes and elements alike
+fn properties(metadata: &Metadata) -> Vec<&'static str> {
+    let mut properties: Vec<_> = attributes(metadata)
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect();
+    if metadata.description.is_some() {
+        properties.push("dc:description");
+    }
+    if metadata.media_type.is_some() {
+        properties.push("dc:type");
+    }
+    if !metadata.subjects.is_empty() {
+        properties.push("dc:subject");
+    }
+    properties
+}
+
+/// The `rdf:Description` element describing `metadata`
+pub fn description(metadata: &Metadata) -> String {
+    let mut out = String::from("  <rdf:Description rdf:about=\"\"");
+    for (prefix, uri) in NAMESPACES {
+        out.push_str(&format!("\n    xmlns:{}=\"{}\"", prefix, uri));
+    }
+    for (name, value) in attributes(metadata) {
+        out.push_str(&format!("\n   {}=\"{}\"", name, escape(&value)));
+    }
+    out.push('>');
+
+    if let Some(description) = &metadata.description {
+        out.push_str(&format!(
+            "\n   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>",
+            escape(description)
+        ));
+    }
+    if let Some(media_type) = &metadata.media_type {
+        out.push_str(&format!(
+            "\n   <dc:type><rdf:Bag><rdf:li>{}</rdf:li></rdf:Bag></dc:type>",
+            media_type.as_str()
+        ));
+    }
+    if !metadata.subjects.is_empty() {
+        out.push_str("\n   <dc:subject><rdf:Bag>");
+        for subject in &metadata.subjects {
+            out.push_str(&format!("<rdf:li>{}</rdf:li>", escape(subject)));
+        }
+        out.push_str("</rdf:Bag></dc:subject>");
+    }
+    out.push_str("\n  </rdf:Description>\n");
+    out
+}
+
+/// A complete XMP packet containing only `metadata`
+pub fn packet(metadata: &Metadata) -> String {
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\" x:xmptk=\"{}\">\n \
+         <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         {} </rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        XMPTK,
+        description(metadata)
+    )
+}
+
+/// Add `metadata` to an existing packet. Packets previously written by
+/// snapback are replaced; anything else gets an extra `rdf:Description`,
+/// and the properties about to be written are removed from the existing
+/// ones so readers don't find two conflicting values. The rest are kept.
+pub fn merge(existing: &str, metadata: &Metadata) -> String {
+    if existing.contains(&format!("x:xmptk=\"{}\"", XMPTK)) {
+        return packet(metadata);
+    }
+    let Some(end) = existing.rfind("</rdf:RDF>") else {
+        return packet(metadata);
+    };
+
+    let prefixes = declared_prefixes(existing);
+    let mut kept = existing[..end].to_string();
+    for property in properties(metadata) {
+        let (prefix, name) = property.split_once(':').unwrap();
+        let uri = NAMESPACES
+            .iter()
+            .find(|(p, _)| *p == prefix)
+            .map(|(_, uri)| *uri)
+            .unwrap();
+        // The packet may bind the namespace to another prefix, e.g. `xap`
+        for (declared, _) in prefixes.iter().filter(|(_, u)| *u == uri) {
+            kept = remove_property(&kept, &format!("{}:{}", declared, name));
+        }
+    }
+    format!("{}{}{}", kept, description(metadata), &existing[end..])
+}
+
+/// The `xmlns:prefix="uri"` namespace declarations in `packet`
+fn declared_prefixes(packet: &str) -> Vec<(&str, &str)> {
+    let mut prefixes = Vec::new();
+    for (start, _) in packet.match_indices("xmlns:") {
+        let rest = &packet[start + "xmlns:".len()..];
+        let Some((prefix, rest)) = rest.split_once('=') else {
+            continue;
+        };
+        let rest = rest.trim_start();
+        let Some(quote) = rest.chars().next().filter(|c| *c == '"' || *c == '\'') else {
+            continue;
+        };
+        if let Some(uri) = rest[1..].split(quote).next() {
+            prefixes.push((prefix.trim(), uri));
+        }
+    }
+    prefixes
+}
+
+/// Remove every `property`, written either as an attribute
+/// (`property="value"`) or as an element (`<property>...</property>`), from
+/// the XML in `xml`
+fn remove_property(xml: &str, property: &str) -> String {
+    let mut out = String::with_capacity(xml.len());
+    let mut rest = xml;
+    while let Some(found) = rest.find(property) {
+        let before = &rest[..found];
+        let after = &rest[found + property.len()..];
+        let end = if before.ends_with('<') {
+            element_end(after, property)
+        } else if before.ends_with(|c: char| c.is_whitespace()) {
+            attribute_end(after)
+        } else {
+            None
+        };
+        match end {
+            Some(end) => {
+                // Drop the indentation before it as well
+                let kept = match before.strip_suffix('<') {
+                    Some(before) => {
+                        let before = before.trim_end_matches([' ', '\t']);
+                        before.strip_suffix('\n').unwrap_or(before)
+                    }
+                    None => before.trim_end(),
+                };
+                out.push_str(kept);
+                rest = &after[end..];
+            }
+            None => {
+                out.push_str(&rest[..found + property.len()]);
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The length of the rest of an attribute (`="value"`) following its name
+/// in `after`, or `None` if the name was only the start of a longer one
+fn attribute_end(after: &str) -> Option<usize> {
+    let value = after.trim_start().strip_prefix('=')?.trim_start();
+    let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+    let close = value[1..].find(quote)?;
+    Some(after.len() - value.len() + close + 2)
+}
+
+/// The length of the rest of an element following `<name` in `after`, up to
+/// and including its closing tag
+fn element_end(after: &str, name: &str) -> Option<usize> {
+    if !after.starts_with(['>', '/', ' ', '\t', '\r', '\n']) {
+        return None;
+    }
+    let tag_end = after.find('>')?;
+    if after[..tag_end].ends_with('/') {
+        return Some(tag_end + 1);
+    }
+    let closing = format!("</{}>", name);
+    let close = after.find(&closing)?;
+    Some(close + closing.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tests::metadata;
+
+    /// A packet from another tool, binding the XMP namespace to the old
+    /// `xap` prefix and using both attributes and elements
+    const FOREIGN: &str = r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="Adobe XMP Core 5.6">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xap="http://ns.adobe.com/xap/1.0/"
+    xmlns:dc="http://purl.org/dc/elements/1.1/"
+    xmlns:exif="http://ns.adobe.com/exif/1.0/"
+   xap:CreateDate="2020-01-01T00:00:00Z"
+   xap:CreatorTool="Camera">
+   <exif:DateTimeOriginal>2020-01-01T00:00:00Z</exif:DateTimeOriginal>
+   <dc:type><rdf:Bag><rdf:li>Photo</rdf:li></rdf:Bag></dc:type>
+   <dc:title><rdf:Alt><rdf:li xml:lang="x-default">Beach</rdf:li></rdf:Alt></dc:title>
+   <exif:GPSLatitude/>
+  </rdf:Description>
+ </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#;
+
+    #[test]
+    fn merging_replaces_conflicting_properties_and_keeps_the_rest() {
+        let merged = merge(FOREIGN, &metadata());
+
+        assert!(!merged.contains("2020-01-01"), "{}", merged);
+        assert!(!merged.contains("Photo"), "{}", merged);
+        assert!(!merged.contains("<exif:GPSLatitude/>"), "{}", merged);
+        assert_eq!(merged.matches("CreateDate=").count(), 1, "{}", merged);
+        assert!(merged.contains("xmp:CreateDate=\"2021-07-03T14:34:56+02:00\""));
+        assert!(merged.contains("xap:CreatorTool=\"Camera\""));
+        assert!(merged.contains("<rdf:li xml:lang=\"x-default\">Beach</rdf:li>"));
+        assert!(merged.contains("x:xmptk=\"Adobe XMP Core 5.6\""));
+        assert_eq!(merged.matches("<rdf:Description").count(), 2);
+    }
+
+    #[test]
+    fn snapback_packets_are_replaced() {
+        let mut older = metadata();
+        older.media_id = "older".to_string();
+        let merged = merge(&packet(&older), &metadata());
+        assert_eq!(merged, packet(&metadata()));
+    }
+
+    #[test]
+    fn properties_are_only_removed_by_their_full_name() {
+        let xml = "<a xmp:CreateDateX=\"1\" xmp:CreateDate=\"2\">\n <xmp:CreateDateY>3</xmp:CreateDateY>\n</a>";
+        assert_eq!(
+            remove_property(xml, "xmp:CreateDate"),
+            "<a xmp:CreateDateX=\"1\">\n <xmp:CreateDateY>3</xmp:CreateDateY>\n</a>"
+        );
+    }
+
+    #[test]
+    fn coordinates_use_degrees_and_decimal_minutes() {
+        assert_eq!(xmp_coordinate(40.6892, 'N', 'S'), "40,41.352000N");
+        assert_eq!(xmp_coordinate(-74.0445, 'E', 'W'), "74,2.670000W");
+    }
+
+    #[test]
+    fn minutes_that_round_up_carry_into_the_degrees() {
+        // 59.9999997 minutes
+        assert_eq!(xmp_coordinate(12.999999995, 'N', 'S'), "13,0.000000N");
+        assert_eq!(xmp_coordinate(-0.999999995, 'E', 'W'), "1,0.000000W");
+        assert_eq!(xmp_coordinate(12.5, 'N', 'S'), "12,30.000000N");
+    }
+}
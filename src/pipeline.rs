@@ -0,0 +1,1606 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono_tz::Tz;
+use clap::ValueEnum;
+use glob::glob;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::*;
+
+use crate::artifact::{
+    converted_path, overlaid_path, sidecar_path, with_stem_suffix, Artifact, ArtifactKind,
+    OVERLAY_SUFFIX,
+};
+use crate::bundle::{self, ChatGrouping, Grouping, MemoriesGrouping, MemoryBundle};
+use crate::chat::{chat_media_map, parse_chat_history_file};
+use crate::composite::{self, VideoEncoding, DEFAULT_IMAGE_QUALITY};
+use crate::convert::{self, ImageConversion, VideoConversion};
+use crate::infer::{LocationInference, INFERRED_LOCATION_METHOD};
+use crate::journal::{Entry, Journal, JOURNAL_FILE_NAME};
+use crate::memories::{parse_memories_history_file, LocationPolicy, Media, MediaType};
+use crate::metadata::{
+    is_video, Metadata, MetadataBackend, MetadataMode, MetadataWriter, Sidecar, TagProfile,
+};
+use crate::plan::{Plan, PlannedFile, PlannedOverlay};
+use crate::record::Record;
+use crate::rename::{unique_path, NameFields, RenameTemplate};
+use crate::report::{Conversion, ErrorKind, Failure, FileOutcome, Report, StepOutcome};
+use crate::timezone::LocalTime;
+use crate::tools::Tools;
+use crate::transfer::{self, Transfer};
+use crate::{journal, unzip};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlayMode {
+    /// Apply overlay directly to the original file
+    Overwrite,
+    /// Create a `_with_overlay` copy while preserving the original
+    Copy,
+    /// Skip overlays entirely
+    Ignore,
+}
+
+/// Which part of the export to process
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportSource {
+    /// Saved memories (`memories*/` + `memories_history.json`)
+    Memories,
+    /// Media sent and received in chats (`chat_media/` + `chat_history.json`
+    /// and `snap_history.json`)
+    Chat,
+}
+
+/// Subdirectories of the output directory files are sorted into
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Layout {
+    /// Everything directly in the output directory
+    #[default]
+    Flat,
+    /// `2021/07/`, by local capture date
+    #[value(name = "year/month")]
+    YearMonth,
+    /// `2021/07/03/`, by local capture date
+    #[value(name = "year/month/day")]
+    YearMonthDay,
+    /// `images/` and `videos/`
+    ByMediaType,
+}
+
+/// Subdirectory for files sorted by date that have no record to take the
+/// date from
+const UNKNOWN_DATE_DIR: &str = "unknown";
+
+/// Directory in the output directory that [`Transfer::Copy`] processes
+/// copies of the exported files in
+const STAGING_DIR_NAME: &str = ".snapback-staging";
+
+/// Extensions of the media, overlay and thumbnail files in an export
+const MEDIA_EXTENSIONS: [&str; 6] = ["jpg", "jpeg", "png", "webp", "mp4", "mov"];
+
+/// Options controlling a [`Pipeline`] run
+///
+/// Construct with [`ProcessOptions::new`], adjust with the builder methods and
+/// finish with [`ProcessOptions::build`]:
+///
+/// ```no_run
+/// use snapback::{OverlayMode, ProcessOptions};
+///
+/// let report = ProcessOptions::new()
+///     .overlays(OverlayMode::Copy)
+///     .processes(4)
+///     .skip_unzip(true)
+///     .build()
+///     .run()
+///     .unwrap();
+/// println!("moved {} files", report.moved);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProcessOptions {
+    pub source: ExportSource,
+    pub overlays: OverlayMode,
+    pub metadata_backend: MetadataBackend,
+    pub metadata_mode: MetadataMode,
+    pub tag_profile: TagProfile,
+    pub processes: usize,
+    pub zip_dir: PathBuf,
+    pub extract_dir: PathBuf,
+    pub output_dir: PathBuf,
+    pub overlay_suffix: String,
+    pub overlay_output_dir: Option<PathBuf>,
+    pub image_quality: u8,
+    pub video_encoding: VideoEncoding,
+    pub tools: Tools,
+    pub convert_images: ImageConversion,
+    pub convert_videos: VideoConversion,
+    pub rename: Option<RenameTemplate>,
+    pub layout: Layout,
+    pub transfer: Transfer,
+    pub skip_unzip: bool,
+    pub memories_history_json_path: PathBuf,
+    pub chat_history_json_path: PathBuf,
+    pub snap_history_json_path: PathBuf,
+    pub tag_partner: bool,
+    pub local_time: LocalTime,
+    pub missing_location: LocationPolicy,
+    pub infer_location: Option<LocationInference>,
+    pub mark_inferred_location: bool,
+    pub media_prefix: String,
+    /// How exported files are grouped into bundles; `None` for the
+    /// [`ExportSource`]'s own naming scheme
+    pub grouping: Option<Arc<dyn Grouping>>,
+    pub show_progress: bool,
+    pub journal: bool,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        Self {
+            source: ExportSource::Memories,
+            overlays: OverlayMode::Overwrite,
+            metadata_backend: MetadataBackend::Native,
+            metadata_mode: MetadataMode::Embedded,
+            tag_profile: TagProfile::PhotosApp,
+            processes: 1,
+            zip_dir: PathBuf::from("."),
+            extract_dir: PathBuf::from("."),
+            output_dir: PathBuf::from("./processed_media"),
+            overlay_suffix: OVERLAY_SUFFIX.to_string(),
+            overlay_output_dir: None,
+            image_quality: DEFAULT_IMAGE_QUALITY,
+            video_encoding: VideoEncoding::default(),
+            tools: Tools::default(),
+            convert_images: ImageConversion::Keep,
+            convert_videos: VideoConversion::Keep,
+            rename: None,
+            layout: Layout::Flat,
+            transfer: Transfer::Move,
+            skip_unzip: false,
+            memories_history_json_path: PathBuf::from("./json/memories_history.json"),
+            chat_history_json_path: PathBuf::from("./json/chat_history.json"),
+            snap_history_json_path: PathBuf::from("./json/snap_history.json"),
+            tag_partner: false,
+            local_time: LocalTime::default(),
+            missing_location: LocationPolicy::Skip,
+            infer_location: None,
+            mark_inferred_location: false,
+            media_prefix: String::from("memories"),
+            grouping: None,
+            show_progress: false,
+            journal: true,
+        }
+    }
+}
+
+impl ProcessOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Which part of the export to process
+    pub fn source(mut self, source: ExportSource) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// How to handle overlays (captions, drawings, stickers, etc.)
+    pub fn overlays(mut self, overlays: OverlayMode) -> Self {
+        self.overlays = overlays;
+        self
+    }
+
+    /// How metadata is written onto the media files
+    pub fn metadata_backend(mut self, metadata_backend: MetadataBackend) -> Self {
+        self.metadata_backend = metadata_backend;
+        self
+    }
+
+    /// Whether metadata is written into the media files or into XMP sidecars
+    /// next to them
+    pub fn metadata_mode(mut self, metadata_mode: MetadataMode) -> Self {
+        self.metadata_mode = metadata_mode;
+        self
+    }
+
+    /// Which tags are written onto the media files
+    pub fn tag_profile(mut self, tag_profile: TagProfile) -> Self {
+        self.tag_profile = tag_profile;
+        self
+    }
+
+    /// Number of concurrent exiftool/ffmpeg processes
+    pub fn processes(mut self, processes: usize) -> Self {
+        self.processes = processes;
+        self
+    }
+
+    /// Directory containing zip files to unpack
+    pub fn zip_dir(mut self, zip_dir: impl Into<PathBuf>) -> Self {
+        self.zip_dir = zip_dir.into();
+        self
+    }
+
+    /// Directory the archives are extracted into and media is discovered from
+    pub fn extract_dir(mut self, extract_dir: impl Into<PathBuf>) -> Self {
+        self.extract_dir = extract_dir.into();
+        self
+    }
+
+    /// Directory to move processed media files into
+    pub fn output_dir(mut self, output_dir: impl Into<PathBuf>) -> Self {
+        self.output_dir = output_dir.into();
+        self
+    }
+
+    /// Added to the file names of overlaid copies ([`OverlayMode::Copy`])
+    pub fn overlay_suffix(mut self, overlay_suffix: impl Into<String>) -> Self {
+        self.overlay_suffix = overlay_suffix.into();
+        self
+    }
+
+    /// Directory to move overlaid copies ([`OverlayMode::Copy`]) into instead
+    /// of the output directory
+    pub fn overlay_output_dir(mut self, overlay_output_dir: Option<PathBuf>) -> Self {
+        self.overlay_output_dir = overlay_output_dir;
+        self
+    }
+
+    /// JPEG quality (1-100) photos are re-encoded with after compositing
+    /// their overlay
+    pub fn image_quality(mut self, image_quality: u8) -> Self {
+        self.image_quality = image_quality;
+        self
+    }
+
+    /// How videos are re-encoded after compositing their overlay
+    pub fn video_encoding(mut self, video_encoding: VideoEncoding) -> Self {
+        self.video_encoding = video_encoding;
+        self
+    }
+
+    /// Where to find ffmpeg, ffprobe and heif-enc, when they aren't on `PATH`
+    pub fn tools(mut self, tools: Tools) -> Self {
+        self.tools = tools;
+        self
+    }
+
+    /// Format photos are converted to before they're moved
+    pub fn convert_images(mut self, convert_images: ImageConversion) -> Self {
+        self.convert_images = convert_images;
+        self
+    }
+
+    /// Codec videos are converted to before they're moved
+    pub fn convert_videos(mut self, convert_videos: VideoConversion) -> Self {
+        self.convert_videos = convert_videos;
+        self
+    }
+
+    /// Name files in the output directory after a template instead of keeping
+    /// their exported names
+    pub fn rename(mut self, rename: Option<RenameTemplate>) -> Self {
+        self.rename = rename;
+        self
+    }
+
+    /// How files are sorted into subdirectories of the output directory
+    pub fn layout(mut self, layout: Layout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// How files get into the output directory
+    pub fn transfer(mut self, transfer: Transfer) -> Self {
+        self.transfer = transfer;
+        self
+    }
+
+    /// Skip the unzip step (use if .zip files are already extracted)
+    pub fn skip_unzip(mut self, skip_unzip: bool) -> Self {
+        self.skip_unzip = skip_unzip;
+        self
+    }
+
+    /// Path to the `memories_history.json` file from the export
+    pub fn memories_history_json_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.memories_history_json_path = path.into();
+        self
+    }
+
+    /// Path to the `chat_history.json` file from the export
+    pub fn chat_history_json_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.chat_history_json_path = path.into();
+        self
+    }
+
+    /// Path to the `snap_history.json` file from the export
+    pub fn snap_history_json_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.snap_history_json_path = path.into();
+        self
+    }
+
+    /// Tag chat media with the conversation partner
+    pub fn tag_partner(mut self, tag_partner: bool) -> Self {
+        self.tag_partner = tag_partner;
+        self
+    }
+
+    /// Zone capture times are written in: for every file, or with
+    /// [`ProcessOptions::timezone_lookup`] for those that can't be localized
+    /// from a location (chat media, memories without one)
+    pub fn timezone(mut self, timezone: Tz) -> Self {
+        self.local_time.fallback = timezone;
+        self
+    }
+
+    /// Whether to convert capture times to the local time at the capture
+    /// location (otherwise [`ProcessOptions::timezone`] is used for all).
+    /// Off by default, since the zone is only approximated from the nearest
+    /// reference city; see [`crate::timezone`].
+    pub fn timezone_lookup(mut self, timezone_lookup: bool) -> Self {
+        self.local_time.lookup = timezone_lookup;
+        self
+    }
+
+    /// What to write for memories without a real location
+    pub fn missing_location(mut self, missing_location: LocationPolicy) -> Self {
+        self.missing_location = missing_location;
+        self
+    }
+
+    /// Fill in missing memory locations from memories taken shortly before or
+    /// after (`None` to leave them missing)
+    pub fn infer_location(mut self, infer_location: Option<LocationInference>) -> Self {
+        self.infer_location = infer_location;
+        self
+    }
+
+    /// Record in the XMP metadata when a written location was inferred
+    pub fn mark_inferred_location(mut self, mark_inferred_location: bool) -> Self {
+        self.mark_inferred_location = mark_inferred_location;
+        self
+    }
+
+    /// Directory name prefix to glob for media files
+    pub fn media_prefix(mut self, media_prefix: impl Into<String>) -> Self {
+        self.media_prefix = media_prefix.into();
+        self
+    }
+
+    /// Group exported files into bundles with `grouping` instead of by the
+    /// [`ExportSource`]'s naming scheme ([`MemoriesGrouping`] or
+    /// [`ChatGrouping`]). Its ids are what files are matched to records by.
+    pub fn grouping(mut self, grouping: impl Grouping + 'static) -> Self {
+        self.grouping = Some(Arc::new(grouping));
+        self
+    }
+
+    /// Draw progress bars and per-file messages on the terminal
+    pub fn show_progress(mut self, show_progress: bool) -> Self {
+        self.show_progress = show_progress;
+        self
+    }
+
+    /// Keep a journal of completed stages in the output directory so an
+    /// interrupted run can be resumed without repeating work
+    pub fn journal(mut self, journal: bool) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        Pipeline::new(self)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    MemoriesHistoryNotFound(PathBuf),
+    MemoriesHistory(PathBuf, serde_json::Error),
+    ChatHistoryNotFound(PathBuf, PathBuf),
+    ChatHistory(PathBuf, serde_json::Error),
+    OutputDir(PathBuf, io::Error),
+    /// Overlaid copies would get the same names as their originals
+    EmptyOverlaySuffix,
+    /// Conversions would replace the media sidecar mode leaves untouched
+    SidecarConversion,
+    /// Extracting again would overwrite hard linked outputs, which can only
+    /// be detected on Unix
+    HardlinkWithUnzip,
+    Journal(PathBuf, io::Error),
+    ThreadPool(rayon::ThreadPoolBuildError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::MemoriesHistoryNotFound(path) => write!(
+                f,
+                "Memories history file not found at {:?}. Did unzipping work?",
+                path
+            ),
+            Error::MemoriesHistory(path, e) => {
+                write!(f, "Failed to parse memories history {:?}: {}", path, e)
+            }
+            Error::ChatHistoryNotFound(chat, snap) => write!(
+                f,
+                "Neither {:?} nor {:?} exist. Did unzipping work?",
+                chat, snap
+            ),
+            Error::ChatHistory(path, e) => {
+                write!(f, "Failed to parse chat history {:?}: {}", path, e)
+            }
+            Error::OutputDir(path, e) => {
+                write!(f, "Failed to create output directory {:?}: {}", path, e)
+            }
+            Error::EmptyOverlaySuffix => write!(
+                f,
+                "An empty overlay suffix needs a separate overlay output directory, \
+                 or the overlaid copies would replace the originals"
+            ),
+            Error::SidecarConversion => write!(
+                f,
+                "--metadata-mode sidecar leaves the media untouched, \
+                 so it can't be combined with --convert-images or --convert-videos"
+            ),
+            Error::HardlinkWithUnzip => write!(
+                f,
+                "--transfer hardlink needs --skip-unzip on this platform, \
+                 or extracting the export again would overwrite the outputs"
+            ),
+            Error::Journal(path, e) => write!(f, "Failed to open journal {:?}: {}", path, e),
+            Error::ThreadPool(e) => write!(f, "Failed to build thread pool: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MemoriesHistoryNotFound(_) => None,
+            Error::MemoriesHistory(_, e) => Some(e),
+            Error::ChatHistoryNotFound(..) => None,
+            Error::ChatHistory(_, e) => Some(e),
+            Error::OutputDir(_, e) => Some(e),
+            Error::EmptyOverlaySuffix => None,
+            Error::SidecarConversion => None,
+            Error::HardlinkWithUnzip => None,
+            Error::Journal(_, e) => Some(e),
+            Error::ThreadPool(e) => Some(e),
+        }
+    }
+}
+
+/// The unzip → parse → metadata → overlay → move pipeline
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    options: ProcessOptions,
+}
+
+impl Pipeline {
+    pub fn new(options: ProcessOptions) -> Self {
+        Self { options }
+    }
+
+    pub fn options(&self) -> &ProcessOptions {
+        &self.options
+    }
+
+    /// Run every step of the pipeline and report what happened to each file
+    pub fn run(&self) -> Result<Report, Error> {
+        self.check_options()?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.options.processes)
+            .build()
+            .map_err(Error::ThreadPool)?;
+
+        if !self.options.skip_unzip {
+            self.unzip();
+        }
+
+        let records = self.load_records()?;
+        let bundles = self.discover_bundles();
+
+        let output_dir = &self.options.output_dir;
+        fs::create_dir_all(output_dir).map_err(|e| Error::OutputDir(output_dir.clone(), e))?;
+
+        let writer: Box<dyn MetadataWriter> = match self.options.metadata_mode {
+            MetadataMode::Embedded => self
+                .options
+                .metadata_backend
+                .writer(self.options.processes, self.options.tag_profile),
+            MetadataMode::Sidecar => Box::new(Sidecar),
+        };
+        let journal = if self.options.journal {
+            let journal_path = self.journal_path();
+            Some(Journal::open(&journal_path).map_err(|e| Error::Journal(journal_path, e))?)
+        } else {
+            None
+        };
+
+        let mut files = pool
+            .install(|| self.process_files(&bundles, &records, writer.as_ref(), journal.as_ref()));
+        let moved = self.move_files(&mut files, &records, journal.as_ref());
+        // What's left is the staged originals of converted files, and files
+        // that failed to move, which the next run stages again
+        if self.options.transfer == Transfer::Copy {
+            let _ = fs::remove_dir_all(self.staging_dir());
+        }
+
+        Ok(Report { files, moved })
+    }
+
+    /// Work out what [`Pipeline::run`] would do without modifying anything.
+    ///
+    /// Archives are listed but not extracted, so with the unzip step enabled
+    /// only media that has already been extracted is planned.
+    pub fn plan(&self) -> Result<Plan, Error> {
+        self.check_options()?;
+        let archives = if self.options.skip_unzip {
+            Vec::new()
+        } else {
+            unzip::find_archives(&self.options.zip_dir).unwrap_or_default()
+        };
+
+        let records = self.load_records()?;
+        let journal = if self.options.journal {
+            let journal_path = self.journal_path();
+            Journal::load(&journal_path).map_err(|e| Error::Journal(journal_path, e))?
+        } else {
+            HashMap::new()
+        };
+
+        let mut taken = HashSet::new();
+        let files = self
+            .discover_bundles()
+            .into_iter()
+            .map(|bundle| {
+                let path = bundle.main;
+                let staged = self.staged_path(&path);
+                // Where the processing happens
+                let working = staged.clone().unwrap_or_else(|| path.clone());
+                let previous = journal.get(&self.journal_key(&path)).cloned();
+                let current = if working.exists() { &working } else { &path };
+                let (entry, _) = Entry::reconcile(previous, journal::fingerprint(current).ok());
+
+                let record = records.get(&bundle.id);
+                let overlaid =
+                    self.overlay_mode() != OverlayMode::Ignore && !bundle.overlays.is_empty();
+                let composited =
+                    is_video(&path) && overlaid && self.overlay_mode() == OverlayMode::Overwrite;
+                let conversion = self
+                    .conversion_for(&path, composited)
+                    .filter(|_| !entry.converted && !entry.is_moved());
+
+                // Claimed in the order the move step claims them: the file,
+                // then its composited copy
+                let destination = self
+                    .destination_for(&self.delivered_name(&path, composited), record, false)
+                    .filter(|_| !entry.is_moved())
+                    .map(|dest| unique_path(dest, &mut taken));
+                let sidecar =
+                    self.options.metadata_mode == MetadataMode::Sidecar && record.is_some();
+                let overlay = if !overlaid || entry.overlay {
+                    None
+                } else {
+                    let output = overlay_output_for(
+                        &working,
+                        self.overlay_mode(),
+                        &self.options.overlay_suffix,
+                    );
+                    // Composited videos are encoded with the target codec
+                    // already, so only photo copies are converted
+                    let copy_conversion = conversion.filter(|_| !is_video(&path));
+                    let delivered = match copy_conversion {
+                        Some(ext) => path.with_extension(ext),
+                        None => path.clone(),
+                    };
+                    let copy = output != working;
+                    let destination = copy
+                        .then(|| self.destination_for(&delivered, record, true))
+                        .flatten()
+                        .filter(|_| !entry.is_moved())
+                        .map(|dest| unique_path(dest, &mut taken));
+                    Some(PlannedOverlay {
+                        sources: bundle.overlays,
+                        converted: copy_conversion
+                            .filter(|_| copy)
+                            .map(|ext| converted_path(&output, ext)),
+                        sidecar_destination: destination
+                            .as_deref()
+                            .filter(|_| sidecar)
+                            .map(sidecar_path),
+                        destination,
+                        output,
+                    })
+                };
+
+                PlannedFile {
+                    media_id: record.map(|r| r.id().to_string()),
+                    tags: record
+                        .filter(|_| !entry.exif)
+                        .map(|r| {
+                            self.metadata_for(r, &working)
+                                .tags(self.tag_profile(), is_video(&path))
+                        })
+                        .unwrap_or_default(),
+                    sidecar: (sidecar && !entry.exif).then(|| sidecar_path(&working)),
+                    sidecar_destination: destination
+                        .as_deref()
+                        .filter(|_| sidecar)
+                        .map(sidecar_path),
+                    overlay,
+                    converted: conversion.map(|ext| converted_path(&working, ext)),
+                    destination,
+                    already_done: entry.completed_stages(),
+                    staged,
+                    path,
+                }
+            })
+            .collect();
+
+        Ok(Plan { archives, files })
+    }
+
+    /// Reject combinations of options that would lose files
+    fn check_options(&self) -> Result<(), Error> {
+        let options = &self.options;
+        if self.overlay_mode() == OverlayMode::Copy
+            && options.overlay_suffix.is_empty()
+            && options.overlay_output_dir.is_none()
+            && options.rename.is_none()
+        {
+            return Err(Error::EmptyOverlaySuffix);
+        }
+        if options.metadata_mode == MetadataMode::Sidecar
+            && (options.convert_images != ImageConversion::Keep
+                || options.convert_videos != VideoConversion::Keep)
+        {
+            return Err(Error::SidecarConversion);
+        }
+        if cfg!(not(unix)) && options.transfer == Transfer::Hardlink && !options.skip_unzip {
+            return Err(Error::HardlinkWithUnzip);
+        }
+        Ok(())
+    }
+
+    /// Unzip all archives found in the zip directory
+    pub fn unzip(&self) {
+        let zip_dir = &self.options.zip_dir;
+        self.log(format!(
+            "Looking for zip files in: {}",
+            zip_dir.join("*.zip").display()
+        ));
+
+        let archives = match unzip::find_archives(zip_dir) {
+            Ok(archives) => archives,
+            Err(e) => {
+                self.log_err(format!("Glob error: {:?}", e));
+                return;
+            }
+        };
+
+        // Outputs hard linked by an earlier run would be overwritten along
+        // with the media they share an inode with
+        if !archives.is_empty() {
+            for path in self.discover_media() {
+                if let Err(e) = transfer::unshare(&path) {
+                    self.log_err(format!(
+                        "Failed to unlink {:?} from its output: {}",
+                        path, e
+                    ));
+                }
+            }
+        }
+
+        for path in archives {
+            self.log(format!("Unzipping {:?}", path));
+            match unzip::unzip_archive(&path, &self.options.extract_dir) {
+                Ok(()) => self.log(format!("Successfully unzipped {:?}", path)),
+                Err(e) => self.log_err(e),
+            }
+        }
+    }
+
+    /// Parse the memories history file into a map keyed by memory id, with
+    /// locations inferred if [`ProcessOptions::infer_location`] is set and
+    /// then [`ProcessOptions::missing_location`] applied to every location
+    pub fn load_media_map(&self) -> Result<HashMap<String, Media>, Error> {
+        let json_path = &self.options.memories_history_json_path;
+        if !json_path.exists() {
+            return Err(Error::MemoriesHistoryNotFound(json_path.clone()));
+        }
+
+        let memories_data = parse_memories_history_file(json_path)
+            .map_err(|e| Error::MemoriesHistory(json_path.clone(), e))?;
+
+        let mut saved_media = memories_data.saved_media;
+        if let Some(inference) = &self.options.infer_location {
+            let inferred = inference.apply(&mut saved_media);
+            self.log(format!("Inferred locations for {} memories", inferred));
+        }
+
+        Ok(saved_media
+            .into_iter()
+            .map(|mut m| {
+                m.coordinate = self.options.missing_location.apply(m.coordinate);
+                (m.id.clone(), m)
+            })
+            .collect())
+    }
+
+    /// Parse the JSON file(s) for the selected [`ExportSource`] into a map
+    /// keyed by the id that appears in exported filenames
+    pub fn load_records(&self) -> Result<HashMap<String, Record>, Error> {
+        match self.options.source {
+            ExportSource::Memories => Ok(self
+                .load_media_map()?
+                .into_iter()
+                .map(|(id, media)| (id, Record::Memory(media)))
+                .collect()),
+            ExportSource::Chat => {
+                let paths = [
+                    &self.options.chat_history_json_path,
+                    &self.options.snap_history_json_path,
+                ];
+                if !paths.iter().any(|p| p.exists()) {
+                    return Err(Error::ChatHistoryNotFound(
+                        paths[0].clone(),
+                        paths[1].clone(),
+                    ));
+                }
+
+                let mut records = HashMap::new();
+                for path in paths.into_iter().filter(|p| p.exists()) {
+                    let history = parse_chat_history_file(path)
+                        .map_err(|e| Error::ChatHistory(path.clone(), e))?;
+                    records.extend(
+                        chat_media_map(history)
+                            .into_iter()
+                            .map(|(id, chat)| (id, Record::Chat(chat))),
+                    );
+                }
+                Ok(records)
+            }
+        }
+    }
+
+    /// Glob for all exported media files under the extract directory
+    pub fn discover_media(&self) -> Vec<PathBuf> {
+        self.discover_bundles()
+            .into_iter()
+            .map(|bundle| bundle.main)
+            .collect()
+    }
+
+    /// Find the extracted media and group each file with its overlays and
+    /// thumbnails
+    pub fn discover_bundles(&self) -> Vec<MemoryBundle> {
+        let extract_dir = &self.options.extract_dir;
+        let (pattern, default_grouping): (PathBuf, &dyn Grouping) = match self.options.source {
+            ExportSource::Memories => (
+                extract_dir.join(format!("{}*/**/*", self.options.media_prefix)),
+                &MemoriesGrouping,
+            ),
+            ExportSource::Chat => (extract_dir.join("chat_media*/**/*"), &ChatGrouping),
+        };
+        let grouping = self.options.grouping.as_deref().unwrap_or(default_grouping);
+
+        let paths = MEDIA_EXTENSIONS
+            .iter()
+            .flat_map(|ext| {
+                let pattern = format!("{}.{}", pattern.to_string_lossy(), ext);
+                glob(&pattern).expect("Failed to read glob pattern")
+            })
+            .filter_map(Result::ok)
+            // Overlaid copies named with a custom suffix, which the
+            // grouping can't tell from exported files
+            .filter(|path| {
+                let suffix = &self.options.overlay_suffix;
+                suffix.is_empty()
+                    || !path
+                        .file_stem()
+                        .is_some_and(|stem| stem.to_string_lossy().ends_with(suffix.as_str()))
+            });
+        // Without media there is nothing to write metadata onto, only
+        // overlays (WebP named `.png`). In chats they're leftovers from media
+        // that wasn't exported.
+        bundle::group(paths, grouping)
+            .into_iter()
+            .filter(|bundle| !bundle.caption_only)
+            .collect()
+    }
+
+    fn process_files(
+        &self,
+        bundles: &[MemoryBundle],
+        records: &HashMap<String, Record>,
+        writer: &dyn MetadataWriter,
+        journal: Option<&Journal>,
+    ) -> Vec<FileOutcome> {
+        let pb = self.progress_bar(
+            bundles.len(),
+            "Processing {pos}/{len} [{wide_bar:.cyan/blue}] {percent}% ({eta})",
+        );
+
+        let files = bundles
+            .par_iter()
+            .map(|bundle| {
+                let outcome = self.process_file(bundle, records, writer, journal);
+
+                for failure in outcome.failures() {
+                    pb.println(&failure.message);
+                }
+
+                // Log once per file
+                let file_name_str = bundle
+                    .main
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_str()
+                    .unwrap_or("?");
+                match (outcome.exif.is_done(), outcome.overlay.is_done()) {
+                    (true, true) => {
+                        pb.println(format!("Added EXIF data and overlay to {}", file_name_str))
+                    }
+                    (true, false) => pb.println(format!("Added EXIF data to {}", file_name_str)),
+                    (false, true) => pb.println(format!("Added overlay to {}", file_name_str)),
+                    (false, false) => {}
+                }
+                pb.inc(1);
+
+                outcome
+            })
+            .collect();
+
+        pb.finish_with_message("Processing complete");
+        files
+    }
+
+    fn process_file(
+        &self,
+        bundle: &MemoryBundle,
+        records: &HashMap<String, Record>,
+        writer: &dyn MetadataWriter,
+        journal: Option<&Journal>,
+    ) -> FileOutcome {
+        let mut outcome = FileOutcome::new(&bundle.main);
+        let record = records.get(&bundle.id);
+        outcome.media_id = record.map(|r| r.id().to_string());
+        outcome.location = record.and_then(Record::coordinates).cloned();
+        outcome.location_inferred = record.is_some_and(Record::location_inferred);
+
+        let key = self.journal_key(&bundle.main);
+        let staged = self.staged_path(&bundle.main);
+        let mut entry = match journal {
+            Some(journal) => {
+                // A staged copy left by an interrupted run has its progress
+                let current = staged
+                    .as_deref()
+                    .filter(|staged| staged.exists())
+                    .unwrap_or(&bundle.main);
+                let (entry, changed) = journal.resume(&key, current);
+                if changed {
+                    outcome.warnings.push(format!(
+                        "{:?} changed since the last run; processing it again",
+                        current
+                    ));
+                }
+                entry
+            }
+            None => Entry::default(),
+        };
+
+        if entry.is_moved() {
+            outcome.exif = StepOutcome::Resumed;
+            outcome.overlay = StepOutcome::Resumed;
+            outcome.moved = StepOutcome::Resumed;
+            outcome.destination = entry.destination;
+            return outcome;
+        }
+
+        // Copies are processed in the staging directory, leaving the
+        // extracted export as it is
+        let staged_bundle;
+        let bundle = match staged {
+            Some(staged) => {
+                if !staged.exists() {
+                    let copied = create_parent(&staged)
+                        .map_err(|e| format!("Failed to create directory for {:?}: {}", staged, e))
+                        .and_then(|()| Transfer::Reflink.apply(&bundle.main, &staged));
+                    if let Err(e) = copied {
+                        outcome.moved = StepOutcome::Failed(Failure::new(ErrorKind::Move, e));
+                        return outcome;
+                    }
+                }
+                outcome.staged = Some(staged.clone());
+                staged_bundle = MemoryBundle {
+                    main: staged,
+                    ..bundle.clone()
+                };
+                &staged_bundle
+            }
+            None => bundle,
+        };
+        let path = bundle.main.as_path();
+        let save = |entry: &mut Entry, outcome: &mut FileOutcome| {
+            if let Some(journal) = journal {
+                if let Err(e) = journal.update(&key, entry, path) {
+                    outcome.warnings.push(format!(
+                        "Failed to update journal {:?}: {}",
+                        journal.path(),
+                        e
+                    ));
+                }
+            }
+        };
+
+        // 1. Apply EXIF metadata
+        if entry.exif {
+            outcome.exif = StepOutcome::Resumed;
+        } else if let Some(record) = record {
+            let metadata = self.metadata_for(record, path);
+            outcome.exif = writer.write(path, &metadata).into();
+            if outcome.exif.is_done() {
+                entry.exif = true;
+                save(&mut entry, &mut outcome);
+            }
+        }
+        if self.options.metadata_mode == MetadataMode::Sidecar
+            && matches!(outcome.exif, StepOutcome::Done | StepOutcome::Resumed)
+        {
+            let sidecar = sidecar_path(path);
+            if sidecar.exists() {
+                outcome
+                    .artifacts
+                    .push(Artifact::new(ArtifactKind::Sidecar, sidecar));
+            }
+        }
+
+        // 2. Apply overlay (after EXIF so metadata is already set, and restored
+        // onto the composite)
+        if entry.overlay {
+            outcome.overlay = StepOutcome::Resumed;
+            if let Some(copy) = entry.overlaid_copy.clone().filter(|copy| copy.exists()) {
+                outcome.artifacts.extend(self.overlaid_artifacts(copy));
+            }
+        } else if self.overlay_mode() != OverlayMode::Ignore && !bundle.overlays.is_empty() {
+            entry.overlay_pending = true;
+            save(&mut entry, &mut outcome);
+
+            let tagged = matches!(outcome.exif, StepOutcome::Done | StepOutcome::Resumed);
+            outcome.overlay = match apply_overlay(
+                bundle,
+                self.overlay_mode(),
+                &self.options,
+                &mut outcome.warnings,
+            ) {
+                Ok(Some(output)) => {
+                    let restored = match record {
+                        Some(record) if tagged => {
+                            self.restore_metadata(&output, path, record, writer)
+                        }
+                        _ => Ok(()),
+                    };
+                    if let Err(failure) = restored {
+                        if output == path {
+                            entry.exif = false;
+                            outcome.exif = StepOutcome::Failed(failure);
+                        } else {
+                            outcome.warnings.push(failure.message);
+                        }
+                    }
+                    if output != path {
+                        entry.overlaid_copy = Some(output.clone());
+                        outcome.artifacts.extend(self.overlaid_artifacts(output));
+                    }
+                    StepOutcome::Done
+                }
+                Ok(None) => StepOutcome::Skipped,
+                Err(failure) => StepOutcome::Failed(failure),
+            };
+
+            entry.overlay = outcome.overlay.is_done();
+            entry.overlay_pending = false;
+            save(&mut entry, &mut outcome);
+        }
+
+        // 3. Convert to a more compact format
+        let composited = is_video(path)
+            && self.overlay_mode() == OverlayMode::Overwrite
+            && matches!(outcome.overlay, StepOutcome::Done | StepOutcome::Resumed);
+        if entry.converted {
+            // The overlaid copy was converted along with the file, and
+            // resumed with the overlay
+            outcome.convert = StepOutcome::Resumed;
+            outcome.converted = entry.converted_file.clone().filter(|c| c.exists());
+        } else if let Some(ext) = self.conversion_for(path, composited) {
+            outcome.convert = self.convert(path, ext, record, writer, &mut outcome).into();
+            if outcome.convert.is_done() {
+                entry.converted = true;
+                entry.converted_file = outcome.converted.clone();
+                entry.overlaid_copy = outcome
+                    .artifacts
+                    .iter()
+                    .find(|artifact| artifact.kind == ArtifactKind::Overlaid)
+                    .map(|artifact| artifact.path.clone());
+            }
+            save(&mut entry, &mut outcome);
+        }
+
+        outcome
+    }
+
+    /// The overlaid copy at `output` and, in sidecar mode, the sidecar its
+    /// metadata was restored into
+    fn overlaid_artifacts(&self, output: PathBuf) -> Vec<Artifact> {
+        let sidecar = sidecar_path(&output);
+        let mut artifacts = vec![Artifact::new(ArtifactKind::Overlaid, output)];
+        if self.options.metadata_mode == MetadataMode::Sidecar && sidecar.exists() {
+            artifacts.push(Artifact::new(ArtifactKind::Sidecar, sidecar));
+        }
+        artifacts
+    }
+
+    /// The extension `path` is converted to, or `None` when it's kept as is.
+    /// A video `composited` in place was encoded with the target codec
+    /// already, so it keeps its container.
+    fn conversion_for(&self, path: &Path, composited: bool) -> Option<&'static str> {
+        if is_video(path) {
+            let codec = self.options.convert_videos.codec().filter(|_| !composited);
+            codec.map(|_| "mp4")
+        } else {
+            let ext = self.options.convert_images.extension()?;
+            let current = path.extension().unwrap_or_default().to_string_lossy();
+            (!current.eq_ignore_ascii_case(ext)).then_some(ext)
+        }
+    }
+
+    /// Convert `path` and its overlaid copy to the format with extension
+    /// `ext`, writing the metadata from step 1 onto the results
+    fn convert(
+        &self,
+        path: &Path,
+        ext: &str,
+        record: Option<&Record>,
+        writer: &dyn MetadataWriter,
+        outcome: &mut FileOutcome,
+    ) -> Result<(), Failure> {
+        let tagged = matches!(outcome.exif, StepOutcome::Done | StepOutcome::Resumed);
+        let mut conversion = Conversion::default();
+        let mut convert_file = |source: &Path, warnings: &mut Vec<String>| {
+            let output = converted_path(source, ext);
+            let result = if is_video(source) {
+                let mut encoding = self.options.video_encoding.clone();
+                encoding.codec = self.options.convert_videos.codec().unwrap_or_default();
+                convert::video(source, &output, &encoding, &self.options.tools, warnings)
+            } else {
+                convert::image(
+                    source,
+                    &output,
+                    self.options.convert_images,
+                    &self.options.tools,
+                )
+            };
+            let result = result.and_then(|()| match record {
+                Some(record) if tagged => self.restore_metadata(&output, path, record, writer),
+                _ => Ok(()),
+            });
+            if let Err(failure) = result {
+                let _ = fs::remove_file(&output);
+                return Err(failure);
+            }
+            conversion.original_bytes += fs::metadata(source).map_or(0, |m| m.len());
+            conversion.converted_bytes += fs::metadata(&output).map_or(0, |m| m.len());
+            Ok(output)
+        };
+
+        outcome.converted = Some(convert_file(path, &mut outcome.warnings)?);
+        // Copies of videos composited in this or an earlier run were encoded
+        // with the target codec already
+        let composited_video =
+            is_video(path) && matches!(outcome.overlay, StepOutcome::Done | StepOutcome::Resumed);
+        if !composited_video {
+            for artifact in &mut outcome.artifacts {
+                if artifact.kind == ArtifactKind::Overlaid {
+                    let converted = convert_file(&artifact.path, &mut outcome.warnings)?;
+                    // The unconverted copy is ours, so it isn't left behind
+                    let _ = fs::remove_file(&artifact.path);
+                    artifact.path = converted;
+                }
+            }
+        }
+        outcome.conversion = Some(conversion);
+        Ok(())
+    }
+
+    /// `path` with the extension of the file delivered in its place
+    fn delivered_name(&self, path: &Path, composited: bool) -> PathBuf {
+        match self.conversion_for(path, composited) {
+            Some(ext) => path.with_extension(ext),
+            None => path.to_path_buf(),
+        }
+    }
+
+    /// Write the metadata from step 1 again onto the composite or conversion
+    /// of `path` at `output`. Both write a new file, so at least the
+    /// modification time is lost, and ffmpeg and heif-enc drop most tags. In
+    /// sidecar mode the new file gets a sidecar of its own instead.
+    fn restore_metadata(
+        &self,
+        output: &Path,
+        path: &Path,
+        record: &Record,
+        writer: &dyn MetadataWriter,
+    ) -> Result<(), Failure> {
+        writer
+            .write(output, &self.metadata_for(record, path))
+            .map_err(|failure| {
+                Failure::new(
+                    failure.kind,
+                    format!(
+                        "Failed to restore metadata of {:?}: {}",
+                        output, failure.message
+                    ),
+                )
+            })
+    }
+
+    /// Move processed media files to the output directory
+    fn move_files(
+        &self,
+        files: &mut [FileOutcome],
+        records: &HashMap<String, Record>,
+        journal: Option<&Journal>,
+    ) -> usize {
+        let move_pb = self.progress_bar(
+            files.len(),
+            "Moving {pos}/{len} [{wide_bar:.green/dim}] {percent}%",
+        );
+
+        let mut moved = 0usize;
+        let mut taken = HashSet::new();
+        for file in files.iter_mut() {
+            // Already moved, or never staged
+            if matches!(file.moved, StepOutcome::Resumed | StepOutcome::Failed(_)) {
+                move_pb.inc(1);
+                continue;
+            }
+
+            let path = &file.path;
+            let record = file.media_id.as_ref().and_then(|id| records.get(id));
+            // Staged copies are ours to move
+            let (processed, transfer) = match &file.staged {
+                Some(staged) => (staged.as_path(), Transfer::Move),
+                None => (path.as_path(), self.options.transfer),
+            };
+            let source = file.converted.as_deref().unwrap_or(processed);
+            let dest = match self.destination_for(&renamed_like(path, source), record, false) {
+                Some(dest) => unique_path(dest, &mut taken),
+                None => {
+                    move_pb.inc(1);
+                    continue;
+                }
+            };
+
+            match transfer_file(transfer, source, &dest) {
+                Ok(()) => {
+                    moved += 1;
+                    file.moved = StepOutcome::Done;
+                    // The converted file replaces the original
+                    if source != processed && transfer == Transfer::Move {
+                        if let Err(e) = fs::remove_file(processed) {
+                            file.warnings.push(format!(
+                                "Failed to remove {:?} after moving its conversion: {}",
+                                processed, e
+                            ));
+                        }
+                    }
+                    if let Some(journal) = journal {
+                        if let Err(e) = journal.mark_moved(&self.journal_key(path), &dest) {
+                            file.warnings.push(format!(
+                                "Failed to update journal {:?}: {}",
+                                journal.path(),
+                                e
+                            ));
+                        }
+                    }
+                    file.destination = Some(dest);
+                }
+                Err(failure) => {
+                    move_pb.println(&failure.message);
+                    file.moved = StepOutcome::Failed(failure);
+                }
+            }
+
+            // Then everything derived from it, each sidecar after its file
+            for i in 0..file.artifacts.len() {
+                let artifact = &file.artifacts[i];
+                let dest = match artifact.kind {
+                    // Sidecars keep the name of their file, wherever it went
+                    ArtifactKind::Sidecar => {
+                        let owner = std::iter::once((processed, &file.destination))
+                            .chain(
+                                file.artifacts[..i]
+                                    .iter()
+                                    .map(|other| (other.path.as_path(), &other.destination)),
+                            )
+                            .find(|(owner, _)| sidecar_path(owner) == artifact.path);
+                        match owner {
+                            Some((_, Some(dest))) => sidecar_path(dest),
+                            _ => continue,
+                        }
+                    }
+                    ArtifactKind::Overlaid => match self.destination_for(
+                        &renamed_like(path, &artifact.path),
+                        record,
+                        true,
+                    ) {
+                        Some(dest) => unique_path(dest, &mut taken),
+                        None => continue,
+                    },
+                };
+                match transfer_file(transfer, &file.artifacts[i].path, &dest) {
+                    Ok(()) => {
+                        moved += 1;
+                        file.artifacts[i].destination = Some(dest);
+                    }
+                    Err(failure) => {
+                        move_pb.println(&failure.message);
+                        file.moved = StepOutcome::Failed(failure);
+                    }
+                }
+            }
+
+            move_pb.inc(1);
+        }
+
+        move_pb.finish_and_clear();
+        moved
+    }
+
+    /// Transfer `from` to `to` in the output directory as
+    /// [`ProcessOptions::transfer`] says, creating the directory first
+    fn journal_path(&self) -> PathBuf {
+        self.options.output_dir.join(JOURNAL_FILE_NAME)
+    }
+
+    /// Where `path` is copied to and processed with [`Transfer::Copy`], which
+    /// leaves the extracted export as it is. The staging directory is in the
+    /// output directory, so staged files are renamed into place.
+    fn staged_path(&self, path: &Path) -> Option<PathBuf> {
+        if self.options.transfer != Transfer::Copy {
+            return None;
+        }
+        let relative = path
+            .strip_prefix(&self.options.extract_dir)
+            .unwrap_or_else(|_| Path::new(path.file_name().unwrap_or_default()));
+        Some(self.staging_dir().join(relative))
+    }
+
+    fn staging_dir(&self) -> PathBuf {
+        self.options.output_dir.join(STAGING_DIR_NAME)
+    }
+
+    /// Journal entries are keyed by the path relative to the extract directory
+    fn journal_key(&self, path: &Path) -> String {
+        path.strip_prefix(&self.options.extract_dir)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// How overlays are handled under the current options: sidecar mode never
+    /// modifies the media, so overlays are composited onto copies instead
+    fn overlay_mode(&self) -> OverlayMode {
+        match (self.options.metadata_mode, self.options.overlays) {
+            (MetadataMode::Sidecar, OverlayMode::Overwrite) => OverlayMode::Copy,
+            (_, mode) => mode,
+        }
+    }
+
+    /// The tags written under the current options: sidecars only ever hold XMP
+    fn tag_profile(&self) -> TagProfile {
+        match self.options.metadata_mode {
+            MetadataMode::Embedded => self.options.tag_profile,
+            MetadataMode::Sidecar => TagProfile::XmpSidecar,
+        }
+    }
+
+    /// The metadata written for the exported file at `path`, matched to
+    /// `record`, under the current options
+    fn metadata_for(&self, record: &Record, path: &Path) -> Metadata {
+        let mut metadata =
+            Metadata::for_record(record, self.options.tag_partner, &self.options.local_time);
+        metadata.original_filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned());
+        if self.options.mark_inferred_location && record.location_inferred() {
+            metadata.location_method = Some(INFERRED_LOCATION_METHOD.to_string());
+        }
+        metadata
+    }
+
+    /// Where `path` (or its composited copy, if `overlaid`) is moved to in
+    /// the output directory, before collisions are resolved. Without a
+    /// record to fill in the rename template the exported name is kept.
+    fn destination_for(
+        &self,
+        path: &Path,
+        record: Option<&Record>,
+        overlaid: bool,
+    ) -> Option<PathBuf> {
+        let date = record.map(|r| self.options.local_time.convert(r.date(), r.coordinates()));
+        let name = match (&self.options.rename, record, date) {
+            (Some(template), Some(record), Some(date)) => template
+                .render(&NameFields {
+                    date,
+                    id: record.id(),
+                    media_type: media_type_of(path, Some(record)),
+                    overlay_suffix: if overlaid {
+                        &self.options.overlay_suffix
+                    } else {
+                        ""
+                    },
+                    stem: path.file_stem()?.to_str()?,
+                    ext: path.extension().and_then(|ext| ext.to_str()).unwrap_or(""),
+                })
+                .into(),
+            _ if overlaid => with_stem_suffix(path, &self.options.overlay_suffix)
+                .file_name()?
+                .to_os_string(),
+            _ => path.file_name()?.to_os_string(),
+        };
+
+        let dir = match (self.options.layout, date) {
+            (Layout::Flat, _) => PathBuf::new(),
+            (Layout::YearMonth, Some(date)) => date.format("%Y/%m").to_string().into(),
+            (Layout::YearMonthDay, Some(date)) => date.format("%Y/%m/%d").to_string().into(),
+            (Layout::YearMonth | Layout::YearMonthDay, None) => UNKNOWN_DATE_DIR.into(),
+            (Layout::ByMediaType, _) => format!("{}s", media_type_of(path, record)).into(),
+        };
+        let output_dir = match &self.options.overlay_output_dir {
+            Some(overlay_output_dir) if overlaid => overlay_output_dir,
+            _ => &self.options.output_dir,
+        };
+        Some(output_dir.join(dir).join(name))
+    }
+
+    fn progress_bar(&self, len: usize, template: &str) -> ProgressBar {
+        if !self.options.show_progress {
+            return ProgressBar::hidden();
+        }
+        let pb = ProgressBar::new(len as u64);
+        pb.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("=> "),
+        );
+        pb
+    }
+
+    fn log(&self, message: String) {
+        if self.options.show_progress {
+            println!("{}", message);
+        }
+    }
+
+    fn log_err(&self, message: String) {
+        if self.options.show_progress {
+            eprintln!("{}", message);
+        }
+    }
+}
+
+/// `image` or `video`, from the record if it says, otherwise the extension
+fn media_type_of(path: &Path, record: Option<&Record>) -> &'static str {
+    match record {
+        Some(Record::Memory(media)) => match media.media_type {
+            MediaType::Image => "image",
+            MediaType::Video => "video",
+        },
+        _ if is_video(path) => "video",
+        _ => "image",
+    }
+}
+
+/// Create the directory `path` is about to be moved into
+fn create_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) => fs::create_dir_all(parent),
+        None => Ok(()),
+    }
+}
+
+/// `path` with the extension of `delivered`, the file moved in its place
+fn transfer_file(transfer: Transfer, from: &Path, to: &Path) -> Result<(), Failure> {
+    create_parent(to)
+        .map_err(|e| format!("Failed to create directory for {:?}: {}", to, e))
+        .and_then(|()| transfer.apply(from, to))
+        .map_err(|e| Failure::new(ErrorKind::Move, e))
+}
+
+fn renamed_like(path: &Path, delivered: &Path) -> PathBuf {
+    match delivered.extension() {
+        Some(ext) => path.with_extension(ext),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Where the composited version of `path` is written for the given overlay mode
+fn overlay_output_for(path: &Path, overlay_mode: OverlayMode, overlay_suffix: &str) -> PathBuf {
+    match overlay_mode {
+        OverlayMode::Copy => overlaid_path(path, overlay_suffix),
+        OverlayMode::Overwrite | OverlayMode::Ignore => path.to_path_buf(),
+    }
+}
+
+/// Composite the overlays of `bundle` onto its media as `mode` says,
+/// returning the file the result was written to, or `None` when there is no
+/// overlay to apply
+fn apply_overlay(
+    bundle: &MemoryBundle,
+    mode: OverlayMode,
+    options: &ProcessOptions,
+    warnings: &mut Vec<String>,
+) -> Result<Option<PathBuf>, Failure> {
+    if bundle.overlays.is_empty() {
+        return Ok(None);
+    }
+    let path = bundle.main.as_path();
+    let output = overlay_output_for(path, mode, &options.overlay_suffix);
+
+    if is_video(path) {
+        // Go straight to the codec videos are converted to, rather than
+        // encoding them twice
+        let mut encoding = options.video_encoding.clone();
+        if let Some(codec) = options.convert_videos.codec() {
+            encoding.codec = codec;
+        }
+        composite::video(
+            path,
+            &bundle.overlays,
+            &output,
+            &encoding,
+            &options.tools,
+            warnings,
+        )?;
+    } else {
+        composite::photo(path, &bundle.overlays, &output, options.image_quality)?;
+    }
+    Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use super::*;
+
+    /// A memory taken late on 2021-07-03 UTC, already the 4th in Berlin
+    fn memory(media_type: MediaType) -> Record {
+        Record::Memory(Media {
+            date: Utc.with_ymd_and_hms(2021, 7, 3, 23, 30, 0).unwrap(),
+            media_type,
+            coordinate: None,
+            location_inferred: false,
+            id: "B1A2C3D4-E5F6-4789-ABCD-0123456789AB".to_string(),
+        })
+    }
+
+    #[test]
+    fn layouts_sort_files_into_subdirectories() {
+        let image = memory(MediaType::Image);
+        let video = memory(MediaType::Video);
+        for (layout, name, record, expected) in [
+            (Layout::Flat, "a.jpg", Some(&image), "out/a.jpg"),
+            (Layout::Flat, "a.jpg", None, "out/a.jpg"),
+            (
+                Layout::YearMonth,
+                "a.jpg",
+                Some(&image),
+                "out/2021/07/a.jpg",
+            ),
+            (Layout::YearMonth, "a.jpg", None, "out/unknown/a.jpg"),
+            (
+                Layout::YearMonthDay,
+                "a.jpg",
+                Some(&image),
+                "out/2021/07/04/a.jpg",
+            ),
+            (Layout::YearMonthDay, "a.jpg", None, "out/unknown/a.jpg"),
+            (
+                Layout::ByMediaType,
+                "a.jpg",
+                Some(&image),
+                "out/images/a.jpg",
+            ),
+            // The record wins over the extension
+            (
+                Layout::ByMediaType,
+                "a.jpg",
+                Some(&video),
+                "out/videos/a.jpg",
+            ),
+            (Layout::ByMediaType, "a.mov", None, "out/videos/a.mov"),
+            (Layout::ByMediaType, "a.png", None, "out/images/a.png"),
+        ] {
+            let pipeline = ProcessOptions::new()
+                .output_dir("out")
+                .timezone(Tz::Europe__Berlin)
+                .layout(layout)
+                .build();
+            assert_eq!(
+                pipeline.destination_for(Path::new(name), record, false),
+                Some(PathBuf::from(expected)),
+                "{:?} {:?}",
+                layout,
+                record.is_some()
+            );
+        }
+    }
+
+    #[test]
+    fn overlaid_copies_are_laid_out_in_their_own_directory() {
+        let image = memory(MediaType::Image);
+        let pipeline = ProcessOptions::new()
+            .output_dir("out")
+            .overlay_output_dir(Some("overlaid".into()))
+            .layout(Layout::YearMonth)
+            .build();
+        assert_eq!(
+            pipeline.destination_for(Path::new("a.jpg"), Some(&image), true),
+            Some(PathBuf::from("overlaid/2021/07/a_with_overlay.jpg"))
+        );
+        assert_eq!(
+            pipeline.destination_for(Path::new("a.jpg"), None, true),
+            Some(PathBuf::from("overlaid/unknown/a_with_overlay.jpg"))
+        );
+    }
+
+    #[test]
+    fn resumed_files_keep_the_artifacts_their_journal_recorded() {
+        let dir = tempfile::tempdir().unwrap();
+        let main = dir.path().join("2021-07-03_ID-main.jpg");
+        let overlay = dir.path().join("2021-07-03_ID-overlay.png");
+        // Named under options that have changed since
+        let copy = dir.path().join("2021-07-03_ID-main_edited.webp");
+        let converted = dir.path().join("2021-07-03_ID-main.avif");
+        for path in [&main, &overlay, &copy, &converted] {
+            fs::write(path, path.to_string_lossy().as_bytes()).unwrap();
+        }
+
+        let pipeline = ProcessOptions::new()
+            .extract_dir(dir.path())
+            .overlays(OverlayMode::Copy)
+            .convert_images(ImageConversion::Webp)
+            .build();
+        let journal = Journal::open(&dir.path().join(JOURNAL_FILE_NAME)).unwrap();
+        let key = pipeline.journal_key(&main);
+        let (mut entry, _) = journal.resume(&key, &main);
+        entry.overlay = true;
+        entry.converted = true;
+        entry.overlaid_copy = Some(copy.clone());
+        entry.converted_file = Some(converted.clone());
+        journal.update(&key, &mut entry, &main).unwrap();
+
+        let bundle = MemoryBundle {
+            id: "ID".to_string(),
+            main: main.clone(),
+            overlays: vec![overlay],
+            thumbnails: Vec::new(),
+            caption_only: false,
+        };
+        let outcome = pipeline.process_file(&bundle, &HashMap::new(), &Sidecar, Some(&journal));
+        assert_eq!(outcome.overlay, StepOutcome::Resumed);
+        assert_eq!(outcome.convert, StepOutcome::Resumed);
+        assert_eq!(outcome.converted, Some(converted));
+        assert_eq!(
+            outcome.artifacts,
+            [Artifact::new(ArtifactKind::Overlaid, copy)]
+        );
+    }
+}
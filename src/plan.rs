@@ -0,0 +1,124 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::metadata::Tag;
+
+/// An overlay that would be composited onto a file
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedOverlay {
+    /// The exported overlay layers, bottom first
+    pub sources: Vec<PathBuf>,
+    /// The file the composited result would be written to
+    pub output: PathBuf,
+    /// The file the composited copy would be converted to
+    pub converted: Option<PathBuf>,
+    /// Where the composited copy would be moved to; `None` when the
+    /// overlays are composited onto the file itself
+    pub destination: Option<PathBuf>,
+    /// Where the composited copy's own sidecar would be moved to, next to it
+    pub sidecar_destination: Option<PathBuf>,
+}
+
+/// Everything that would happen to a single exported media file
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedFile {
+    pub path: PathBuf,
+    /// The copy that would be processed in place of `path`, leaving it as
+    /// it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staged: Option<PathBuf>,
+    /// Id of the `memories_history.json` entry this file matches
+    pub media_id: Option<String>,
+    /// Tags that would be written; empty when the file has no metadata match
+    pub tags: Vec<Tag>,
+    /// XMP sidecar the tags would be written to instead of the file itself
+    pub sidecar: Option<PathBuf>,
+    /// Where the sidecar would be moved to, next to the file
+    pub sidecar_destination: Option<PathBuf>,
+    pub overlay: Option<PlannedOverlay>,
+    /// The file the media would be converted to, moved in its place
+    pub converted: Option<PathBuf>,
+    pub destination: Option<PathBuf>,
+    /// Stages the journal shows were completed in an earlier run
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub already_done: Vec<&'static str>,
+}
+
+/// What a [`Pipeline`](crate::Pipeline) run would do, computed without
+/// touching any files
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Plan {
+    /// Archives that would be extracted before processing
+    pub archives: Vec<PathBuf>,
+    pub files: Vec<PlannedFile>,
+}
+
+impl Plan {
+    /// Write the plan as pretty-printed JSON
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for archive in &self.archives {
+            writeln!(f, "unzip {}", archive.display())?;
+        }
+        for file in &self.files {
+            writeln!(f, "{}", file.path.display())?;
+            if !file.already_done.is_empty() {
+                writeln!(f, "  already done: {}", file.already_done.join(", "))?;
+            }
+            if let Some(staged) = &file.staged {
+                writeln!(f, "  copy -> {}", staged.display())?;
+            }
+            match &file.media_id {
+                Some(id) => writeln!(f, "  metadata ({})", id)?,
+                None => writeln!(f, "  metadata: no match")?,
+            }
+            for tag in &file.tags {
+                writeln!(f, "    -{}", tag)?;
+            }
+            if let Some(sidecar) = &file.sidecar {
+                writeln!(f, "    -> {}", sidecar.display())?;
+            }
+            if let Some(overlay) = &file.overlay {
+                let sources: Vec<_> = overlay
+                    .sources
+                    .iter()
+                    .map(|source| source.display().to_string())
+                    .collect();
+                writeln!(
+                    f,
+                    "  overlay {} -> {}",
+                    sources.join(" + "),
+                    overlay.output.display()
+                )?;
+                if let Some(converted) = &overlay.converted {
+                    writeln!(f, "    convert -> {}", converted.display())?;
+                }
+                if let Some(destination) = &overlay.destination {
+                    writeln!(f, "    move -> {}", destination.display())?;
+                }
+                if let Some(destination) = &overlay.sidecar_destination {
+                    writeln!(f, "    move sidecar -> {}", destination.display())?;
+                }
+            }
+            if let Some(converted) = &file.converted {
+                writeln!(f, "  convert -> {}", converted.display())?;
+            }
+            if let Some(destination) = &file.destination {
+                writeln!(f, "  move -> {}", destination.display())?;
+            }
+            if let Some(destination) = &file.sidecar_destination {
+                writeln!(f, "  move sidecar -> {}", destination.display())?;
+            }
+        }
+        write!(f, "{} files would be processed", self.files.len())
+    }
+}
@@ -0,0 +1,46 @@
+use chrono::{DateTime, Utc};
+
+use crate::chat::ChatMedia;
+use crate::memories::{Coordinates, Media};
+
+/// An entry from the export's JSON files that media files are matched against
+#[derive(Debug, Clone)]
+pub enum Record {
+    /// A saved memory from `memories_history.json`
+    Memory(Media),
+    /// A chat attachment from `chat_history.json` or `snap_history.json`
+    Chat(ChatMedia),
+}
+
+impl Record {
+    pub fn id(&self) -> &str {
+        match self {
+            Record::Memory(media) => &media.id,
+            Record::Chat(chat) => &chat.id,
+        }
+    }
+
+    /// When the media was captured (memories) or sent (chats)
+    pub fn date(&self) -> DateTime<Utc> {
+        match self {
+            Record::Memory(media) => media.date,
+            Record::Chat(chat) => chat.date,
+        }
+    }
+
+    /// Where the media was captured, if known (chats never have a location)
+    pub fn coordinates(&self) -> Option<&Coordinates> {
+        match self {
+            Record::Memory(media) => media.coordinate.as_ref(),
+            Record::Chat(_) => None,
+        }
+    }
+
+    /// Whether [`Record::coordinates`] were inferred rather than exported
+    pub fn location_inferred(&self) -> bool {
+        match self {
+            Record::Memory(media) => media.location_inferred,
+            Record::Chat(_) => false,
+        }
+    }
+}
@@ -0,0 +1,251 @@
+//! Templates for naming files in the output directory.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset};
+
+/// Date format used by a bare `{date}` placeholder
+const DEFAULT_DATE_FORMAT: &str = "%Y%m%d_%H%M%S";
+
+/// Number of characters of the id used by `{id_short}`
+const SHORT_ID_LEN: usize = 8;
+
+/// The placeholders a [`RenameTemplate`] can contain
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    /// `{date}` or `{date:FORMAT}` with a strftime format
+    Date(String),
+    /// `{id}`
+    Id,
+    /// `{id_short}`
+    IdShort,
+    /// `{media_type}`: `image` or `video`
+    MediaType,
+    /// `{overlay_suffix}`: empty, or the overlay suffix for composited copies
+    OverlaySuffix,
+    /// `{stem}`: the exported file name without its extension
+    Stem,
+    /// `{ext}`: the exported file's extension, lowercased
+    Ext,
+}
+
+/// A file name template such as `{date:%Y%m%d_%H%M%S}_{id_short}{overlay_suffix}.{ext}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenameTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+/// The values a [`RenameTemplate`] is rendered with
+#[derive(Debug, Clone)]
+pub struct NameFields<'a> {
+    /// Local capture time
+    pub date: DateTime<FixedOffset>,
+    pub id: &'a str,
+    pub media_type: &'a str,
+    pub overlay_suffix: &'a str,
+    pub stem: &'a str,
+    pub ext: &'a str,
+}
+
+impl RenameTemplate {
+    /// The file name for `fields`. Path separators produced by the date
+    /// format are replaced so the result is always a single file name.
+    pub fn render(&self, fields: &NameFields) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(s) => name.push_str(s),
+                Part::Date(format) => name.push_str(&fields.date.format(format).to_string()),
+                Part::Id => name.push_str(fields.id),
+                Part::IdShort => name.extend(fields.id.chars().take(SHORT_ID_LEN)),
+                Part::MediaType => name.push_str(fields.media_type),
+                Part::OverlaySuffix => name.push_str(fields.overlay_suffix),
+                Part::Stem => name.push_str(fields.stem),
+                Part::Ext => name.push_str(&fields.ext.to_ascii_lowercase()),
+            }
+        }
+        name.replace(['/', '\\'], "-")
+    }
+}
+
+impl FromStr for RenameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .map(|end| start + end)
+                .ok_or_else(|| format!("unclosed '{{' in rename template {:?}", s))?;
+            parts.push(parse_placeholder(&rest[start + 1..end])?);
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        if parts.iter().all(|p| matches!(p, Part::Literal(_))) {
+            return Err(format!(
+                "rename template {:?} has no placeholders, so every file would get the same name",
+                s
+            ));
+        }
+        Ok(Self {
+            template: s.to_string(),
+            parts,
+        })
+    }
+}
+
+fn parse_placeholder(placeholder: &str) -> Result<Part, String> {
+    let (name, format) = match placeholder.split_once(':') {
+        Some((name, format)) => (name, Some(format)),
+        None => (placeholder, None),
+    };
+    let part = match name {
+        "date" => {
+            let format = format.unwrap_or(DEFAULT_DATE_FORMAT);
+            if StrftimeItems::new(format).any(|item| item == Item::Error) {
+                return Err(format!("invalid date format {:?}", format));
+            }
+            return Ok(Part::Date(format.to_string()));
+        }
+        "id" => Part::Id,
+        "id_short" => Part::IdShort,
+        "media_type" => Part::MediaType,
+        "overlay_suffix" => Part::OverlaySuffix,
+        "stem" => Part::Stem,
+        "ext" => Part::Ext,
+        _ => {
+            return Err(format!(
+                "unknown placeholder {{{}}} (expected date, id, id_short, media_type, \
+                 overlay_suffix, stem or ext)",
+                name
+            ))
+        }
+    };
+    match format {
+        Some(_) => Err(format!("{{{}}} does not take a format", name)),
+        None => Ok(part),
+    }
+}
+
+impl fmt::Display for RenameTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+/// `path`, or `path` with `_1`, `_2`, ... added before the extension if it
+/// already exists or was handed out earlier in this run. The result is added
+/// to `taken`.
+pub fn unique_path(path: PathBuf, taken: &mut HashSet<PathBuf>) -> PathBuf {
+    let is_free = |candidate: &Path, taken: &HashSet<PathBuf>| {
+        !taken.contains(candidate) && !candidate.exists()
+    };
+
+    let mut candidate = path.clone();
+    let mut n = 1;
+    while !is_free(&candidate, taken) {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        let name = match path.extension() {
+            Some(ext) => format!("{}_{}.{}", stem, n, ext.to_string_lossy()),
+            None => format!("{}_{}", stem, n),
+        };
+        candidate = path.with_file_name(name);
+        n += 1;
+    }
+    taken.insert(candidate.clone());
+    candidate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(date: &str) -> NameFields<'static> {
+        NameFields {
+            date: DateTime::parse_from_rfc3339(date).unwrap(),
+            id: "B1A2C3D4-E5F6-4789-ABCD-0123456789AB",
+            media_type: "image",
+            overlay_suffix: "_with_overlay",
+            stem: "2021-07-03_B1A2C3D4-E5F6-4789-ABCD-0123456789AB-main",
+            ext: "JPG",
+        }
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        for (template, error) in [
+            ("{date", "unclosed '{'"),
+            ("photo.jpg", "has no placeholders"),
+            ("{name}.{ext}", "unknown placeholder {name}"),
+            ("{id:short}", "{id} does not take a format"),
+            ("{date:%Q}", "invalid date format"),
+        ] {
+            let err = template.parse::<RenameTemplate>().unwrap_err();
+            assert!(err.contains(error), "{:?}: {}", template, err);
+        }
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let template: RenameTemplate = "{date}_{id_short}{overlay_suffix}.{ext}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields("2021-07-03T14:34:56+02:00")),
+            "20210703_143456_B1A2C3D4_with_overlay.jpg"
+        );
+        let template: RenameTemplate = "{media_type}-{stem}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields("2021-07-03T14:34:56+02:00")),
+            "image-2021-07-03_B1A2C3D4-E5F6-4789-ABCD-0123456789AB-main"
+        );
+        assert_eq!(template.to_string(), "{media_type}-{stem}");
+    }
+
+    #[test]
+    fn path_separators_are_replaced() {
+        let template: RenameTemplate = "{date:%Y/%m/%d}\\{id_short}.{ext}".parse().unwrap();
+        assert_eq!(
+            template.render(&fields("2021-07-03T14:34:56+02:00")),
+            "2021-07-03-B1A2C3D4.jpg"
+        );
+    }
+
+    #[test]
+    fn unique_paths_skip_existing_and_taken_names() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("photo.jpg"), b"").unwrap();
+        let mut taken = HashSet::new();
+
+        let path = dir.path().join("photo.jpg");
+        assert_eq!(
+            unique_path(path.clone(), &mut taken),
+            dir.path().join("photo_1.jpg")
+        );
+        assert_eq!(
+            unique_path(path.clone(), &mut taken),
+            dir.path().join("photo_2.jpg")
+        );
+        let free = dir.path().join("video.mp4");
+        assert_eq!(unique_path(free.clone(), &mut taken), free);
+        assert_eq!(
+            unique_path(free, &mut taken),
+            dir.path().join("video_1.mp4")
+        );
+        let bare = dir.path().join("notes");
+        taken.insert(bare.clone());
+        assert_eq!(unique_path(bare, &mut taken), dir.path().join("notes_1"));
+    }
+}
@@ -0,0 +1,300 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::artifact::Artifact;
+use crate::memories::Coordinates;
+
+/// Which part of the pipeline a failure came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// exiftool could not be run or exited unsuccessfully
+    Exiftool,
+    /// The native metadata writer could not update the file
+    Metadata,
+    /// Preparing the overlay failed (copying the original, finalizing the output)
+    Overlay,
+    /// ffmpeg could not be run or exited unsuccessfully
+    Ffmpeg,
+    /// The file could not be converted to another format
+    Convert,
+    /// The file could not be moved into the output directory
+    Move,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Failure {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+/// Result of a single pipeline step for one file
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum StepOutcome {
+    /// The step ran and succeeded
+    Done,
+    /// The step did not apply to this file (no metadata match, no overlay, ...)
+    #[default]
+    Skipped,
+    /// The step already completed in an earlier, interrupted run
+    Resumed,
+    Failed(Failure),
+}
+
+impl StepOutcome {
+    pub fn is_done(&self) -> bool {
+        matches!(self, StepOutcome::Done)
+    }
+
+    pub fn failure(&self) -> Option<&Failure> {
+        match self {
+            StepOutcome::Failed(failure) => Some(failure),
+            _ => None,
+        }
+    }
+}
+
+impl From<Result<(), Failure>> for StepOutcome {
+    fn from(result: Result<(), Failure>) -> Self {
+        match result {
+            Ok(()) => StepOutcome::Done,
+            Err(failure) => StepOutcome::Failed(failure),
+        }
+    }
+}
+
+/// Sizes of a file, and its overlaid copy, before and after conversion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Conversion {
+    pub original_bytes: u64,
+    pub converted_bytes: u64,
+}
+
+/// Everything that happened to a single exported media file
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct FileOutcome {
+    pub path: PathBuf,
+    /// The copy processed in place of `path` with
+    /// [`Transfer::Copy`](crate::Transfer::Copy), leaving `path` as it is
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub staged: Option<PathBuf>,
+    /// Id of the `memories_history.json` entry this file was matched to
+    pub media_id: Option<String>,
+    /// GPS position written for this file, after the missing-location policy
+    pub location: Option<Coordinates>,
+    /// Whether `location` was inferred from neighbouring memories
+    pub location_inferred: bool,
+    pub exif: StepOutcome,
+    pub overlay: StepOutcome,
+    pub convert: StepOutcome,
+    /// The converted file moved in place of `path`
+    pub converted: Option<PathBuf>,
+    /// Sizes before and after the convert step
+    pub conversion: Option<Conversion>,
+    #[serde(rename = "move")]
+    pub moved: StepOutcome,
+    /// Where the file ended up after the move step
+    pub destination: Option<PathBuf>,
+    /// Other files produced while processing this one, such as overlaid
+    /// copies and sidecars
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub artifacts: Vec<Artifact>,
+    /// Non-fatal problems, e.g. an overlay that had to be used without conversion
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<String>,
+}
+
+impl FileOutcome {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn failures(&self) -> impl Iterator<Item = &Failure> {
+        [&self.exif, &self.overlay, &self.convert, &self.moved]
+            .into_iter()
+            .filter_map(StepOutcome::failure)
+    }
+
+    fn is_resumed(&self) -> bool {
+        [&self.exif, &self.overlay, &self.convert, &self.moved]
+            .into_iter()
+            .any(|step| *step == StepOutcome::Resumed)
+    }
+
+    fn has_failure(&self, kind: ErrorKind) -> bool {
+        self.failures().any(|f| f.kind == kind)
+    }
+}
+
+/// Aggregate view over a run, listing the files that need attention
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Summary {
+    pub total: usize,
+    pub exif_written: usize,
+    pub overlays_applied: usize,
+    pub converted: usize,
+    /// Size of the converted files before conversion
+    pub original_bytes: u64,
+    /// Size of the converted files after conversion
+    pub converted_bytes: u64,
+    pub moved: usize,
+    /// Files with at least one stage carried over from an earlier run
+    pub resumed: usize,
+    /// Matched files that got no GPS position
+    pub without_location: usize,
+    /// Files whose GPS position was inferred from neighbouring memories
+    pub locations_inferred: usize,
+    pub unmatched: Vec<PathBuf>,
+    pub exiftool_failures: Vec<PathBuf>,
+    pub metadata_failures: Vec<PathBuf>,
+    pub overlay_failures: Vec<PathBuf>,
+    pub ffmpeg_failures: Vec<PathBuf>,
+    pub convert_failures: Vec<PathBuf>,
+    pub move_failures: Vec<PathBuf>,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Processed {} files", self.total)?;
+        writeln!(f, "  metadata written: {}", self.exif_written)?;
+        writeln!(f, "  overlays applied: {}", self.overlays_applied)?;
+        if self.converted > 0 {
+            writeln!(
+                f,
+                "  converted:        {} ({} -> {}, {})",
+                self.converted,
+                format_size(self.original_bytes),
+                format_size(self.converted_bytes),
+                match self.original_bytes.checked_sub(self.converted_bytes) {
+                    Some(saved) => format!("saved {}", format_size(saved)),
+                    None => format!(
+                        "grew by {}",
+                        format_size(self.converted_bytes - self.original_bytes)
+                    ),
+                }
+            )?;
+        }
+        write!(f, "  moved:            {}", self.moved)?;
+        if self.resumed > 0 {
+            write!(f, "\n  resumed:          {}", self.resumed)?;
+        }
+        if self.without_location > 0 {
+            write!(f, "\n  without location: {}", self.without_location)?;
+        }
+        if self.locations_inferred > 0 {
+            write!(f, "\n  inferred location: {}", self.locations_inferred)?;
+        }
+
+        let sections = [
+            ("No metadata match", &self.unmatched),
+            ("ExifTool failures", &self.exiftool_failures),
+            ("Metadata failures", &self.metadata_failures),
+            ("Overlay failures", &self.overlay_failures),
+            ("FFmpeg failures", &self.ffmpeg_failures),
+            ("Conversion failures", &self.convert_failures),
+            ("Move failures", &self.move_failures),
+        ];
+        for (title, paths) in sections {
+            if paths.is_empty() {
+                continue;
+            }
+            write!(f, "\n{} ({}):", title, paths.len())?;
+            for path in paths {
+                write!(f, "\n  {}", path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `bytes` in MB, or kB for small sizes
+fn format_size(bytes: u64) -> String {
+    if bytes < 1_000_000 {
+        format!("{:.1} kB", bytes as f64 / 1e3)
+    } else {
+        format!("{:.1} MB", bytes as f64 / 1e6)
+    }
+}
+
+/// Per-file outcomes of a pipeline run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Report {
+    pub files: Vec<FileOutcome>,
+    /// Total number of files moved, including artifacts such as overlaid copies
+    pub moved: usize,
+}
+
+impl Report {
+    pub fn summary(&self) -> Summary {
+        let paths_where = |pred: &dyn Fn(&FileOutcome) -> bool| -> Vec<PathBuf> {
+            self.files
+                .iter()
+                .filter(|f| pred(f))
+                .map(|f| f.path.clone())
+                .collect()
+        };
+        let conversions = || self.files.iter().filter_map(|f| f.conversion);
+
+        Summary {
+            total: self.files.len(),
+            exif_written: self.files.iter().filter(|f| f.exif.is_done()).count(),
+            overlays_applied: self.files.iter().filter(|f| f.overlay.is_done()).count(),
+            converted: self.files.iter().filter(|f| f.convert.is_done()).count(),
+            original_bytes: conversions().map(|c| c.original_bytes).sum(),
+            converted_bytes: conversions().map(|c| c.converted_bytes).sum(),
+            moved: self.moved,
+            resumed: self.files.iter().filter(|f| f.is_resumed()).count(),
+            without_location: self
+                .files
+                .iter()
+                .filter(|f| f.media_id.is_some() && f.location.is_none())
+                .count(),
+            locations_inferred: self.files.iter().filter(|f| f.location_inferred).count(),
+            unmatched: paths_where(&|f| f.media_id.is_none()),
+            exiftool_failures: paths_where(&|f| f.has_failure(ErrorKind::Exiftool)),
+            metadata_failures: paths_where(&|f| f.has_failure(ErrorKind::Metadata)),
+            overlay_failures: paths_where(&|f| f.has_failure(ErrorKind::Overlay)),
+            ffmpeg_failures: paths_where(&|f| f.has_failure(ErrorKind::Ffmpeg)),
+            convert_failures: paths_where(&|f| f.has_failure(ErrorKind::Convert)),
+            move_failures: paths_where(&|f| f.has_failure(ErrorKind::Move)),
+        }
+    }
+
+    /// Write the report, including its summary, as pretty-printed JSON
+    pub fn write_json(&self, path: &Path) -> io::Result<()> {
+        #[derive(Serialize)]
+        struct JsonReport<'a> {
+            summary: Summary,
+            files: &'a [FileOutcome],
+        }
+
+        let json = serde_json::to_vec_pretty(&JsonReport {
+            summary: self.summary(),
+            files: &self.files,
+        })?;
+        fs::write(path, json)
+    }
+}
@@ -0,0 +1,247 @@
+//! Offline lookup of the time zone at a capture location.
+//!
+//! There are no zone boundaries here: each location is given the zone of the
+//! nearest reference point from tzdata's `zone.tab` (one per zone and
+//! country) plus extra cities in large zones and along borders through
+//! sparsely populated areas (`extra.tab`). Reference points are sparse, so
+//! the nearest one is often across a border, even from large cities (Lviv is
+//! closer to Warsaw than to Kyiv). That makes the lookup an approximation,
+//! and [`LocalTime`] only uses it when asked to. Locations far from every
+//! reference point (out at sea) get the nautical zone for their longitude
+//! instead.
+
+use std::sync::OnceLock;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use chrono_tz::Tz;
+
+use crate::memories::Coordinates;
+
+const ZONE_TAB: &str = include_str!("zone.tab");
+const EXTRA_TAB: &str = include_str!("extra.tab");
+
+/// Beyond this distance from every reference point a location is considered
+/// to be at sea
+const MAX_LAND_DISTANCE_KM: f64 = 1000.0;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// How capture times are converted to local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Whether to look up the zone at the capture location at all
+    pub lookup: bool,
+    /// Zone for records without a usable location (or all records when
+    /// `lookup` is off)
+    pub fallback: Tz,
+}
+
+impl Default for LocalTime {
+    fn default() -> Self {
+        Self {
+            lookup: false,
+            fallback: Tz::UTC,
+        }
+    }
+}
+
+impl LocalTime {
+    /// The zone to use for a record at `coordinates`
+    pub fn zone_for(&self, coordinates: Option<&Coordinates>) -> Tz {
+        coordinates
+            .filter(|_| self.lookup)
+            .and_then(zone_at)
+            .unwrap_or(self.fallback)
+    }
+
+    /// `date` as the local time (with offset) at `coordinates`
+    pub fn convert(
+        &self,
+        date: DateTime<Utc>,
+        coordinates: Option<&Coordinates>,
+    ) -> DateTime<FixedOffset> {
+        date.with_timezone(&self.zone_for(coordinates))
+            .fixed_offset()
+    }
+}
+
+/// A reference point from one of the tables
+struct Reference {
+    lat: f64,
+    lon: f64,
+    zone: Tz,
+}
+
+fn references() -> &'static [Reference] {
+    static REFERENCES: OnceLock<Vec<Reference>> = OnceLock::new();
+    REFERENCES.get_or_init(|| {
+        [ZONE_TAB, EXTRA_TAB]
+            .iter()
+            .flat_map(|table| table.lines())
+            .filter(|line| !line.starts_with('#'))
+            .filter_map(|line| {
+                let mut columns = line.split('\t');
+                let coordinates = columns.nth(1)?;
+                let zone = columns.next()?.parse().ok()?;
+                let (lat, lon) = parse_iso6709(coordinates)?;
+                Some(Reference { lat, lon, zone })
+            })
+            .collect()
+    })
+}
+
+/// The zone at `coordinates`, or `None` for the 0,0 placeholder Snapchat
+/// exports for memories without a location
+pub fn zone_at(coordinates: &Coordinates) -> Option<Tz> {
+    if coordinates.is_null_island() {
+        return None;
+    }
+
+    let (distance, nearest) = references()
+        .iter()
+        .map(|r| {
+            let distance = distance_km(coordinates.lat, coordinates.lon, r.lat, r.lon);
+            (distance, r)
+        })
+        .min_by(|a, b| a.0.total_cmp(&b.0))?;
+
+    if distance <= MAX_LAND_DISTANCE_KM {
+        Some(nearest.zone)
+    } else {
+        nautical_zone(coordinates.lon)
+    }
+}
+
+/// The `Etc/GMT±N` zone for a longitude. Note the inverted POSIX sign:
+/// `Etc/GMT-10` is ten hours *ahead* of UTC.
+fn nautical_zone(lon: f64) -> Option<Tz> {
+    let hours = (lon / 15.0).round() as i32;
+    let name = match hours {
+        0 => "Etc/GMT".to_string(),
+        h if h > 0 => format!("Etc/GMT-{}", h),
+        h => format!("Etc/GMT+{}", -h),
+    };
+    name.parse().ok()
+}
+
+/// Great-circle distance between two points
+pub fn distance_km(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (lat1, lat2) = (lat1.to_radians(), lat2.to_radians());
+    let dlat = lat2 - lat1;
+    let dlon = (lon2 - lon1).to_radians();
+    let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Parse zone.tab's `±DDMM±DDDMM` / `±DDMMSS±DDDMMSS` coordinates
+fn parse_iso6709(s: &str) -> Option<(f64, f64)> {
+    let split = s[1..].find(['+', '-'])? + 1;
+    let (lat, lon) = s.split_at(split);
+    Some((parse_dms(lat, 2)?, parse_dms(lon, 3)?))
+}
+
+fn parse_dms(s: &str, degree_digits: usize) -> Option<f64> {
+    let sign = if s.starts_with('-') { -1.0 } else { 1.0 };
+    let digits = s.get(1..)?;
+    let part = |range: std::ops::Range<usize>| -> Option<f64> {
+        match digits.get(range) {
+            Some("") | None => Some(0.0),
+            Some(d) => d.parse().ok(),
+        }
+    };
+    let degrees = part(0..degree_digits)?;
+    let minutes = part(degree_digits..degree_digits + 2)?;
+    let seconds = part(degree_digits + 2..degree_digits + 4)?;
+    Some(sign * (degrees + minutes / 60.0 + seconds / 3600.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(lat: f64, lon: f64) -> Option<Tz> {
+        zone_at(&Coordinates { lat, lon })
+    }
+
+    #[test]
+    fn the_fallback_zone_is_used_unless_the_lookup_is_on() {
+        let berlin = Coordinates {
+            lat: 52.52,
+            lon: 13.405,
+        };
+        let local_time = LocalTime {
+            fallback: Tz::America__New_York,
+            ..Default::default()
+        };
+        assert_eq!(local_time.zone_for(Some(&berlin)), Tz::America__New_York);
+
+        let lookup = LocalTime {
+            lookup: true,
+            ..local_time
+        };
+        assert_eq!(lookup.zone_for(Some(&berlin)), Tz::Europe__Berlin);
+        assert_eq!(lookup.zone_for(None), Tz::America__New_York);
+    }
+
+    #[test]
+    fn the_nearest_reference_zone_is_often_the_neighbours() {
+        // The second largest city of each of the ten most populous countries,
+        // then cities a few hundred kilometres or less from a border
+        let cities = [
+            ("Delhi", 28.6139, 77.2090, Tz::Asia__Kolkata),
+            ("Beijing", 39.9042, 116.4074, Tz::Asia__Shanghai),
+            ("Los Angeles", 34.0522, -118.2437, Tz::America__Los_Angeles),
+            ("Surabaya", -7.2575, 112.7521, Tz::Asia__Jakarta),
+            ("Lahore", 31.5204, 74.3587, Tz::Asia__Karachi),
+            ("Kano", 12.0022, 8.5920, Tz::Africa__Lagos),
+            ("Rio de Janeiro", -22.9068, -43.1729, Tz::America__Sao_Paulo),
+            ("Chittagong", 22.3569, 91.7832, Tz::Asia__Dhaka),
+            ("Saint Petersburg", 59.9311, 30.3609, Tz::Europe__Moscow),
+            ("Guadalajara", 20.6597, -103.3496, Tz::America__Mexico_City),
+            ("Lviv", 49.8397, 24.0297, Tz::Europe__Kyiv),
+            ("Brest, Belarus", 52.0976, 23.7341, Tz::Europe__Minsk),
+            ("Narva", 59.3772, 28.1903, Tz::Europe__Tallinn),
+            ("Spokane", 47.6588, -117.4260, Tz::America__Los_Angeles),
+            ("Evansville", 37.9716, -87.5711, Tz::America__Chicago),
+        ];
+
+        let wrong: Vec<_> = cities
+            .iter()
+            .filter(|(_, lat, lon, expected)| zone(*lat, *lon) != Some(*expected))
+            .map(|(city, ..)| *city)
+            .collect();
+        // Why the lookup is opt-in. A lookup against real zone boundaries
+        // would get all of them right.
+        assert_eq!(
+            wrong,
+            [
+                "Lahore",
+                "Kano",
+                "Guadalajara",
+                "Lviv",
+                "Brest, Belarus",
+                "Narva",
+                "Spokane",
+                "Evansville"
+            ]
+        );
+    }
+
+    #[test]
+    fn the_open_sea_gets_a_nautical_zone() {
+        assert_eq!(zone(35.0, -40.0), Some(Tz::Etc__GMTPlus3));
+        assert_eq!(zone(-30.0, 80.0), Some(Tz::Etc__GMTMinus5));
+        assert_eq!(zone(0.0, 0.0), None);
+    }
+
+    #[test]
+    fn iso6709_coordinates_are_parsed() {
+        let (lat, lon) = parse_iso6709("+404251-0740023").unwrap();
+        assert!((lat - 40.714_166).abs() < 1e-5 && (lon + 74.006_388).abs() < 1e-5);
+        assert_eq!(
+            parse_iso6709("-3352+15113"),
+            Some((-33.0 - 52.0 / 60.0, 151.0 + 13.0 / 60.0))
+        );
+        assert_eq!(parse_iso6709("+4045"), None);
+    }
+}
@@ -0,0 +1,25 @@
+//! The external programs snapback runs besides exiftool.
+
+use std::path::PathBuf;
+
+/// Where to find the external programs. Each defaults to its bare name, so
+/// it's looked up on `PATH`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tools {
+    /// Composites overlays onto videos and converts videos and WebP photos
+    pub ffmpeg: PathBuf,
+    /// Probes the bitrate of videos re-encoded at the source's bitrate
+    pub ffprobe: PathBuf,
+    /// Converts photos to AVIF and HEIC
+    pub heif_enc: PathBuf,
+}
+
+impl Default for Tools {
+    fn default() -> Self {
+        Self {
+            ffmpeg: "ffmpeg".into(),
+            ffprobe: "ffprobe".into(),
+            heif_enc: "heif-enc".into(),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+//! Getting processed files into the output directory.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+
+/// How files are transferred into the output directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum)]
+pub enum Transfer {
+    /// Move the files, copying and deleting them when the output directory
+    /// is on another file system
+    #[default]
+    Move,
+    /// Copy the files, leaving the extracted export intact: the pipeline
+    /// processes copies staged in the output directory
+    Copy,
+    /// Hard link the files (copies them when the output directory is on
+    /// another file system)
+    Hardlink,
+    /// Clone the files on file systems with copy-on-write support, such as
+    /// Btrfs, XFS and APFS (copies them elsewhere)
+    Reflink,
+}
+
+impl Transfer {
+    /// Transfer `from` to `to`. Every real copy is verified against a
+    /// checksum of the source before it's relied on.
+    pub fn apply(self, from: &Path, to: &Path) -> Result<(), String> {
+        let result = match self {
+            Transfer::Move => match fs::rename(from, to) {
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+                    copy_verified(from, to).and_then(|()| fs::remove_file(from))
+                }
+                result => result,
+            },
+            Transfer::Copy => copy_verified(from, to),
+            Transfer::Hardlink => match fs::hard_link(from, to) {
+                Err(e) if e.kind() == io::ErrorKind::CrossesDevices => copy_verified(from, to),
+                result => result,
+            },
+            Transfer::Reflink => match reflink_copy::reflink(from, to) {
+                // The file system can't clone, or not across the two paths
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::Unsupported | io::ErrorKind::CrossesDevices
+                    ) =>
+                {
+                    copy_verified(from, to)
+                }
+                result => result,
+            },
+        };
+        result.map_err(|e| format!("Failed to {} {:?} to {:?}: {}", self.verb(), from, to, e))
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            Transfer::Move => "move",
+            Transfer::Copy => "copy",
+            Transfer::Hardlink => "hard link",
+            Transfer::Reflink => "reflink",
+        }
+    }
+}
+
+/// Give `path` an inode of its own if it's hard linked elsewhere, so writing
+/// to it in place, as extracting an archive over it does, can't change the
+/// other links. Only Unix exposes link counts; elsewhere this does nothing.
+pub fn unshare(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        if fs::metadata(path)?.nlink() > 1 {
+            return copy_verified(path, path);
+        }
+    }
+    Ok(())
+}
+
+/// Copy `from` to `to` through a temporary file, keeping the modification
+/// time, and only put it in place once its SHA-256 matches the source's
+fn copy_verified(from: &Path, to: &Path) -> io::Result<()> {
+    let file_name = to.file_name().unwrap_or_default().to_string_lossy();
+    let temp = to.with_file_name(format!(".{}.snapback-tmp", file_name));
+
+    let result = copy_hashed(from, &temp).and_then(|source_hash| {
+        if sha256(&temp)? != source_hash {
+            return Err(io::Error::other("checksum of the copy doesn't match"));
+        }
+        fs::rename(&temp, to)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+/// Copy `from` to `to`, returning the SHA-256 of what was read
+fn copy_hashed(from: &Path, to: &Path) -> io::Result<Vec<u8>> {
+    let source = File::open(from)?;
+    let modified = source.metadata()?.modified()?;
+    let mut reader = BufReader::new(source);
+    let mut writer = BufWriter::new(File::create(to)?);
+    let mut hasher = Sha256::new();
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        writer.write_all(&buf[..n])?;
+    }
+
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    file.set_modified(modified)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+fn sha256(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTENTS: &[u8] = b"exported media";
+
+    /// A temporary directory with a file `from` in it
+    fn source() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("from"), CONTENTS).unwrap();
+        dir
+    }
+
+    #[test]
+    fn move_leaves_nothing_behind() {
+        let dir = source();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        Transfer::Move.apply(&from, &to).unwrap();
+        assert!(!from.exists());
+        assert_eq!(fs::read(&to).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn copy_keeps_the_source_and_its_modification_time() {
+        let dir = source();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        let modified = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1 << 30);
+        File::options()
+            .write(true)
+            .open(&from)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+
+        Transfer::Copy.apply(&from, &to).unwrap();
+        assert_eq!(fs::read(&from).unwrap(), CONTENTS);
+        assert_eq!(fs::read(&to).unwrap(), CONTENTS);
+        assert_eq!(fs::metadata(&to).unwrap().modified().unwrap(), modified);
+        // No temporary file is left next to the copy
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hardlink_shares_the_inode_until_unshared() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = source();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        Transfer::Hardlink.apply(&from, &to).unwrap();
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(&from), inode(&to));
+
+        unshare(&from).unwrap();
+        assert_ne!(inode(&from), inode(&to));
+        assert_eq!(fs::read(&from).unwrap(), CONTENTS);
+        assert_eq!(fs::metadata(&to).unwrap().nlink(), 1);
+    }
+
+    #[test]
+    fn reflink_clones_or_copies() {
+        let dir = source();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        Transfer::Reflink.apply(&from, &to).unwrap();
+        assert_eq!(fs::read(&from).unwrap(), CONTENTS);
+        assert_eq!(fs::read(&to).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn reflink_errors_are_not_hidden_by_a_copy() {
+        let dir = source();
+        let (from, to) = (dir.path().join("from"), dir.path().join("to"));
+        fs::write(&to, b"already there").unwrap();
+
+        let err = Transfer::Reflink.apply(&from, &to).unwrap_err();
+        assert!(err.starts_with("Failed to reflink"), "{}", err);
+        assert_eq!(fs::read(&to).unwrap(), b"already there");
+    }
+}
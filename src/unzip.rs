@@ -0,0 +1,31 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glob::glob;
+use ripunzip::{NullProgressReporter, UnzipEngine, UnzipOptions};
+
+/// Find all `.zip` archives directly inside `zip_dir`
+pub fn find_archives(zip_dir: &Path) -> Result<Vec<PathBuf>, glob::PatternError> {
+    let zip_pattern = zip_dir.join("*.zip");
+    let zip_pattern_str = zip_pattern.to_string_lossy();
+
+    Ok(glob(&zip_pattern_str)?.filter_map(Result::ok).collect())
+}
+
+/// Extract a single archive into `output_dir` (using ripunzip for parallel extraction)
+pub fn unzip_archive(path: &Path, output_dir: &Path) -> Result<(), String> {
+    let zip_file =
+        fs::File::open(path).map_err(|e| format!("Failed to open zip {:?}: {}", path, e))?;
+    let engine = UnzipEngine::for_file(zip_file)
+        .map_err(|e| format!("Failed to open zip {:?}: {}", path, e))?;
+    let options = UnzipOptions {
+        output_directory: Some(output_dir.to_path_buf()),
+        password: None,
+        single_threaded: false,
+        filename_filter: None,
+        progress_reporter: Box::new(NullProgressReporter),
+    };
+    engine
+        .unzip(options)
+        .map_err(|e| format!("Unzip failed for {:?}: {}", path, e))
+}
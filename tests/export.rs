@@ -0,0 +1,967 @@
+//! End-to-end runs of the pipeline over the fixture export in
+//! `tests/fixtures/export`.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use snapback::bundle::{group, ChatGrouping, Grouping, MemoriesGrouping, Role};
+use snapback::convert::{ImageConversion, VideoConversion};
+use snapback::metadata::MetadataMode;
+use snapback::Transfer;
+use snapback::{
+    ArtifactKind, ExportSource, FileOutcome, Layout, OverlayMode, ProcessOptions, Report,
+    StepOutcome, Tools,
+};
+use tempfile::TempDir;
+use zip::write::SimpleFileOptions;
+use zip::ZipWriter;
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/export");
+
+const WITH_OVERLAY: &str = "2021-07-03_B1A2C3D4-E5F6-4789-ABCD-0123456789AB-main.jpg";
+const WITHOUT_OVERLAY: &str = "2021-07-04_F1E2D3C4-B5A6-4987-9876-FEDCBA987654-main.jpg";
+const OVERLAY: &str = "2021-07-03_B1A2C3D4-E5F6-4789-ABCD-0123456789AB-overlay.png";
+const OVERLAID: &str = "2021-07-03_B1A2C3D4-E5F6-4789-ABCD-0123456789AB-main_with_overlay.jpg";
+const ID: &str = "B1A2C3D4-E5F6-4789-ABCD-0123456789AB";
+
+/// When the memory with an overlay was taken, 2021-07-03 12:34:56 UTC
+const CAPTURED: u64 = 1_625_315_696;
+
+/// A copy of the fixture export to process
+fn export() -> TempDir {
+    let dir = tempfile::tempdir().unwrap();
+    copy_dir(Path::new(FIXTURE), dir.path());
+    dir
+}
+
+fn copy_dir(from: &Path, to: &Path) {
+    fs::create_dir_all(to).unwrap();
+    for entry in fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let dest = to.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            copy_dir(&entry.path(), &dest);
+        } else {
+            fs::copy(entry.path(), dest).unwrap();
+        }
+    }
+}
+
+/// The fixture export zipped up the way Snapchat delivers it, alone in a
+/// directory that is both the zip and the extract directory
+fn zipped_export() -> TempDir {
+    fn add_dir(zip: &mut ZipWriter<fs::File>, dir: &Path, prefix: &str) {
+        for entry in fs::read_dir(dir).unwrap() {
+            let entry = entry.unwrap();
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type().unwrap().is_dir() {
+                add_dir(zip, &entry.path(), &format!("{}/", name));
+            } else {
+                zip.start_file(name, SimpleFileOptions::default()).unwrap();
+                zip.write_all(&fs::read(entry.path()).unwrap()).unwrap();
+            }
+        }
+    }
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut zip = ZipWriter::new(fs::File::create(dir.path().join("mydata~1.zip")).unwrap());
+    add_dir(&mut zip, Path::new(FIXTURE), "");
+    zip.finish().unwrap();
+    dir
+}
+
+/// An MP4 with nothing but a movie header, dated 1904 like ffmpeg output
+/// without a `creation_time`
+fn bare_mp4() -> Vec<u8> {
+    fn atom(kind: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+        let size = (payload.len() as u32 + 8).to_be_bytes();
+        [&size[..], kind, payload].concat()
+    }
+
+    let mut mvhd = vec![0; 100];
+    mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes()); // timescale
+    mvhd[20..24].copy_from_slice(&0x0001_0000u32.to_be_bytes()); // rate
+    mvhd[24..26].copy_from_slice(&0x0100u16.to_be_bytes()); // volume
+    [
+        atom(b"ftyp", b"isom\0\0\x02\0isomiso2mp41"),
+        atom(b"moov", &atom(b"mvhd", &mvhd)),
+        atom(b"mdat", &[]),
+    ]
+    .concat()
+}
+
+/// Stand-ins for the external tools: ffmpeg "composites" and converts by
+/// writing a [`bare_mp4`] to the output, dropping every tag like a real
+/// re-encode, and heif-enc "converts" by copying its input
+#[cfg(unix)]
+fn fake_tools() -> Tools {
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::OnceLock;
+
+    static DIR: OnceLock<TempDir> = OnceLock::new();
+    let dir = DIR.get_or_init(|| {
+        let dir = tempfile::tempdir().unwrap();
+        let bare = dir.path().join("bare.mp4");
+        fs::write(&bare, bare_mp4()).unwrap();
+        let script = dir.path().join("ffmpeg");
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\nfor arg; do output=$arg; done\ncp {:?} \"$output\"\n",
+                bare
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        let script = dir.path().join("heif-enc");
+        fs::write(
+            &script,
+            "#!/bin/sh\nwhile [ \"$1\" != -o ]; do input=$1; shift; done\ncp \"$input\" \"$2\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        dir
+    });
+    Tools {
+        ffmpeg: dir.path().join("ffmpeg"),
+        heif_enc: dir.path().join("heif-enc"),
+        ..Default::default()
+    }
+}
+
+fn modified(path: &Path) -> SystemTime {
+    fs::metadata(path).unwrap().modified().unwrap()
+}
+
+fn contains(data: &[u8], needle: &[u8]) -> bool {
+    data.windows(needle.len()).any(|w| w == needle)
+}
+
+fn options(export: &Path) -> ProcessOptions {
+    ProcessOptions::new()
+        .extract_dir(export)
+        .output_dir(export.join("processed_media"))
+        .memories_history_json_path(export.join("json/memories_history.json"))
+        .skip_unzip(true)
+}
+
+/// Names of the files in `dir`, ignoring the journal
+fn file_names(dir: &Path) -> BTreeSet<String> {
+    fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .filter(|name| !name.starts_with('.'))
+        .collect()
+}
+
+fn names(names: &[&str]) -> BTreeSet<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+fn outcome<'a>(report: &'a Report, name: &str) -> &'a FileOutcome {
+    report
+        .files
+        .iter()
+        .find(|f| f.path.file_name().unwrap() == name)
+        .unwrap()
+}
+
+#[test]
+fn copy_mode_moves_overlaid_copies() {
+    let export = export();
+    let output = export.path().join("processed_media");
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .build()
+        .run()
+        .unwrap();
+
+    assert_eq!(
+        file_names(&output),
+        names(&[WITH_OVERLAY, WITHOUT_OVERLAY, OVERLAID])
+    );
+    // Nothing but the overlay itself is left behind
+    assert_eq!(
+        file_names(&export.path().join("memories")),
+        names(&[OVERLAY])
+    );
+    assert_eq!(report.moved, 3);
+
+    let file = outcome(&report, WITH_OVERLAY);
+    assert!(file.overlay.is_done());
+    assert_eq!(file.artifacts.len(), 1);
+    assert_eq!(file.artifacts[0].kind, ArtifactKind::Overlaid);
+    assert_eq!(file.artifacts[0].destination, Some(output.join(OVERLAID)));
+    assert!(outcome(&report, WITHOUT_OVERLAY).artifacts.is_empty());
+}
+
+/// Every file under `dir` with its contents, by path relative to `dir`
+fn tree(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let entry = entry.unwrap();
+        if entry.file_type().unwrap().is_dir() {
+            files.extend(
+                tree(&entry.path())
+                    .into_iter()
+                    .map(|(path, data)| (Path::new(&entry.file_name()).join(path), data)),
+            );
+        } else {
+            files.push((entry.file_name().into(), fs::read(entry.path()).unwrap()));
+        }
+    }
+    files.sort();
+    files
+}
+
+#[test]
+fn copy_transfers_leave_the_extracted_export_intact() {
+    let export = export();
+    let output = export.path().join("processed_media");
+    let pipeline = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .transfer(Transfer::Copy)
+        .build();
+
+    let plan = pipeline.plan().unwrap();
+    let planned = plan
+        .files
+        .iter()
+        .find(|f| f.path.file_name().unwrap() == WITH_OVERLAY)
+        .unwrap();
+    let staged = output.join(".snapback-staging/memories").join(WITH_OVERLAY);
+    assert_eq!(planned.staged, Some(staged.clone()));
+    assert_eq!(
+        planned.overlay.as_ref().unwrap().output,
+        staged.with_file_name(OVERLAID)
+    );
+
+    let report = pipeline.run().unwrap();
+    assert_eq!(
+        file_names(&output),
+        names(&[WITH_OVERLAY, WITHOUT_OVERLAY, OVERLAID])
+    );
+    assert_eq!(report.moved, 3);
+    let file = outcome(&report, WITH_OVERLAY);
+    assert!(file.exif.is_done() && file.overlay.is_done());
+    assert_eq!(file.staged, Some(staged));
+    assert!(contains(
+        &fs::read(output.join(WITH_OVERLAY)).unwrap(),
+        ID.as_bytes()
+    ));
+    assert!(!output.join(".snapback-staging").exists());
+
+    // Nothing was written to, added to or removed from the export
+    fs::remove_dir_all(&output).unwrap();
+    assert!(tree(export.path()) == tree(Path::new(FIXTURE)));
+}
+
+#[test]
+fn sidecar_mode_moves_sidecars_and_leaves_media_untouched() {
+    let export = export();
+    let output = export.path().join("processed_media");
+
+    let pipeline = options(export.path())
+        .metadata_mode(MetadataMode::Sidecar)
+        .transfer(Transfer::Copy)
+        .build();
+    let plan = pipeline.plan().unwrap();
+    let report = pipeline.run().unwrap();
+
+    // The overlay goes onto a copy, as if `--overlays copy` had been passed,
+    // which gets a sidecar of its own
+    assert_eq!(
+        file_names(&output),
+        names(&[
+            WITH_OVERLAY,
+            &format!("{}.xmp", WITH_OVERLAY),
+            OVERLAID,
+            &format!("{}.xmp", OVERLAID),
+            WITHOUT_OVERLAY,
+            &format!("{}.xmp", WITHOUT_OVERLAY),
+        ])
+    );
+    assert_eq!(report.moved, 6);
+
+    for name in [WITH_OVERLAY, WITHOUT_OVERLAY] {
+        let original = fs::read(PathBuf::from(FIXTURE).join("memories").join(name)).unwrap();
+        assert!(fs::read(output.join(name)).unwrap() == original);
+        assert!(fs::read(export.path().join("memories").join(name)).unwrap() == original);
+
+        let file = outcome(&report, name);
+        let sidecar = file
+            .artifacts
+            .iter()
+            .find(|a| a.kind == ArtifactKind::Sidecar)
+            .unwrap();
+        assert_eq!(
+            sidecar.destination,
+            Some(output.join(format!("{}.xmp", name)))
+        );
+        let planned = plan.files.iter().find(|f| f.path == file.path).unwrap();
+        assert_eq!(planned.sidecar_destination, sidecar.destination);
+    }
+
+    for name in [WITH_OVERLAY, OVERLAID] {
+        let sidecar = fs::read_to_string(output.join(format!("{}.xmp", name))).unwrap();
+        assert!(sidecar.contains("dc:identifier=\"B1A2C3D4-E5F6-4789-ABCD-0123456789AB\""));
+        assert!(sidecar.contains("2021-07-03T12:34:56"), "{}", sidecar);
+    }
+    let kinds: Vec<_> = outcome(&report, WITH_OVERLAY)
+        .artifacts
+        .iter()
+        .map(|a| (a.kind, a.destination.clone()))
+        .collect();
+    let overlaid_sidecar = Some(output.join(format!("{}.xmp", OVERLAID)));
+    assert_eq!(
+        kinds,
+        [
+            (
+                ArtifactKind::Sidecar,
+                Some(output.join(format!("{}.xmp", WITH_OVERLAY)))
+            ),
+            (ArtifactKind::Overlaid, Some(output.join(OVERLAID))),
+            (ArtifactKind::Sidecar, overlaid_sidecar.clone()),
+        ]
+    );
+    let planned = plan
+        .files
+        .iter()
+        .find(|f| f.path.file_name().unwrap() == WITH_OVERLAY)
+        .unwrap();
+    assert_eq!(
+        planned.overlay.as_ref().unwrap().sidecar_destination,
+        overlaid_sidecar
+    );
+
+    // Conversions would replace the media
+    assert!(options(export.path())
+        .metadata_mode(MetadataMode::Sidecar)
+        .convert_videos(VideoConversion::Hevc)
+        .build()
+        .run()
+        .is_err());
+}
+
+#[test]
+fn overlaid_copies_go_to_overlay_output_dir() {
+    let export = export();
+    let output = export.path().join("processed_media");
+    let overlay_output = output.join("with_overlays");
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .overlay_suffix("")
+        .overlay_output_dir(Some(overlay_output.clone()))
+        .build()
+        .run()
+        .unwrap();
+
+    assert_eq!(
+        file_names(&output),
+        names(&[WITH_OVERLAY, WITHOUT_OVERLAY, "with_overlays"])
+    );
+    assert_eq!(file_names(&overlay_output), names(&[WITH_OVERLAY]));
+    assert_eq!(
+        outcome(&report, WITH_OVERLAY).artifacts[0].destination,
+        Some(overlay_output.join(WITH_OVERLAY))
+    );
+}
+
+#[test]
+fn layouts_sort_outputs_into_subdirectories() {
+    // No record to take a date from, and a video by its extension
+    let unmatched = "2021-07-05_00000000-0000-4000-8000-000000000000-main.mp4";
+    for (layout, dirs) in [
+        (
+            Layout::YearMonthDay,
+            ["2021/07/03", "2021/07/04", "unknown"],
+        ),
+        (Layout::ByMediaType, ["images", "images", "videos"]),
+    ] {
+        let export = export();
+        let output = export.path().join("processed_media");
+        fs::write(export.path().join("memories").join(unmatched), bare_mp4()).unwrap();
+
+        let report = options(export.path())
+            .overlays(OverlayMode::Copy)
+            .overlay_output_dir(Some(output.join("overlaid")))
+            .layout(layout)
+            .build()
+            .run()
+            .unwrap();
+        assert_eq!(report.moved, 4, "{:?}", layout);
+
+        for (dir, name) in dirs.iter().zip([WITH_OVERLAY, WITHOUT_OVERLAY, unmatched]) {
+            assert_eq!(
+                outcome(&report, name).destination,
+                Some(output.join(dir).join(name)),
+                "{:?}",
+                layout
+            );
+            assert!(output.join(dir).join(name).exists());
+        }
+        // Overlaid copies are laid out the same way in their own directory
+        assert_eq!(
+            outcome(&report, WITH_OVERLAY).artifacts[0].destination,
+            Some(output.join("overlaid").join(dirs[0]).join(OVERLAID))
+        );
+    }
+}
+
+#[test]
+fn existing_outputs_are_not_overwritten() {
+    let export = export();
+    let output = export.path().join("processed_media");
+    fs::create_dir(&output).unwrap();
+    fs::write(output.join(WITHOUT_OVERLAY), b"kept").unwrap();
+    let renamed = WITHOUT_OVERLAY.replace(".jpg", "_1.jpg");
+
+    let report = options(export.path()).build().run().unwrap();
+    assert_eq!(
+        outcome(&report, WITHOUT_OVERLAY).destination,
+        Some(output.join(&renamed))
+    );
+    assert_eq!(fs::read(output.join(WITHOUT_OVERLAY)).unwrap(), b"kept");
+    assert_eq!(
+        file_names(&output),
+        names(&[WITH_OVERLAY, WITHOUT_OVERLAY, &renamed])
+    );
+}
+
+#[test]
+fn dry_run_plans_the_destinations_the_run_uses() {
+    let export = export();
+    let output = export.path().join("processed_media");
+    // Every file is named after the year, so all three collide
+    let pipeline = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .overlay_suffix("")
+        .rename(Some("{date:%Y}.{ext}".parse().unwrap()))
+        .build();
+
+    let plan = pipeline.plan().unwrap();
+    let planned: BTreeSet<_> = plan
+        .files
+        .iter()
+        .flat_map(|file| {
+            let overlaid = file.overlay.as_ref().and_then(|o| o.destination.clone());
+            file.destination.clone().into_iter().chain(overlaid)
+        })
+        .collect();
+    assert_eq!(
+        planned,
+        ["2021.jpg", "2021_1.jpg", "2021_2.jpg"]
+            .map(|name| output.join(name))
+            .into()
+    );
+
+    let report = pipeline.run().unwrap();
+    let moved: BTreeSet<_> = report
+        .files
+        .iter()
+        .flat_map(|file| {
+            let artifacts = file.artifacts.iter().map(|a| a.destination.clone());
+            std::iter::once(file.destination.clone()).chain(artifacts)
+        })
+        .flatten()
+        .collect();
+    assert_eq!(moved, planned);
+}
+
+#[test]
+fn overlays_are_composited_into_photos_keeping_metadata() {
+    let export = export();
+    let output = export.path().join("processed_media");
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Overwrite)
+        .build()
+        .run()
+        .unwrap();
+    assert!(outcome(&report, WITH_OVERLAY).overlay.is_done());
+
+    let data = fs::read(output.join(WITH_OVERLAY)).unwrap();
+    let photo = image::load_from_memory(&data).unwrap().into_rgb8();
+    assert_eq!(photo.dimensions(), (16, 16));
+    // The overlay's left half is opaque red, its right half transparent
+    let left = photo.get_pixel(2, 8);
+    assert!(left[0] > 200 && left[1] < 60 && left[2] < 60, "{:?}", left);
+    let original = image::open(PathBuf::from(FIXTURE).join("memories").join(WITH_OVERLAY))
+        .unwrap()
+        .into_rgb8();
+    let right = photo.get_pixel(13, 8);
+    let expected = original.get_pixel(13, 8);
+    assert!(
+        (0..3).all(|c| right[c].abs_diff(expected[c]) < 24),
+        "{:?} vs {:?}",
+        right,
+        expected
+    );
+}
+
+#[test]
+fn overlay_layers_and_naming_variants_are_grouped() {
+    let export = export();
+    let memories = export.path().join("memories");
+    let output = export.path().join("processed_media");
+    let jpeg = WITHOUT_OVERLAY.replace(".jpg", ".jpeg");
+    fs::rename(memories.join(WITHOUT_OVERLAY), memories.join(&jpeg)).unwrap();
+    let thumbnail = WITH_OVERLAY.replace("-main", "-thumbnail");
+    fs::copy(memories.join(WITH_OVERLAY), memories.join(&thumbnail)).unwrap();
+    // A second layer over the first: opaque blue on the bottom half
+    let layer = image::RgbaImage::from_fn(16, 16, |_, y| {
+        image::Rgba(if y >= 8 { [0, 0, 255, 255] } else { [0; 4] })
+    });
+    layer
+        .save(memories.join(OVERLAY.replace("-overlay", "-overlay2")))
+        .unwrap();
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Overwrite)
+        .build()
+        .run()
+        .unwrap();
+    assert_eq!(report.files.len(), 2);
+    assert!(outcome(&report, &jpeg).exif.is_done());
+    assert!(outcome(&report, WITH_OVERLAY).overlay.is_done());
+    assert_eq!(file_names(&output), names(&[WITH_OVERLAY, &jpeg]));
+    assert!(memories.join(&thumbnail).exists());
+
+    let photo = image::open(output.join(WITH_OVERLAY)).unwrap().into_rgb8();
+    let red = photo.get_pixel(2, 4);
+    assert!(red[0] > 200 && red[1] < 60 && red[2] < 60, "{:?}", red);
+    for (x, y) in [(2, 12), (13, 12)] {
+        let blue = photo.get_pixel(x, y);
+        assert!(blue[0] < 60 && blue[1] < 60 && blue[2] > 200, "{:?}", blue);
+    }
+}
+
+#[test]
+fn memories_artifacts_and_unnamed_files_are_not_grouped() {
+    let dir = Path::new("memories");
+    let video = WITH_OVERLAY.replace(".jpg", ".mp4");
+    let paths = [
+        WITH_OVERLAY.to_string(),
+        OVERLAY.to_string(),
+        OVERLAY.replace("-overlay", "-overlay2"),
+        WITH_OVERLAY.replace("-main", "-thumbnail"),
+        OVERLAID.to_string(),
+        video.clone(),
+        video.replace("-main", "-main_temp"),
+        video.replace("-main.mp4", "-main_converted.mov"),
+        OVERLAY.replace("-overlay", "-overlay_with_overlay"),
+        "IMG_0001.jpg".to_string(),
+        "frame_00001.png".to_string(),
+    ]
+    .map(|name| dir.join(name));
+
+    let bundles = group(paths, &MemoriesGrouping);
+    let mains: Vec<_> = bundles.iter().map(|b| b.main.clone()).collect();
+    assert_eq!(mains, [dir.join(WITH_OVERLAY), dir.join(&video)]);
+    for bundle in &bundles {
+        assert_eq!(bundle.id, ID);
+        assert_eq!(
+            bundle.overlays,
+            [
+                dir.join(OVERLAY),
+                dir.join(OVERLAY.replace("-overlay", "-overlay2"))
+            ]
+        );
+        assert_eq!(
+            bundle.thumbnails,
+            [dir.join(WITH_OVERLAY.replace("-main", "-thumbnail"))]
+        );
+    }
+
+    // Nor are they picked up when they're found in the export
+    let export = export();
+    let memories = export.path().join("memories");
+    let frames = memories.join(WITH_OVERLAY.replace("-main.jpg", "-main_overlay1_frames"));
+    fs::create_dir(&frames).unwrap();
+    fs::copy(memories.join(OVERLAY), frames.join("frame_00001.png")).unwrap();
+    fs::copy(memories.join(OVERLAY), memories.join("IMG_0001.png")).unwrap();
+    let report = options(export.path()).build().run().unwrap();
+    assert_eq!(report.files.len(), 2);
+}
+
+#[test]
+fn overlay_layers_are_stacked_by_number() {
+    let dir = Path::new("memories");
+    let layers = ["-overlay", "-overlay2", "-overlay3", "-overlay10"]
+        .map(|suffix| dir.join(OVERLAY.replace("-overlay", suffix)));
+    let mut paths = layers.to_vec();
+    paths.reverse();
+    paths.push(dir.join(WITH_OVERLAY));
+
+    let bundles = group(paths, &MemoriesGrouping);
+    assert_eq!(bundles.len(), 1);
+    assert_eq!(bundles[0].overlays, layers);
+}
+
+#[test]
+fn caption_only_memories_are_left_alone() {
+    let export = export();
+    let memories = export.path().join("memories");
+    fs::remove_file(memories.join(WITH_OVERLAY)).unwrap();
+
+    let report = options(export.path()).build().run().unwrap();
+    assert_eq!(report.files.len(), 1);
+    assert_eq!(
+        file_names(&export.path().join("processed_media")),
+        names(&[WITHOUT_OVERLAY])
+    );
+    assert_eq!(file_names(&memories), names(&[OVERLAY]));
+}
+
+/// Files named after the bare memory id, with `.overlay` before the
+/// extension for overlays
+#[derive(Debug)]
+struct BareIdGrouping;
+
+impl Grouping for BareIdGrouping {
+    fn classify(&self, path: &Path) -> Option<(String, Role)> {
+        let stem = path.file_stem()?.to_str()?;
+        match stem.strip_suffix(".overlay") {
+            Some(id) => Some((id.to_string(), Role::Overlay)),
+            None => Some((stem.to_string(), Role::Main)),
+        }
+    }
+}
+
+#[test]
+fn files_are_grouped_by_a_custom_grouping() {
+    let export = export();
+    let memories = export.path().join("memories");
+    let output = export.path().join("processed_media");
+    fs::rename(
+        memories.join(WITH_OVERLAY),
+        memories.join(format!("{}.jpg", ID)),
+    )
+    .unwrap();
+    fs::rename(
+        memories.join(OVERLAY),
+        memories.join(format!("{}.overlay.png", ID)),
+    )
+    .unwrap();
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .grouping(BareIdGrouping)
+        .build()
+        .run()
+        .unwrap();
+
+    // The exported name of the other memory isn't an id
+    assert_eq!(report.files.len(), 2);
+    let file = outcome(&report, &format!("{}.jpg", ID));
+    assert_eq!(file.media_id.as_deref(), Some(ID));
+    assert!(file.exif.is_done() && file.overlay.is_done());
+    assert_eq!(outcome(&report, WITHOUT_OVERLAY).media_id, None);
+    assert_eq!(
+        file_names(&output),
+        names(&[
+            &format!("{}.jpg", ID),
+            &format!("{}_with_overlay.jpg", ID),
+            WITHOUT_OVERLAY
+        ])
+    );
+}
+
+#[test]
+fn chat_artifacts_are_not_grouped_as_media() {
+    let dir = Path::new("chat_media");
+    let paths = [
+        "2023-01-15_b~EiQSFWxyz.jpg",
+        "2023-01-15_overlay~b~EiQSFWxyz.webp",
+        "2023-01-15_b~EiQSFWxyz_with_overlay.jpg",
+        "2023-01-15_b~EiQSFWxyz_converted.webp",
+        "2023-01-15_b~Video.mp4",
+        "2023-01-15_b~Video_temp.mp4",
+    ]
+    .map(|name| dir.join(name));
+
+    let bundles = group(paths, &ChatGrouping);
+    let mains: Vec<_> = bundles.iter().map(|b| b.main.clone()).collect();
+    assert_eq!(
+        mains,
+        [
+            dir.join("2023-01-15_b~EiQSFWxyz.jpg"),
+            dir.join("2023-01-15_b~Video.mp4")
+        ]
+    );
+    assert_eq!(
+        bundles[0].overlays,
+        [dir.join("2023-01-15_overlay~b~EiQSFWxyz.webp")]
+    );
+    assert!(bundles[1].overlays.is_empty());
+}
+
+#[test]
+fn chat_media_gets_its_send_time_and_partner() {
+    let export = export();
+    let output = export.path().join("processed_media");
+    let received = "2023-01-15_b~EiQSFWxyz.jpg";
+    let sent = "2023-01-16_b~Gr0upPic.jpg";
+
+    let report = options(export.path())
+        .source(ExportSource::Chat)
+        .chat_history_json_path(export.path().join("json/chat_history.json"))
+        .snap_history_json_path(export.path().join("json/snap_history.json"))
+        .tag_partner(true)
+        .build()
+        .run()
+        .unwrap();
+
+    assert_eq!(report.files.len(), 2);
+    assert_eq!(file_names(&output), names(&[received, sent]));
+    assert!(file_names(&export.path().join("chat_media")).is_empty());
+    for (name, id, date, description) in [
+        (
+            received,
+            "b~EiQSFWxyz",
+            "2023:01:15 10:20:30",
+            "Received from alex",
+        ),
+        (
+            sent,
+            "b~Gr0upPic",
+            "2023:01:16 08:00:00",
+            "Sent to Ski trip",
+        ),
+    ] {
+        let file = outcome(&report, name);
+        assert_eq!(file.media_id.as_deref(), Some(id));
+        assert!(file.exif.is_done());
+        let data = fs::read(output.join(name)).unwrap();
+        assert!(contains(&data, date.as_bytes()), "{}", name);
+        assert!(contains(&data, description.as_bytes()), "{}", name);
+        assert!(contains(&data, b"Snapchat Chat Media"), "{}", name);
+    }
+}
+
+#[test]
+fn metadata_survives_compositing_photos() {
+    for (mode, name) in [
+        (OverlayMode::Overwrite, WITH_OVERLAY),
+        (OverlayMode::Copy, OVERLAID),
+    ] {
+        let export = export();
+        let output = export.path().join("processed_media");
+        options(export.path()).overlays(mode).build().run().unwrap();
+
+        let path = output.join(name);
+        let data = fs::read(&path).unwrap();
+        assert!(contains(&data, b"Exif\0\0"), "{:?}", mode);
+        assert!(contains(&data, b"2021:07:03 12:34:56"), "{:?}", mode);
+        assert!(contains(&data, ID.as_bytes()), "{:?}", mode);
+        assert_eq!(
+            modified(&path),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(CAPTURED),
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn metadata_survives_compositing_videos() {
+    let video = WITH_OVERLAY.replace(".jpg", ".mp4");
+
+    for (mode, name) in [
+        (OverlayMode::Overwrite, video.clone()),
+        (OverlayMode::Copy, OVERLAID.replace(".jpg", ".mp4")),
+    ] {
+        let export = export();
+        let memories = export.path().join("memories");
+        fs::remove_file(memories.join(WITH_OVERLAY)).unwrap();
+        fs::write(memories.join(&video), bare_mp4()).unwrap();
+        let output = export.path().join("processed_media");
+
+        let report = options(export.path())
+            .overlays(mode)
+            .tools(fake_tools())
+            .build()
+            .run()
+            .unwrap();
+        let file = outcome(&report, &video);
+        assert!(file.exif.is_done() && file.overlay.is_done(), "{:?}", mode);
+        assert!(file.warnings.is_empty(), "{:?}", file.warnings);
+
+        let path = output.join(&name);
+        let data = fs::read(&path).unwrap();
+        // The movie header's creation time, in seconds since 1904
+        let created = (CAPTURED + 2_082_844_800) as u32;
+        assert!(contains(&data, &created.to_be_bytes()), "{:?}", mode);
+        assert!(
+            contains(&data, b"com.apple.quicktime.creationdate"),
+            "{:?}",
+            mode
+        );
+        assert!(contains(&data, ID.as_bytes()), "{:?}", mode);
+        assert_eq!(
+            modified(&path),
+            SystemTime::UNIX_EPOCH + Duration::from_secs(CAPTURED),
+            "{:?}",
+            mode
+        );
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn converted_videos_replace_their_originals() {
+    let export = export();
+    let memories = export.path().join("memories");
+    let output = export.path().join("processed_media");
+    let video = WITH_OVERLAY.replace(".jpg", ".mp4");
+    fs::remove_file(memories.join(WITH_OVERLAY)).unwrap();
+    fs::remove_file(memories.join(OVERLAY)).unwrap();
+    fs::write(memories.join(&video), bare_mp4()).unwrap();
+
+    let report = options(export.path())
+        .convert_videos(VideoConversion::Hevc)
+        .tools(fake_tools())
+        .build()
+        .run()
+        .unwrap();
+
+    let file = outcome(&report, &video);
+    assert!(file.convert.is_done());
+    let conversion = file.conversion.unwrap();
+    assert!(conversion.original_bytes > 0 && conversion.converted_bytes > 0);
+    // Photos are kept as they are
+    assert_eq!(
+        outcome(&report, WITHOUT_OVERLAY).convert,
+        StepOutcome::Skipped
+    );
+    assert_eq!(report.summary().converted, 1);
+
+    // The conversion takes the original's place
+    assert_eq!(file_names(&output), names(&[&video, WITHOUT_OVERLAY]));
+    assert!(file_names(&memories).is_empty());
+    let data = fs::read(output.join(&video)).unwrap();
+    assert!(contains(&data, ID.as_bytes()));
+}
+
+#[cfg(unix)]
+#[test]
+fn converted_photos_and_their_overlaid_copies_replace_the_originals() {
+    let export = export();
+    let memories = export.path().join("memories");
+    let output = export.path().join("processed_media");
+
+    let report = options(export.path())
+        .overlays(OverlayMode::Copy)
+        .convert_images(ImageConversion::Avif)
+        .tools(fake_tools())
+        .build()
+        .run()
+        .unwrap();
+
+    let file = outcome(&report, WITH_OVERLAY);
+    assert!(file.convert.is_done());
+    assert_eq!(report.summary().converted, 2);
+    assert_eq!(
+        file_names(&output),
+        names(&[
+            &WITH_OVERLAY.replace(".jpg", ".avif"),
+            &WITHOUT_OVERLAY.replace(".jpg", ".avif"),
+            &OVERLAID.replace(".jpg", ".avif"),
+        ])
+    );
+    assert_eq!(file_names(&memories), names(&[OVERLAY]));
+    // The metadata is written again onto both conversions
+    for name in [WITH_OVERLAY, OVERLAID] {
+        let data = fs::read(output.join(name.replace(".jpg", ".avif"))).unwrap();
+        assert!(contains(&data, ID.as_bytes()), "{}", name);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn plan_and_summary_match_the_conversions_made() {
+    let export = export();
+    let memories = export.path().join("memories");
+    let output = export.path().join("processed_media");
+    // Composited in place, and so encoded with the target codec already
+    let composited = WITH_OVERLAY.replace(".jpg", ".mov");
+    let plain = WITHOUT_OVERLAY.replace(".jpg", ".mov");
+    fs::remove_file(memories.join(WITH_OVERLAY)).unwrap();
+    fs::remove_file(memories.join(WITHOUT_OVERLAY)).unwrap();
+    fs::write(memories.join(&composited), bare_mp4()).unwrap();
+    fs::write(memories.join(&plain), bare_mp4()).unwrap();
+    let pipeline = options(export.path())
+        .overlays(OverlayMode::Overwrite)
+        .convert_videos(VideoConversion::Hevc)
+        .tools(fake_tools())
+        .build();
+
+    let plan = pipeline.plan().unwrap();
+    let planned = |name: &str| {
+        plan.files
+            .iter()
+            .find(|f| f.path.file_name().unwrap() == name)
+            .unwrap()
+    };
+    let plain_mp4 = plain.replace(".mov", ".mp4");
+    assert_eq!(planned(&composited).converted, None);
+    assert_eq!(
+        planned(&composited).destination,
+        Some(output.join(&composited))
+    );
+    assert_eq!(
+        planned(&plain).converted,
+        Some(memories.join(plain.replace(".mov", "_converted.mp4")))
+    );
+    assert_eq!(planned(&plain).destination, Some(output.join(&plain_mp4)));
+
+    let report = pipeline.run().unwrap();
+    assert_eq!(outcome(&report, &composited).convert, StepOutcome::Skipped);
+    assert_eq!(outcome(&report, &composited).conversion, None);
+    assert!(outcome(&report, &plain).convert.is_done());
+    let summary = report.summary();
+    assert_eq!(summary.converted, 1);
+    assert!(summary.original_bytes > 0 && summary.converted_bytes > 0);
+    assert_eq!(file_names(&output), names(&[&composited, &plain_mp4]));
+}
+
+#[cfg(unix)]
+#[test]
+fn hardlinked_outputs_survive_extracting_again() {
+    let export = zipped_export();
+    let output = export.path().join("processed_media");
+    let run = || {
+        options(export.path())
+            .zip_dir(export.path())
+            .skip_unzip(false)
+            .transfer(Transfer::Hardlink)
+            .build()
+            .run()
+            .unwrap()
+    };
+
+    run();
+    let processed = fs::read(output.join(WITHOUT_OVERLAY)).unwrap();
+    let exported = fs::read(
+        PathBuf::from(FIXTURE)
+            .join("memories")
+            .join(WITHOUT_OVERLAY),
+    )
+    .unwrap();
+    assert!(processed != exported);
+
+    // Extracting again recreates the exported files in place
+    let report = run();
+    assert_eq!(
+        outcome(&report, WITHOUT_OVERLAY).moved,
+        StepOutcome::Resumed
+    );
+    assert!(fs::read(output.join(WITHOUT_OVERLAY)).unwrap() == processed);
+    assert!(fs::read(export.path().join("memories").join(WITHOUT_OVERLAY)).unwrap() == exported);
+}